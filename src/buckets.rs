@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::collector::{HISTOGRAMS, PER_LABELS_HISTOGRAMS};
+
+#[derive(Debug)]
+pub struct BucketsError(pub String);
+
+impl std::fmt::Display for BucketsError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Invalid buckets file: {}", self.0)
+    }
+}
+
+impl std::error::Error for BucketsError {}
+
+/// The buckets of histograms, for all vhosts or for specific vhosts.
+#[derive(Clone, Debug, Default)]
+pub struct Buckets {
+    pub histograms: HashMap<String, Vec<f64>>,
+    /// Buckets by histogram, then by vhost
+    pub vhosts: HashMap<String, HashMap<String, Vec<f64>>>,
+}
+
+/// Read a file mapping histogram names to their buckets.
+///
+/// The file uses a subset of TOML, one histogram per line, optionally for
+/// a single vhost:
+///
+/// ```toml
+/// # Latency SLOs
+/// request_duration = [0.05, 0.1, 0.5, 1, 5]
+/// request_duration{vhost="api.example.org"} = [0.01, 0.025, 0.05]
+/// ```
+pub fn read_buckets_file(path: &Path) -> Result<Buckets, BucketsError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| BucketsError(format!("{}: {}", path.display(), e)))?;
+    parse_buckets(&content)
+}
+
+/// Split `name{vhost="<vhost>"}` into the name and the vhost.
+fn split_vhost(key: &str) -> Option<(&str, Option<&str>)> {
+    match key.find('{') {
+        Some(i) => {
+            let vhost = key[i..].strip_prefix("{vhost=\"")?.strip_suffix("\"}")?;
+            if vhost.is_empty() || vhost.contains('"') {
+                return None;
+            }
+            Some((key[..i].trim(), Some(vhost)))
+        }
+        None => Some((key, None)),
+    }
+}
+
+pub fn parse_buckets(content: &str) -> Result<Buckets, BucketsError> {
+    let mut buckets = Buckets::default();
+    for (lineno, line) in content.lines().enumerate() {
+        let lineno = lineno + 1;
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        }.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // The list comes after the last '=', the key can have one for the vhost
+        let (key, list) = match line.rfind('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(BucketsError(format!("line {}: expected <name> = [<buckets>]", lineno))),
+        };
+        let (name, vhost) = split_vhost(key)
+            .ok_or_else(|| BucketsError(format!("line {}: expected <name> or <name>{{vhost=\"<vhost>\"}}", lineno)))?;
+        if !HISTOGRAMS.contains(&name) {
+            return Err(BucketsError(format!("line {}: unknown histogram {:?}", lineno, name)));
+        }
+        if vhost.is_some() && !PER_LABELS_HISTOGRAMS.contains(&name) {
+            return Err(BucketsError(format!("line {}: histogram {:?} has no vhost label", lineno, name)));
+        }
+        let duplicate = match vhost {
+            Some(vhost) => buckets.vhosts.get(name).map_or(false, |v| v.contains_key(vhost)),
+            None => buckets.histograms.contains_key(name),
+        };
+        if duplicate {
+            return Err(BucketsError(format!("line {}: duplicate histogram {:?}", lineno, key)));
+        }
+        if !list.starts_with('[') || !list.ends_with(']') {
+            return Err(BucketsError(format!("line {}: expected a list in brackets", lineno)));
+        }
+
+        let mut values = Vec::new();
+        for value in list[1..list.len() - 1].split(',') {
+            let value = value.trim();
+            if value.is_empty() {
+                // Allow a trailing comma
+                continue;
+            }
+            let value = match value.parse::<f64>() {
+                // The +Inf bucket is always there
+                Ok(v) if v.is_finite() => v,
+                _ => return Err(BucketsError(format!("line {}: invalid number {:?}", lineno, value))),
+            };
+            if let Some(&last) = values.last() {
+                if value <= last {
+                    return Err(BucketsError(format!("line {}: buckets are not strictly increasing", lineno)));
+                }
+            }
+            values.push(value);
+        }
+        if values.is_empty() {
+            return Err(BucketsError(format!("line {}: no buckets", lineno)));
+        }
+        match vhost {
+            Some(vhost) => {
+                buckets.vhosts.entry(name.to_owned()).or_default().insert(vhost.to_owned(), values);
+            }
+            None => {
+                buckets.histograms.insert(name.to_owned(), values);
+            }
+        }
+    }
+    Ok(buckets)
+}
+
+#[test]
+fn test_parse_buckets() {
+    let buckets = parse_buckets(
+        "# Our SLOs\n\
+         request_duration = [0.05, 0.1, 0.5, 1, 5]\n\
+         \n\
+         response_body_size = [1000, 1000000,]  # 1kB, 1MB\n",
+    ).unwrap();
+    assert_eq!(buckets.histograms.len(), 2);
+    assert_eq!(buckets.histograms["request_duration"], vec![0.05, 0.1, 0.5, 1.0, 5.0]);
+    assert_eq!(buckets.histograms["response_body_size"], vec![1000.0, 1000000.0]);
+    assert!(buckets.vhosts.is_empty());
+
+    assert!(parse_buckets("request_duration = [0.1, 0.1]").is_err());
+    assert!(parse_buckets("request_duration = [1, 0.5]").is_err());
+    assert!(parse_buckets("requests = [1, 2]").is_err());
+    assert!(parse_buckets("request_duration = 1, 2").is_err());
+    assert!(parse_buckets("request_duration = [1, two]").is_err());
+    assert!(parse_buckets("request_duration = [NaN]").is_err());
+    assert!(parse_buckets("request_duration = [1, NaN]").is_err());
+    assert!(parse_buckets("request_duration = [1, inf]").is_err());
+}
+
+#[test]
+fn test_parse_vhost_buckets() {
+    let buckets = parse_buckets(
+        "request_duration = [0.1, 1, 10]\n\
+         request_duration{vhost=\"api.example.org\"} = [0.01, 0.05]\n\
+         request_duration{vhost=\"files.example.org\"} = [1, 60]\n",
+    ).unwrap();
+    assert_eq!(buckets.histograms["request_duration"], vec![0.1, 1.0, 10.0]);
+    assert_eq!(buckets.vhosts["request_duration"].len(), 2);
+    assert_eq!(buckets.vhosts["request_duration"]["api.example.org"], vec![0.01, 0.05]);
+    assert_eq!(buckets.vhosts["request_duration"]["files.example.org"], vec![1.0, 60.0]);
+
+    assert!(parse_buckets("request_duration{vhost=\"a\"} = [1]\nrequest_duration{vhost=\"a\"} = [2]").is_err());
+    assert!(parse_buckets("request_duration{status=\"200\"} = [1]").is_err());
+    assert!(parse_buckets("request_duration{vhost=\"\"} = [1]").is_err());
+    assert!(parse_buckets("log_processing_lag_seconds{vhost=\"a\"} = [1]").is_err());
+}
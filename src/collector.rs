@@ -1,40 +1,670 @@
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts};
+use prometheus::{CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
 use prometheus::core::{Collector, Desc};
-use prometheus::proto::MetricFamily;
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::{HashMap, HashSet};
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
 
+use crate::hyperloglog::HyperLogLog;
 use crate::log_parser::LogParser;
-use crate::processor::{Filter, FilterFunc, Extractor, ExtractorFunc, LogProcessor};
+use crate::openmetrics::{ExemplarStore, SharedExemplars};
+use crate::processor::{BOT_PATTERNS, Combination, CombinationFunc, Composite, Filter, FilterFunc, Extractor, ExtractorFunc, LogProcessor, ProcessorOptions, DebugLines, RecentLines, Replay, TransformStep, UpstreamTimes, Warmup, json_string};
+#[cfg(feature = "statsd")]
+use crate::statsd::StatsdClient;
+use crate::timestamp::{self, TIME_FIELDS, TimestampFormat};
+
+/// The names of the histograms, for which buckets can be configured.
+pub const HISTOGRAMS: &[&str] = &[
+    "request_duration",
+    "upstream_duration",
+    "upstream_header_time_seconds",
+    "upstream_connect_time_seconds",
+    "response_body_size",
+    "requests_per_connection",
+    "log_processing_lag_seconds",
+    "extractor_duration_seconds",
+];
+
+/// The histograms that have the same labels as `requests`, for which buckets
+/// can be set per vhost.
+pub const PER_LABELS_HISTOGRAMS: &[&str] = &[
+    "request_duration",
+    "upstream_duration",
+    "upstream_header_time_seconds",
+    "upstream_connect_time_seconds",
+    "response_body_size",
+    "requests_per_connection",
+];
+
+/// Settings for the metrics, including which optional metrics to record.
+#[derive(Clone, Debug, Default)]
+pub struct LogDataOptions {
+    /// Record the total time spent on requests as a counter
+    pub duration_sum: bool,
+    /// Record the total bytes sent and received as counters
+    pub byte_counters: bool,
+    /// Also record the count and sum of `request_duration` as counters
+    pub histogram_counters: bool,
+    /// Record the duration and body size of the latest request as gauges
+    pub last_values: bool,
+    /// Report the fraction of lines that could not be parsed as a gauge
+    pub error_ratio: bool,
+    /// The window in seconds over which to count distinct clients, if
+    /// `add_unique_clients()` is used
+    pub unique_clients_window: Option<f64>,
+    /// Buckets for the histograms, by name, instead of the defaults
+    pub buckets: HashMap<String, Vec<f64>>,
+    /// Buckets for some vhosts, by histogram name then by vhost
+    pub vhost_buckets: HashMap<String, HashMap<String, Vec<f64>>>,
+    /// Labels with the same value on all the metrics, such as `logfile`
+    pub const_labels: HashMap<String, String>,
+    /// Keep the results of this many recent lines, for debugging
+    pub debug_lines: Option<usize>,
+    /// Record the time spent in each extractor in a histogram
+    pub profile_extractors: bool,
+}
+
+impl LogDataOptions {
+    fn buckets(&self, histogram: &str, default: Vec<f64>) -> Vec<f64> {
+        match self.buckets.get(histogram) {
+            Some(b) => b.clone(),
+            None => default,
+        }
+    }
+
+    /// The options of a metric, with the constant labels.
+    fn opts<S1: Into<String>, S2: Into<String>>(&self, name: S1, help: S2) -> Opts {
+        Opts::new(name, help).const_labels(self.const_labels.clone())
+    }
+
+    fn histogram_opts<S1: Into<String>, S2: Into<String>>(&self, name: S1, help: S2) -> HistogramOpts {
+        HistogramOpts::new(name, help).const_labels(self.const_labels.clone())
+    }
+}
+
+/// Describe the labels for the help text of metrics, e.g. " per vhost and status".
+fn help_per_labels(labels: &[&str]) -> String {
+    match labels {
+        [] => String::new(),
+        [label] => format!(" per {}", label),
+        [first @ .., last] => format!(" per {} and {}", first.join(", "), last),
+    }
+}
+
+/// A histogram vector where some vhosts have their own buckets.
+///
+/// Those vhosts are observed in separate vectors, which are reported as a
+/// single metric.
+pub struct RoutedHistogram {
+    default: HistogramVec,
+    default_buckets: Vec<f64>,
+    /// The position of the vhost label, if any
+    vhost_index: Option<usize>,
+    vhosts: HashMap<String, (HistogramVec, Vec<f64>)>,
+}
+
+impl RoutedHistogram {
+    fn new(opts: HistogramOpts, labels: &[&str], buckets: Vec<f64>, vhost_buckets: Option<&HashMap<String, Vec<f64>>>) -> RoutedHistogram {
+        let vhost_index = labels.iter().position(|&l| l == "vhost");
+        let mut vhosts = HashMap::new();
+        if let (Some(_), Some(vhost_buckets)) = (vhost_index, vhost_buckets) {
+            for (vhost, b) in vhost_buckets {
+                let histogram = HistogramVec::new(opts.clone().buckets(b.clone()), labels).unwrap();
+                vhosts.insert(vhost.clone(), (histogram, b.clone()));
+            }
+        }
+        RoutedHistogram {
+            default: HistogramVec::new(opts.buckets(buckets.clone()), labels).unwrap(),
+            default_buckets: buckets,
+            vhost_index,
+            vhosts,
+        }
+    }
+
+    fn route(&self, label_values: &[&str]) -> (&HistogramVec, &[f64]) {
+        match self.vhost_index.and_then(|i| self.vhosts.get(label_values[i])) {
+            Some((histogram, buckets)) => (histogram, buckets),
+            None => (&self.default, &self.default_buckets),
+        }
+    }
+
+    pub fn with_label_values(&self, label_values: &[&str]) -> Histogram {
+        self.route(label_values).0.with_label_values(label_values)
+    }
+
+    /// The upper bounds of the buckets used for these label values.
+    pub fn buckets(&self, label_values: &[&str]) -> &[f64] {
+        self.route(label_values).1
+    }
+
+    pub fn reset(&self) {
+        self.default.reset();
+        for (histogram, _) in self.vhosts.values() {
+            histogram.reset();
+        }
+    }
+}
+
+impl Collector for RoutedHistogram {
+    fn desc(&self) -> Vec<&Desc> {
+        // The other vectors have the same name and labels
+        self.default.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.default.collect();
+        for (histogram, _) in self.vhosts.values() {
+            for mut family in histogram.collect() {
+                families[0].mut_metric().extend(family.take_metric());
+            }
+        }
+        families
+    }
+}
 
 pub struct LogData {
     pub active: bool,
     pub request_count: IntCounterVec,
-    pub request_duration: HistogramVec,
-    pub response_body_size: HistogramVec,
+    pub request_duration: RoutedHistogram,
+    pub request_duration_sum: Option<CounterVec>,
+    /// The count and sum of `request_duration` as counters, for tools that
+    /// don't handle histograms
+    pub request_duration_count_counter: Option<IntCounterVec>,
+    pub request_duration_sum_counter: Option<CounterVec>,
+    pub last_request_duration: Option<GaugeVec>,
+    pub last_response_body_size: Option<GaugeVec>,
+    /// Requests observed in the buckets of `request_duration`, for OpenMetrics
+    pub request_duration_exemplars: Option<SharedExemplars>,
+    pub upstream_duration: Option<RoutedHistogram>,
+    pub upstream_header_time: Option<RoutedHistogram>,
+    pub upstream_connect_time: Option<RoutedHistogram>,
+    pub response_body_size: RoutedHistogram,
+    pub bytes_sent: Option<IntCounterVec>,
+    pub bytes_received: Option<IntCounterVec>,
+    pub requests_per_connection: Option<RoutedHistogram>,
+    pub processing_lag: Option<Histogram>,
+    /// The time spent in each extractor, by label (or by field for the
+    /// extractors that don't set a label)
+    pub extractor_duration: Option<HistogramVec>,
+    /// The time of the most recent request, to tell how fresh the metrics are
+    pub last_timestamp: Option<Gauge>,
     pub error_count: IntCounter,
+    /// Computed from `error_count` and `request_count` when collecting
+    pub error_ratio: Option<Gauge>,
+    /// Estimated from `unique_clients_sketch` when collecting
+    pub unique_clients: Option<Gauge>,
+    pub unique_clients_sketch: Option<Mutex<HyperLogLog>>,
+    pub http_version_requests: Option<IntCounterVec>,
+    pub response_header_count: Option<IntCounterVec>,
+    /// The values seen for each header in `response_header_count`
+    pub response_header_values: Mutex<HashMap<String, HashSet<String>>>,
+    pub debug_lines: Option<Mutex<DebugLines>>,
+    /// Computed from `cache_statuses` when collecting
+    pub cache_hit_ratio: Option<Gauge>,
+    /// The number of requests for each cache status
+    pub cache_statuses: Option<Mutex<HashMap<&'static str, u64>>>,
+    pub trailing_data_count: IntCounter,
+    pub out_of_time_window_count: IntCounter,
+    pub buffer_size: IntGauge,
+    pub dropped_lines_count: IntCounter,
+    pub duplicate_lines_count: IntCounter,
+    pub cardinality_limit_reached: IntGauge,
+    pub label_cardinality: IntGaugeVec,
+    pub file_present: IntGauge,
+    pub watch_active: IntGauge,
+    /// Whether no lines were processed for `--idle-timeout`
+    pub idle: Option<IntGauge>,
+    pub rotation_count: IntCounter,
+    pub truncation_count: IntCounter,
+    /// Incremented by `reset()`, so the processor forgets what it has seen
+    pub generation: u64,
+    labels: Vec<String>,
+    options: LogDataOptions,
+}
+
+fn processing_lag_histogram(options: &LogDataOptions) -> Histogram {
+    Histogram::with_opts(
+        options.histogram_opts("log_processing_lag_seconds", "Delay between the time of requests and the processing of their log line in seconds")
+        .buckets(options.buckets("log_processing_lag_seconds", vec![0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0])),
+    ).unwrap()
 }
 
 impl LogData {
-    fn new(labels: &[&str]) -> LogData {
+    fn new(labels: &[&str], options: &LogDataOptions, extractors: &[Extractor]) -> LogData {
+        let per = help_per_labels(labels);
+        let has_timestamp = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::Timestamp { .. }));
+        let has_upstream_duration = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::UpstreamDuration { .. }));
+        let has_upstream_header_time = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::UpstreamHeaderTime { .. }));
+        let has_upstream_connect_time = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::UpstreamConnectTime { .. }));
+        let has_request_id = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::RequestId));
+        let has_connection_requests = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::ConnectionRequests));
+        let has_request_length = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::RequestLength));
+        let has_client_hash = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::ClientHash));
+        let has_cache_status = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::CacheStatus));
+        let has_response_headers = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::ResponseHeader { .. }));
+        let has_http_version = extractors.iter().any(|e| matches!(e.func, ExtractorFunc::HttpVersion { .. }));
+        let histogram = |name: &str, help: String, default: Vec<f64>| {
+            RoutedHistogram::new(options.histogram_opts(name, help), labels, options.buckets(name, default), options.vhost_buckets.get(name))
+        };
+        let label_cardinality = IntGaugeVec::new(
+            options.opts("label_cardinality", "The number of distinct values seen for each label"),
+            &["label"],
+        ).unwrap();
+        for label in labels {
+            label_cardinality.with_label_values(&[label]).set(0);
+        }
         LogData {
             active: false,
             request_count: IntCounterVec::new(
-                Opts::new("requests", "The total number of requests per HTTP status code and virtual host name"),
-                &labels,
+                options.opts("requests", format!("The total number of requests{}", per)),
+                labels,
             ).unwrap(),
-            request_duration: HistogramVec::new(
-                HistogramOpts::new("request_duration", "Duration of HTTP requests in seconds per HTTP status code and virtual host name"),
-                &labels,
-            ).unwrap(),
-            response_body_size: HistogramVec::new(
-                HistogramOpts::new("response_body_size", "Size of responses' bodies in bytes HTTP status code and virtual host name")
-                .buckets(prometheus::exponential_buckets(100.0, 5.0, 10).unwrap()),
-                &labels,
-            ).unwrap(),
-            error_count: IntCounter::new("errors", "The total number of log lines that failed parsing").unwrap(),
+            request_duration: histogram(
+                "request_duration",
+                format!("Duration of HTTP requests in seconds{}", per),
+                prometheus::DEFAULT_BUCKETS.to_vec(),
+            ),
+            request_duration_sum: if options.duration_sum {
+                Some(CounterVec::new(
+                    options.opts("request_duration_seconds_total", format!("Total duration of HTTP requests in seconds{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            request_duration_count_counter: if options.histogram_counters {
+                Some(IntCounterVec::new(
+                    options.opts("request_duration_seconds_count", format!("Number of HTTP requests with a duration{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            request_duration_sum_counter: if options.histogram_counters {
+                Some(CounterVec::new(
+                    options.opts("request_duration_seconds_sum", format!("Total duration of HTTP requests in seconds{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            last_request_duration: if options.last_values {
+                Some(GaugeVec::new(
+                    options.opts("last_request_duration_seconds", format!("Duration of the latest HTTP request in seconds{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            last_response_body_size: if options.last_values {
+                Some(GaugeVec::new(
+                    options.opts("last_response_body_size_bytes", format!("Size of the latest response's body in bytes{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            request_duration_exemplars: if has_request_id {
+                Some(Arc::new(Mutex::new(ExemplarStore::new("request_duration"))))
+            } else {
+                None
+            },
+            upstream_duration: if has_upstream_duration {
+                Some(histogram(
+                    "upstream_duration",
+                    format!("Time spent waiting on upstream servers in seconds{}", per),
+                    prometheus::DEFAULT_BUCKETS.to_vec(),
+                ))
+            } else {
+                None
+            },
+            upstream_header_time: if has_upstream_header_time {
+                Some(histogram(
+                    "upstream_header_time_seconds",
+                    format!("Time until the first byte of the response from upstream servers in seconds{}", per),
+                    prometheus::DEFAULT_BUCKETS.to_vec(),
+                ))
+            } else {
+                None
+            },
+            upstream_connect_time: if has_upstream_connect_time {
+                Some(histogram(
+                    "upstream_connect_time_seconds",
+                    format!("Time spent establishing connections to upstream servers in seconds{}", per),
+                    prometheus::DEFAULT_BUCKETS.to_vec(),
+                ))
+            } else {
+                None
+            },
+            response_body_size: histogram(
+                "response_body_size",
+                format!("Size of responses' bodies in bytes{}", per),
+                prometheus::exponential_buckets(100.0, 5.0, 10).unwrap(),
+            ),
+            bytes_sent: if options.byte_counters {
+                Some(IntCounterVec::new(
+                    options.opts("bytes_sent_total", format!("Total size of responses' bodies in bytes{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            bytes_received: if options.byte_counters && has_request_length {
+                Some(IntCounterVec::new(
+                    options.opts("bytes_received_total", format!("Total size of requests in bytes{}", per)),
+                    labels,
+                ).unwrap())
+            } else {
+                None
+            },
+            requests_per_connection: if has_connection_requests {
+                Some(histogram(
+                    "requests_per_connection",
+                    format!("Number of requests made so far on the connection of each request{}", per),
+                    vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0],
+                ))
+            } else {
+                None
+            },
+            processing_lag: if has_timestamp {
+                Some(processing_lag_histogram(options))
+            } else {
+                None
+            },
+            extractor_duration: if options.profile_extractors {
+                Some(HistogramVec::new(
+                    options.histogram_opts("extractor_duration_seconds", "Time spent extracting values from a field in seconds per label")
+                    .buckets(options.buckets("extractor_duration_seconds", prometheus::exponential_buckets(0.000_001, 4.0, 10).unwrap())),
+                    &["label"],
+                ).unwrap())
+            } else {
+                None
+            },
+            last_timestamp: if has_timestamp {
+                Some(Gauge::with_opts(options.opts("log_last_timestamp_seconds", "The time of the most recent request in the log, as a Unix timestamp")).unwrap())
+            } else {
+                None
+            },
+            error_count: IntCounter::with_opts(options.opts("errors", "The total number of log lines that failed parsing")).unwrap(),
+            trailing_data_count: IntCounter::with_opts(options.opts("trailing_data_errors", "The total number of log lines with unexpected data after the last field")).unwrap(),
+            out_of_time_window_count: IntCounter::with_opts(options.opts("out_of_time_window", "The total number of log lines dropped because their timestamp was too old or in the future")).unwrap(),
+            buffer_size: IntGauge::with_opts(options.opts("log_buffer_bytes", "The size of the data written to the log that is not processed yet")).unwrap(),
+            dropped_lines_count: IntCounter::with_opts(options.opts("log_lines_dropped_total", "The total number of log lines dropped without processing because the buffer was full")).unwrap(),
+            duplicate_lines_count: IntCounter::with_opts(options.opts("log_lines_duplicate_total", "The total number of log lines dropped because they were identical to a recent line")).unwrap(),
+            cardinality_limit_reached: IntGauge::with_opts(options.opts("cardinality_limit_reached", "Whether new label combinations are being counted in the overflow series (1) or not (0)")).unwrap(),
+            error_ratio: if options.error_ratio {
+                Some(Gauge::with_opts(options.opts("error_ratio", "The fraction of log lines that could not be parsed")).unwrap())
+            } else {
+                None
+            },
+            unique_clients: if has_client_hash {
+                Some(Gauge::with_opts(options.opts("approx_unique_clients", "Estimated number of distinct client addresses in the current window")).unwrap())
+            } else {
+                None
+            },
+            unique_clients_sketch: if has_client_hash {
+                Some(Mutex::new(HyperLogLog::new(options.unique_clients_window.unwrap_or(3600.0))))
+            } else {
+                None
+            },
+            cache_hit_ratio: if has_cache_status {
+                Some(Gauge::with_opts(options.opts("cache_hit_ratio", "The fraction of cacheable requests that were served from the cache")).unwrap())
+            } else {
+                None
+            },
+            cache_statuses: if has_cache_status {
+                Some(Mutex::new(HashMap::new()))
+            } else {
+                None
+            },
+            debug_lines: options.debug_lines.map(|n| Mutex::new(DebugLines::new(n))),
+            response_header_count: if has_response_headers {
+                Some(IntCounterVec::new(
+                    options.opts("response_header_total", "The total number of responses with each value of some headers"),
+                    &["header", "value"],
+                ).unwrap())
+            } else {
+                None
+            },
+            response_header_values: Mutex::new(HashMap::new()),
+            http_version_requests: if has_http_version {
+                Some(IntCounterVec::new(
+                    options.opts("http_version_requests_total", "The total number of requests by HTTP version"),
+                    &["version"],
+                ).unwrap())
+            } else {
+                None
+            },
+            label_cardinality,
+            file_present: IntGauge::with_opts(options.opts("log_file_present", "Whether the log file exists and is open (1) or not (0)")).unwrap(),
+            watch_active: IntGauge::with_opts(options.opts("log_watch_active", "Whether the watch on the log file is established (1) or not (0)")).unwrap(),
+            idle: None,
+            rotation_count: IntCounter::with_opts(options.opts("log_rotations_total", "The total number of times the log file was reopened, e.g. after rotation")).unwrap(),
+            truncation_count: IntCounter::with_opts(options.opts("log_truncations_total", "The total number of times the log file was truncated")).unwrap(),
+            generation: 0,
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            options: options.clone(),
+        }
+    }
+
+    /// Set the metrics read from the log back to zero, for testing.
+    ///
+    /// The metrics are reset in place, so they keep the same descriptors.
+    /// The status of the watch is left alone.
+    pub fn reset(&mut self) {
+        self.request_count.reset();
+        self.request_duration.reset();
+        self.response_body_size.reset();
+        if let Some(m) = &self.request_duration_sum {
+            m.reset();
+        }
+        if let Some(m) = &self.request_duration_count_counter {
+            m.reset();
+        }
+        if let Some(m) = &self.request_duration_sum_counter {
+            m.reset();
+        }
+        if let Some(m) = &self.last_request_duration {
+            m.reset();
+        }
+        if let Some(m) = &self.last_response_body_size {
+            m.reset();
+        }
+        if let Some(m) = &self.upstream_duration {
+            m.reset();
+        }
+        if let Some(m) = &self.upstream_header_time {
+            m.reset();
+        }
+        if let Some(m) = &self.upstream_connect_time {
+            m.reset();
+        }
+        if let Some(m) = &self.bytes_sent {
+            m.reset();
+        }
+        if let Some(m) = &self.bytes_received {
+            m.reset();
+        }
+        if let Some(m) = &self.requests_per_connection {
+            m.reset();
+        }
+        if let Some(m) = &self.extractor_duration {
+            m.reset();
+        }
+        if let Some(exemplars) = &self.request_duration_exemplars {
+            exemplars.lock().unwrap().clear();
+        }
+        // Plain histograms can't be reset, but this one has no labels
+        if self.processing_lag.is_some() {
+            self.processing_lag = Some(processing_lag_histogram(&self.options));
+        }
+        if let Some(m) = &self.last_timestamp {
+            m.set(0.0);
+        }
+        if let Some(m) = &self.error_ratio {
+            m.set(0.0);
+        }
+        if let Some(m) = &self.unique_clients {
+            m.set(0.0);
+        }
+        if let Some(sketch) = &self.unique_clients_sketch {
+            sketch.lock().unwrap().clear();
+        }
+        if let Some(m) = &self.cache_hit_ratio {
+            m.set(0.0);
+        }
+        if let Some(m) = &self.cache_statuses {
+            m.lock().unwrap().clear();
+        }
+        if let Some(m) = &self.http_version_requests {
+            m.reset();
+        }
+        if let Some(m) = &self.response_header_count {
+            m.reset();
+        }
+        self.response_header_values.lock().unwrap().clear();
+        if let Some(m) = &self.debug_lines {
+            m.lock().unwrap().clear();
+        }
+        self.error_count.reset();
+        self.trailing_data_count.reset();
+        self.out_of_time_window_count.reset();
+        self.dropped_lines_count.reset();
+        self.duplicate_lines_count.reset();
+        self.cardinality_limit_reached.set(0);
+        self.label_cardinality.reset();
+        for label in &self.labels {
+            self.label_cardinality.with_label_values(&[label]).set(0);
+        }
+        self.generation += 1;
+    }
+
+    /// The metrics read from the log, reported while the watch is active.
+    fn log_metrics(&self) -> Vec<&dyn Collector> {
+        let mut metrics: Vec<&dyn Collector> = vec![
+            &self.request_count,
+            &self.request_duration,
+            &self.response_body_size,
+            &self.error_count,
+            &self.trailing_data_count,
+            &self.out_of_time_window_count,
+            &self.buffer_size,
+            &self.dropped_lines_count,
+            &self.duplicate_lines_count,
+            &self.cardinality_limit_reached,
+            &self.label_cardinality,
+        ];
+        if let Some(m) = &self.request_duration_sum {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.request_duration_count_counter {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.request_duration_sum_counter {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.last_request_duration {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.last_response_body_size {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.upstream_duration {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.upstream_header_time {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.upstream_connect_time {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.bytes_sent {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.bytes_received {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.requests_per_connection {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.processing_lag {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.extractor_duration {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.last_timestamp {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.error_ratio {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.unique_clients {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.cache_hit_ratio {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.http_version_requests {
+            metrics.push(m);
+        }
+        if let Some(m) = &self.response_header_count {
+            metrics.push(m);
+        }
+        metrics
+    }
+
+    /// Compute `error_ratio` from the current values of the counters.
+    fn update_error_ratio(&self) {
+        if let Some(error_ratio) = &self.error_ratio {
+            let errors = self.error_count.get() as f64;
+            let requests: f64 = self.request_count.collect().iter()
+                .flat_map(|family| family.get_metric())
+                .map(|metric| metric.get_counter().get_value())
+                .sum();
+            let total = errors + requests;
+            error_ratio.set(if total > 0.0 { errors / total } else { 0.0 });
+        }
+    }
+
+    /// Compute `cache_hit_ratio` from the counts of each cache status.
+    /// Requests that bypassed the cache are not cacheable.
+    fn update_cache_hit_ratio(&self) {
+        if let (Some(cache_hit_ratio), Some(cache_statuses)) = (&self.cache_hit_ratio, &self.cache_statuses) {
+            let cache_statuses = cache_statuses.lock().unwrap();
+            let mut hits = 0;
+            let mut cacheable = 0;
+            for (&status, &count) in cache_statuses.iter() {
+                if status == "BYPASS" {
+                    continue;
+                }
+                cacheable += count;
+                if crate::processor::from_cache(status) {
+                    hits += count;
+                }
+            }
+            cache_hit_ratio.set(if cacheable > 0 { hits as f64 / cacheable as f64 } else { 0.0 });
         }
     }
+
+    /// Update `unique_clients`, in case the window is over.
+    fn update_unique_clients(&self) {
+        if let (Some(sketch), Some(unique_clients)) = (&self.unique_clients_sketch, &self.unique_clients) {
+            unique_clients.set(sketch.lock().unwrap().estimate(timestamp::now()));
+        }
+    }
+
+    /// The metrics about the watch itself, always reported.
+    fn status_metrics(&self) -> Vec<&dyn Collector> {
+        let mut metrics: Vec<&dyn Collector> = vec![&self.file_present, &self.watch_active, &self.rotation_count, &self.truncation_count];
+        if let Some(idle) = &self.idle {
+            metrics.push(idle);
+        }
+        metrics
+    }
 }
 
 pub struct LogCollectorBuilder {
@@ -42,13 +672,26 @@ pub struct LogCollectorBuilder {
     filename: PathBuf,
     filters: Vec<Filter>,
     extractors: Vec<Extractor>,
+    combinations: Vec<Combination>,
+    composites: Vec<Composite>,
     labels: Vec<String>,
+    data_options: LogDataOptions,
+    processor_options: ProcessorOptions,
+    json_output: Option<Box<dyn std::io::Write + Send>>,
+    warmup: Option<Warmup>,
+    replay: Option<Replay>,
+    tcp_listener: Option<TcpListener>,
+    duration_statuses: Vec<String>,
+    /// Strip the trailing slash of the `path` label
+    strip_path_slash: bool,
+    #[cfg(feature = "statsd")]
+    statsd: Option<StatsdClient>,
 }
 
 impl LogCollectorBuilder {
     /// Get the index of the label in the array, adding it if it's not there.
     pub fn label(labels: &mut Vec<String>, label: &str) -> usize {
-        match labels.iter().position(|l| l == &label) {
+        match labels.iter().position(|l| l == label) {
             Some(i) => i,
             None => {
                 labels.push(label.to_owned());
@@ -58,16 +701,28 @@ impl LogCollectorBuilder {
     }
 
     pub fn new(log_parser: LogParser, filename: PathBuf) -> LogCollectorBuilder {
+        Self::with_auto_labels(log_parser, filename, true)
+    }
+
+    /// A builder that doesn't add labels for the recognized fields, so only
+    /// the labels that are configured explicitly exist. The durations, sizes,
+    /// and timestamps are still read.
+    pub fn new_bare(log_parser: LogParser, filename: PathBuf) -> LogCollectorBuilder {
+        Self::with_auto_labels(log_parser, filename, false)
+    }
+
+    fn with_auto_labels(log_parser: LogParser, filename: PathBuf, auto_labels: bool) -> LogCollectorBuilder {
         let mut labels = Vec::new();
 
         // Add extractors for the fields that are recognized
         let mut extractors = Vec::new();
         let mut add_extractor = |field_index: usize, label: Option<&str>, func: ExtractorFunc| {
+            if label.is_some() && !auto_labels {
+                return;
+            }
             extractors.push(Extractor {
-                label: match label {
-                    Some(l) => Some((l.to_owned(), Self::label(&mut labels, l))),
-                    None => None,
-                },
+                label: label.map(|l| (l.to_owned(), Self::label(&mut labels, l))),
+                default: None,
                 field_index,
                 func,
             });
@@ -76,186 +731,3046 @@ impl LogCollectorBuilder {
             if field == "remote_user" {
                 add_extractor(field_index, Some("user"), ExtractorFunc::User);
             } else if field == "status" {
-                add_extractor(field_index, Some("status"), ExtractorFunc::Status);
+                add_extractor(field_index, Some("status"), ExtractorFunc::Status { aliases: HashMap::new() });
             } else if field == "request_time" {
                 add_extractor(field_index, None, ExtractorFunc::Duration);
+            } else if field == "upstream_response_time" {
+                add_extractor(field_index, None, ExtractorFunc::UpstreamDuration { policy: UpstreamTimes::Sum });
+            } else if field == "upstream_header_time" {
+                add_extractor(field_index, None, ExtractorFunc::UpstreamHeaderTime { policy: UpstreamTimes::Sum });
+            } else if field == "upstream_connect_time" {
+                add_extractor(field_index, None, ExtractorFunc::UpstreamConnectTime { policy: UpstreamTimes::Sum });
+            } else if field == "connection_requests" {
+                add_extractor(field_index, None, ExtractorFunc::ConnectionRequests);
+            } else if field == "request_id" {
+                add_extractor(field_index, None, ExtractorFunc::RequestId);
             } else if field == "host" {
                 add_extractor(field_index, Some("vhost"), ExtractorFunc::Host);
+            } else if field == "server_name" {
+                add_extractor(field_index, Some("server_name"), ExtractorFunc::ServerName);
+            } else if field == "server_port" {
+                add_extractor(field_index, Some("port"), ExtractorFunc::ServerPort);
+            } else if field == "scheme" {
+                add_extractor(field_index, Some("scheme"), ExtractorFunc::Scheme);
             } else if field == "body_bytes_sent" {
                 add_extractor(field_index, None, ExtractorFunc::ResponseBodySize);
+            } else if field == "request_length" {
+                add_extractor(field_index, None, ExtractorFunc::RequestLength);
+            } else if field == "geoip_country_code" || field == "geoip2_data_country_code" {
+                add_extractor(field_index, Some("country"), ExtractorFunc::Country);
             }
         }
+        if let Some((field_index, format)) = Self::find_time_field(&log_parser) {
+            add_extractor(field_index, None, ExtractorFunc::Timestamp { format });
+        }
+
+        // Add labels that need several fields
+        let mut combinations = Vec::new();
+        let field = |name: &str| if auto_labels { log_parser.fields().iter().position(|f| f == name) } else { None };
+        if let (Some(sni), Some(host)) = (field("ssl_server_name"), field("host")) {
+            // Flag requests where the TLS SNI doesn't match the Host header
+            combinations.push(Combination {
+                label_index: Self::label(&mut labels, "sni_mismatch"),
+                fields: (sni, host),
+                func: CombinationFunc::Mismatch,
+            });
+        }
 
         LogCollectorBuilder {
             log_parser,
             filename,
             filters: Vec::new(),
             extractors,
+            combinations,
+            composites: Vec::new(),
             labels,
+            data_options: LogDataOptions::default(),
+            processor_options: ProcessorOptions::default(),
+            json_output: None,
+            warmup: None,
+            replay: None,
+            tcp_listener: None,
+            duration_statuses: Vec::new(),
+            strip_path_slash: false,
+            #[cfg(feature = "statsd")]
+            statsd: None,
         }
     }
 
-    pub fn add_filter(&mut self, field: String, func: FilterFunc) -> Result<(), ()> {
-        let field_index = match self.log_parser.fields().iter().position(|f| f == &field) {
-            Some(i) => i,
-            None => {
-                return Err(());
-            }
-        };
-        self.filters.push(Filter {
-            field_index,
-            func,
-        });
-        Ok(())
+    pub fn set_data_options(&mut self, options: LogDataOptions) {
+        self.data_options = options;
     }
 
-    pub fn add_extractor(&mut self, label: Option<String>, field: String, func: ExtractorFunc) -> Result<(), ()> {
-        let label = match label {
-            Some(label) => {
-                let label_index = Self::label(&mut self.labels, &label);
-                Some((label, label_index))
-            }
-            None => None,
-        };
-        let field_index = match self.log_parser.fields().iter().position(|f| f == &field) {
-            Some(i) => i,
-            None => {
-                return Err(());
-            }
-        };
-        self.extractors.push(Extractor {
-            label,
-            field_index,
-            func,
-        });
-        Ok(())
+    pub fn set_processor_options(&mut self, options: ProcessorOptions) {
+        self.processor_options = options;
     }
 
-    pub fn build_processor(self, data: Arc<Mutex<LogData>>) -> LogProcessor {
-        let labels = self.labels.clone();
-
-        let mut filters = self.filters;
-        filters.sort_by(|a, b| a.field_index.cmp(&b.field_index));
-        let mut extractors = self.extractors;
-        extractors.sort_by(|a, b| a.field_index.cmp(&b.field_index));
-
-        LogProcessor {
-            data: data.clone(),
-            filename: self.filename,
-            log_parser: self.log_parser,
-            labels,
-            filters,
-            extractors,
-        }
+    /// Write every parsed line to this output, as a JSON object.
+    pub fn set_json_output(&mut self, output: Box<dyn std::io::Write + Send>) {
+        self.json_output = Some(output);
     }
 
-    pub fn build_data(&self) -> LogData {
-        let label_refs: Vec<&str> = self.labels.iter().map(|v| -> &str { &v }).collect();
-        LogData::new(&label_refs)
+    /// Also send each request to statsd.
+    #[cfg(feature = "statsd")]
+    pub fn set_statsd(&mut self, client: StatsdClient) {
+        self.statsd = Some(client);
     }
 
-    pub fn build(self) -> Result<LogCollector, notify::Error> {
-        let data = self.build_data();
-        let mut desc: Vec<Desc> = Vec::new();
-        desc.extend(data.request_count.desc().into_iter().cloned());
-        desc.extend(data.request_duration.desc().into_iter().cloned());
-        desc.extend(data.response_body_size.desc().into_iter().cloned());
-        desc.extend(data.error_count.desc().into_iter().cloned());
+    /// Put these labels first, in this order, followed by the other labels.
+    /// This has to be called after all the labels are added. If a label
+    /// doesn't exist, it is returned as the error.
+    pub fn set_label_order<'a>(&mut self, order: &[&'a str]) -> Result<(), &'a str> {
+        let mut labels: Vec<String> = Vec::with_capacity(self.labels.len());
+        for &label in order {
+            if !self.labels.iter().any(|l| l == label) {
+                return Err(label);
+            }
+            if !labels.iter().any(|l| l == label) {
+                labels.push(label.to_owned());
+            }
+        }
+        for label in &self.labels {
+            if !labels.contains(label) {
+                labels.push(label.clone());
+            }
+        }
 
-        let data = Arc::new(Mutex::new(data));
+        let new_index = |l: &str| labels.iter().position(|n| n == l).unwrap();
+        for extractor in &mut self.extractors {
+            if let Some((label, index)) = &mut extractor.label {
+                *index = new_index(label);
+            }
+        }
+        for combination in &mut self.combinations {
+            combination.label_index = new_index(&self.labels[combination.label_index]);
+        }
+        for composite in &mut self.composites {
+            composite.label_index = new_index(&self.labels[composite.label_index]);
+            composite.parts = (new_index(&self.labels[composite.parts.0]), new_index(&self.labels[composite.parts.1]));
+        }
+        self.labels = labels;
+        Ok(())
+    }
 
-        let log_processor = self.build_processor(data.clone());
-        log_processor.start_thread();
+    /// Add a label made of the values of two existing labels, joined by
+    /// `separator`. If one of them doesn't exist, it is returned as the
+    /// error.
+    pub fn add_composite_label<'a>(&mut self, label: &str, parts: (&'a str, &'a str), separator: &str) -> Result<(), &'a str> {
+        let index = |l: &'a str| self.labels.iter().position(|n| n == l).ok_or(l);
+        let parts = (index(parts.0)?, index(parts.1)?);
+        let label_index = Self::label(&mut self.labels, label);
+        self.composites.push(Composite {
+            label_index,
+            parts,
+            separator: separator.to_owned(),
+        });
+        Ok(())
+    }
 
-        Ok(LogCollector {
-            desc,
-            data,
-        })
+    /// Count `/a/` as `/a` in the `path` label, however it is extracted.
+    pub fn strip_path_trailing_slash(&mut self) -> Result<(), ()> {
+        if !self.labels.iter().any(|l| l == "path") {
+            return Err(());
+        }
+        self.strip_path_slash = true;
+        Ok(())
     }
-}
 
-pub struct LogCollector {
-    data: Arc<Mutex<LogData>>,
-    desc: Vec<Desc>,
-}
+    /// Report a status code with a different label value.
+    pub fn add_status_alias(&mut self, status: String, alias: String) -> Result<(), ()> {
+        for extractor in &mut self.extractors {
+            if let ExtractorFunc::Status { aliases } = &mut extractor.func {
+                aliases.insert(status, alias);
+                return Ok(());
+            }
+        }
+        Err(())
+    }
 
-impl Collector for LogCollector {
-    fn desc(&self) -> Vec<&Desc> {
-        self.desc.iter().collect()
+    /// Only record the duration of requests whose status matches one of
+    /// these patterns, such as `2xx` or `304`. The other requests are still
+    /// counted.
+    pub fn set_duration_statuses(&mut self, patterns: &[&str]) -> Result<(), ()> {
+        if !self.extractors.iter().any(|e| matches!(e.func, ExtractorFunc::Status { .. })) {
+            return Err(());
+        }
+        self.duration_statuses = patterns.iter().map(|p| p.to_ascii_lowercase()).collect();
+        Ok(())
     }
 
-    fn collect(&self) -> Vec<MetricFamily> {
-        let data = self.data.lock().unwrap();
-        if data.active {
-            let mut metrics = Vec::new();
-            metrics.extend(data.request_count.collect());
-            metrics.extend(data.request_duration.collect());
-            metrics.extend(data.response_body_size.collect());
-            metrics.extend(data.error_count.collect());
-            metrics
+    /// Set the value of a label when its field is empty or "-".
+    pub fn set_label_default(&mut self, label: &str, value: String) -> Result<(), ()> {
+        let mut found = false;
+        for extractor in &mut self.extractors {
+            if let Some((l, _)) = &extractor.label {
+                if l == label {
+                    extractor.default = Some(value.clone());
+                    found = true;
+                }
+            }
+        }
+        if found {
+            Ok(())
         } else {
-            Vec::new()
+            Err(())
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::sync::{Arc, Mutex};
-
-    use crate::collector::LogCollectorBuilder;
-    use crate::log_parser::LogParser;
-    use crate::processor::LogProcessor;
 
-    fn test_parse(processor: &LogProcessor, line: &str, expected: Option<(&[&str], Option<f32>, Option<u64>)>) {
-            let mut label_values = vec![std::borrow::Cow::Borrowed("unk"); processor.labels.len()];
-            let mut duration = None;
-            let mut response_body_size = None;
-            let matched = processor.process_line(
-                line,
-                &mut label_values,
-                &mut duration,
-                &mut response_body_size,
-            ).unwrap();
-            match (matched, expected) {
-                (false, None) => {}
-                (false, Some(_)) => panic!("Line was filtered unexpectedly"),
-                (true, None) => panic!("Line was not filtered"),
-                (true, Some((v, d, s))) => {
-                    assert_eq!(label_values, v);
-                    assert_eq!(duration, d);
-                    assert_eq!(response_body_size, s);
+    /// Set how the times of multiple upstreams are combined.
+    pub fn set_upstream_times(&mut self, policy: UpstreamTimes) -> Result<(), ()> {
+        let mut found = false;
+        for extractor in &mut self.extractors {
+            match &mut extractor.func {
+                ExtractorFunc::UpstreamDuration { policy: p } | ExtractorFunc::UpstreamHeaderTime { policy: p } | ExtractorFunc::UpstreamConnectTime { policy: p } => {
+                    *p = policy;
+                    found = true;
                 }
+                _ => {}
             }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Only count lines for these virtual hosts, from `$host` or else
+    /// `$server_name`.
+    pub fn add_vhost_allow(&mut self, vhosts: &[&str]) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let field_index = fields.iter().position(|f| f == "host")
+            .or_else(|| fields.iter().position(|f| f == "server_name"))
+            .ok_or(())?;
+        self.filters.push(Filter {
+            field_index,
+            func: FilterFunc::Allow {
+                values: vhosts.iter().map(|v| v.to_ascii_lowercase()).collect(),
+            },
+        });
+        Ok(())
+    }
+
+    /// Only count requests with these methods, from `$request_method` or else
+    /// `$request`.
+    pub fn add_method_allow(&mut self, methods: &[&str]) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let (field_index, from_request) = match fields.iter().position(|f| f == "request_method") {
+            Some(i) => (i, false),
+            None => (fields.iter().position(|f| f == "request").ok_or(())?, true),
+        };
+        self.filters.push(Filter {
+            field_index,
+            func: FilterFunc::Method {
+                methods: methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+                from_request,
+            },
+        });
+        Ok(())
+    }
+
+    /// Only count requests that were made over TLS, or only the others if
+    /// `https` is false, from `$scheme`, `$https`, or `$ssl_protocol`
+    /// (whichever is in the format first in that order).
+    pub fn add_https_filter(&mut self, https: bool) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let field = |name: &str| fields.iter().position(|f| f == name);
+        let (field_index, func) = if let Some(i) = field("scheme") {
+            let values = vec!["https".to_owned()].into_iter().collect();
+            (i, if https { FilterFunc::Allow { values } } else { FilterFunc::ExcludeExact { values } })
+        } else if let Some(i) = field("https") {
+            // "on" for TLS, empty otherwise
+            (i, FilterFunc::HasValue { present: https })
+        } else if let Some(i) = field("ssl_protocol") {
+            (i, FilterFunc::HasValue { present: https })
+        } else {
+            return Err(());
+        };
+        self.filters.push(Filter {
+            field_index,
+            func,
+        });
+        Ok(())
+    }
+
+    /// Add an `is_bot` label from `$http_user_agent`, using the built-in
+    /// patterns and these.
+    pub fn add_bot_detect(&mut self, extra_patterns: &[&str], empty_is_bot: bool) -> Result<(), ()> {
+        let field_index = self.log_parser.fields().iter().position(|f| f == "http_user_agent").ok_or(())?;
+        let patterns = BOT_PATTERNS.iter().chain(extra_patterns).map(|p| p.to_lowercase()).collect();
+        self.extractors.push(Extractor {
+            label: Some(("is_bot".to_owned(), Self::label(&mut self.labels, "is_bot"))),
+            default: None,
+            field_index,
+            func: ExtractorFunc::BotDetect { patterns, empty_is_bot },
+        });
+        Ok(())
+    }
+
+    /// Estimate the number of distinct `$remote_addr`, over windows of
+    /// `unique_clients_window`.
+    pub fn add_unique_clients(&mut self) -> Result<(), ()> {
+        let field_index = self.log_parser.fields().iter().position(|f| f == "remote_addr").ok_or(())?;
+        self.extractors.push(Extractor {
+            label: None,
+            default: None,
+            field_index,
+            func: ExtractorFunc::ClientHash,
+        });
+        Ok(())
+    }
+
+    /// Compute the fraction of requests served from the cache, from this
+    /// cache status variable (usually `$upstream_cache_status`).
+    pub fn add_cache_hit_ratio(&mut self, field: &str) -> Result<(), ()> {
+        let field = field.trim_start_matches('$');
+        let field_index = self.log_parser.fields().iter().position(|f| f == field).ok_or(())?;
+        self.extractors.push(Extractor {
+            label: None,
+            default: None,
+            field_index,
+            func: ExtractorFunc::CacheStatus,
+        });
+        Ok(())
+    }
+
+    /// Count the responses by value of a header, from `$sent_http_<header>`.
+    /// The header can be given with or without the `sent_http_` prefix.
+    pub fn add_response_header_count(&mut self, header: &str) -> Result<(), ()> {
+        let header = header.trim_start_matches('$');
+        let header = header.strip_prefix("sent_http_").unwrap_or(header).to_ascii_lowercase().replace('-', "_");
+        let field = format!("sent_http_{}", header);
+        let field_index = self.log_parser.fields().iter().position(|f| f == &field).ok_or(())?;
+        self.extractors.push(Extractor {
+            label: None,
+            default: None,
+            field_index,
+            func: ExtractorFunc::ResponseHeader { header },
+        });
+        Ok(())
+    }
+
+    /// Count the requests by HTTP version, from `$server_protocol` or else
+    /// `$request`, in a separate metric rather than a label.
+    pub fn add_http_version_metric(&mut self) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let (field_index, from_request) = match fields.iter().position(|f| f == "server_protocol") {
+            Some(i) => (i, false),
+            None => (fields.iter().position(|f| f == "request").ok_or(())?, true),
+        };
+        self.extractors.push(Extractor {
+            label: None,
+            default: None,
+            field_index,
+            func: ExtractorFunc::HttpVersion { from_request },
+        });
+        Ok(())
+    }
+
+    /// Add a `size_class` label from `$body_bytes_sent`, with these upper
+    /// bounds for the classes.
+    pub fn add_size_class(&mut self, thresholds: [u64; 4]) -> Result<(), ()> {
+        let field_index = self.log_parser.fields().iter().position(|f| f == "body_bytes_sent").ok_or(())?;
+        self.extractors.push(Extractor {
+            label: Some(("size_class".to_owned(), Self::label(&mut self.labels, "size_class"))),
+            default: None,
+            field_index,
+            func: ExtractorFunc::SizeClass { thresholds },
+        });
+        Ok(())
+    }
+
+    /// Add a `keepalive` label from `$connection_requests`, telling whether
+    /// the connection was reused.
+    pub fn add_keepalive_label(&mut self) -> Result<(), ()> {
+        let field_index = self.log_parser.fields().iter().position(|f| f == "connection_requests").ok_or(())?;
+        self.extractors.push(Extractor {
+            label: Some(("keepalive".to_owned(), Self::label(&mut self.labels, "keepalive"))),
+            default: None,
+            field_index,
+            func: ExtractorFunc::Keepalive,
+        });
+        Ok(())
+    }
+
+    /// Add a `served_from` label from `$upstream_cache_status` and `$status`,
+    /// telling whether the response came from the cache or the origin.
+    pub fn add_served_from(&mut self) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let field = |name: &str| fields.iter().position(|f| f == name);
+        let fields = match (field("upstream_cache_status"), field("status")) {
+            (Some(cache_status), Some(status)) => (cache_status, status),
+            _ => return Err(()),
+        };
+        self.combinations.push(Combination {
+            label_index: Self::label(&mut self.labels, "served_from"),
+            fields,
+            func: CombinationFunc::ServedFrom,
+        });
+        Ok(())
+    }
+
+    /// Get the index and format of the field holding the time of the request.
+    fn find_time_field(log_parser: &LogParser) -> Option<(usize, TimestampFormat)> {
+        for name in TIME_FIELDS {
+            if let Some(i) = log_parser.fields().iter().position(|f| f == name) {
+                return Some((i, TimestampFormat::for_field(name).unwrap()));
+            }
+        }
+        None
+    }
+
+    /// Drop lines whose timestamp is older than `max_age` or further than
+    /// `max_future` in the future (in seconds).
+    pub fn add_time_window(&mut self, max_age: Option<f64>, max_future: Option<f64>) -> Result<(), ()> {
+        let (field_index, format) = Self::find_time_field(&self.log_parser).ok_or(())?;
+        self.filters.push(Filter {
+            field_index,
+            func: FilterFunc::TimeWindow {
+                format,
+                max_age,
+                max_future,
+            },
+        });
+        Ok(())
+    }
+
+    /// On startup, read the lines of the last `seconds` of the file before
+    /// following it.
+    pub fn set_warmup(&mut self, seconds: f64) -> Result<(), ()> {
+        let (field_index, format) = Self::find_time_field(&self.log_parser).ok_or(())?;
+        self.warmup = Some(Warmup { field_index, format, seconds });
+        Ok(())
+    }
+
+    /// Read the lines sent to this socket, from any number of connections,
+    /// instead of the file.
+    pub fn set_tcp_listener(&mut self, listener: TcpListener) {
+        self.tcp_listener = Some(listener);
+    }
+
+    /// Process the lines at the pace of their timestamps, `speed` times
+    /// faster, and record them as if they were written now.
+    pub fn set_replay_speed(&mut self, speed: f64) -> Result<(), ()> {
+        let (field_index, format) = Self::find_time_field(&self.log_parser).ok_or(())?;
+        self.replay = Some(Replay { field_index, format, speed, start: Mutex::new(None) });
+        Ok(())
+    }
+
+    pub fn add_filter(&mut self, field: String, func: FilterFunc) -> Result<(), ()> {
+        let field_index = match self.log_parser.fields().iter().position(|f| f == &field) {
+            Some(i) => i,
+            None => {
+                return Err(());
+            }
+        };
+        self.filters.push(Filter {
+            field_index,
+            func,
+        });
+        Ok(())
+    }
+
+    pub fn add_extractor(&mut self, label: Option<String>, field: String, func: ExtractorFunc) -> Result<(), ()> {
+        let label = match label {
+            Some(label) => {
+                let label_index = Self::label(&mut self.labels, &label);
+                Some((label, label_index))
+            }
+            None => None,
+        };
+        let field_index = match self.log_parser.fields().iter().position(|f| f == &field) {
+            Some(i) => i,
+            None => {
+                return Err(());
+            }
+        };
+        self.extractors.push(Extractor {
+            label,
+            default: None,
+            field_index,
+            func,
+        });
+        Ok(())
+    }
+
+    /// Add a step to the transforms of a label, creating it from `field` (or
+    /// the field with the same name) for the first step.
+    pub fn add_transform(&mut self, label: &str, field: Option<&str>, step: TransformStep) -> Result<(), ()> {
+        let field_index = self.log_parser.fields().iter().position(|f| f == field.unwrap_or(label));
+        for extractor in &mut self.extractors {
+            if let (Some((l, _)), ExtractorFunc::Pipeline { steps }) = (&extractor.label, &mut extractor.func) {
+                if l == label {
+                    if field.is_some() && field_index != Some(extractor.field_index) {
+                        return Err(());
+                    }
+                    steps.push(step);
+                    return Ok(());
+                }
+            }
+        }
+        self.extractors.push(Extractor {
+            label: Some((label.to_owned(), Self::label(&mut self.labels, label))),
+            default: None,
+            field_index: field_index.ok_or(())?,
+            func: ExtractorFunc::Pipeline { steps: vec![step] },
+        });
+        Ok(())
+    }
+
+    /// Add a `path` label from the request, with identifiers replaced by
+    /// placeholders.
+    pub fn add_path_templating(&mut self) -> Result<(), ()> {
+        let fields = self.log_parser.fields();
+        let (field_index, from_request) = match ["uri", "request_uri"].iter().find_map(|name| fields.iter().position(|f| f == name)) {
+            Some(i) => (i, false),
+            None => (fields.iter().position(|f| f == "request").ok_or(())?, true),
+        };
+        self.extractors.push(Extractor {
+            label: Some(("path".to_owned(), Self::label(&mut self.labels, "path"))),
+            default: None,
+            field_index,
+            func: ExtractorFunc::TemplatedPath { from_request },
+        });
+        Ok(())
+    }
+
+    pub fn build_processor(self, data: Arc<Mutex<LogData>>) -> LogProcessor {
+        let labels = self.labels.clone();
+
+        let mut filters = self.filters;
+        filters.sort_by_key(|a| a.field_index);
+        let mut extractors = self.extractors;
+        extractors.sort_by_key(|a| a.field_index);
+
+        let recent_lines = self.processor_options.dedup_window.map(|n| Mutex::new(RecentLines::new(n)));
+        // The metric is shared, and reset in place
+        let extractor_duration = data.lock().unwrap().extractor_duration.clone();
+        LogProcessor {
+            data: data.clone(),
+            options: self.processor_options,
+            seen_series: Mutex::new(HashSet::new()),
+            seen_values: Mutex::new(vec![HashSet::new(); labels.len()]),
+            generation: AtomicU64::new(0),
+            filename: self.filename,
+            log_parser: self.log_parser,
+            labels,
+            filters,
+            extractors,
+            combinations: self.combinations,
+            composites: self.composites,
+            extractor_duration,
+            clock: timestamp::now,
+            json_output: self.json_output.map(Mutex::new),
+            recent_lines,
+            warmup: self.warmup,
+            replay: self.replay,
+            tcp_listener: self.tcp_listener,
+            workers: Mutex::new(None),
+            last_line: Mutex::new(Instant::now()),
+            duration_statuses: self.duration_statuses,
+            path_slash_label: if self.strip_path_slash { self.labels.iter().position(|l| l == "path") } else { None },
+            #[cfg(feature = "statsd")]
+            statsd: self.statsd,
+        }
+    }
+
+    pub fn build_data(&self) -> LogData {
+        let label_refs: Vec<&str> = self.labels.iter().map(|v| -> &str { v }).collect();
+        let mut data = LogData::new(&label_refs, &self.data_options, &self.extractors);
+        // Set by the processor's timer
+        if self.processor_options.idle_timeout.is_some() {
+            data.idle = Some(IntGauge::with_opts(self.data_options.opts("log_idle", "Whether no lines were processed for the idle timeout (1) or not (0)")).unwrap());
+        }
+        data
+    }
+
+    pub fn build(self) -> Result<LogCollector, notify::Error> {
+        let data = self.build_data();
+        let exemplars = data.request_duration_exemplars.clone();
+        let mut desc: Vec<Desc> = Vec::new();
+        for metric in data.status_metrics().into_iter().chain(data.log_metrics()) {
+            desc.extend(metric.desc().into_iter().cloned());
+        }
+
+        let data = Arc::new(Mutex::new(data));
+
+        let log_processor = self.build_processor(data.clone());
+        log_processor.start_thread();
+
+        Ok(LogCollector {
+            desc,
+            data,
+            exemplars,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct LogCollector {
+    data: Arc<Mutex<LogData>>,
+    desc: Vec<Desc>,
+    exemplars: Option<SharedExemplars>,
+}
+
+impl LogCollector {
+    /// The exemplars for the OpenMetrics format, if the log has request IDs.
+    pub fn exemplars(&self) -> Option<SharedExemplars> {
+        self.exemplars.clone()
+    }
+
+    /// Set the metrics back to zero, while the log keeps being watched.
+    pub fn reset(&self) {
+        self.data.lock().unwrap().reset();
+    }
+
+    /// The results of the recent lines as JSON, for debugging, if they are
+    /// kept.
+    pub fn last_lines(&self) -> Option<String> {
+        let data = self.data.lock().unwrap();
+        let debug_lines = data.debug_lines.as_ref()?;
+        let out = format!("{{\"lines\":{}}}", debug_lines.lock().unwrap().to_json());
+        Some(out)
+    }
+
+    /// The current values of the metrics as JSON, for debugging: the value
+    /// of counters and gauges, and the count and sum of histograms.
+    pub fn snapshot(&self) -> String {
+        let families = self.collect();
+        let active = self.data.lock().unwrap().active;
+
+        let number = |out: &mut String, value: f64| {
+            if value.is_finite() {
+                out.push_str(&value.to_string());
+            } else {
+                out.push_str("null");
+            }
+        };
+        let mut out = format!("{{\"active\":{},\"metrics\":{{", active);
+        for (i, family) in families.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            json_string(&mut out, family.get_name());
+            out.push_str(":[");
+            for (j, metric) in family.get_metric().iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"labels\":{");
+                for (k, pair) in metric.get_label().iter().enumerate() {
+                    if k > 0 {
+                        out.push(',');
+                    }
+                    json_string(&mut out, pair.get_name());
+                    out.push(':');
+                    json_string(&mut out, pair.get_value());
+                }
+                out.push('}');
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        out.push_str(",\"value\":");
+                        number(&mut out, metric.get_counter().get_value());
+                    }
+                    MetricType::GAUGE => {
+                        out.push_str(",\"value\":");
+                        number(&mut out, metric.get_gauge().get_value());
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        out.push_str(&format!(",\"count\":{},\"sum\":", histogram.get_sample_count()));
+                        number(&mut out, histogram.get_sample_sum());
+                    }
+                    // Not produced by this program
+                    MetricType::SUMMARY | MetricType::UNTYPED => {}
+                }
+                out.push('}');
+            }
+            out.push(']');
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+impl Collector for LogCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.desc.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let data = self.data.lock().unwrap();
+        let mut metrics = Vec::new();
+        for metric in data.status_metrics() {
+            metrics.extend(metric.collect());
+        }
+        if data.active {
+            data.update_error_ratio();
+            data.update_cache_hit_ratio();
+            data.update_unique_clients();
+            for metric in data.log_metrics() {
+                metrics.extend(metric.collect());
+            }
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::core::Collector;
+    use std::sync::{Arc, Mutex};
+
+    use crate::collector::{LogCollectorBuilder, LogDataOptions};
+    use crate::log_parser::LogParser;
+    use crate::processor::{DEFAULT_SIZE_THRESHOLDS, ExtractorFunc, FilterFunc, LineStatus, LogProcessor, Measurements, ProcessorOptions, TransformStep, UpstreamTimes};
+
+    fn test_parse(processor: &LogProcessor, line: &str, expected: Option<(&[&str], Option<f32>, Option<u64>)>) {
+            let values = processor.parse_line(line).unwrap();
+            let mut label_values = vec![std::borrow::Cow::Borrowed("unk"); processor.labels.len()];
+            let mut measurements = Measurements::default();
+            let matched = processor.process_line(
+                &values,
+                &mut label_values,
+                &mut measurements,
+            ).unwrap();
+            match (matched == LineStatus::Accepted, expected) {
+                (false, None) => {}
+                (false, Some(_)) => panic!("Line was filtered unexpectedly"),
+                (true, None) => panic!("Line was not filtered"),
+                (true, Some((v, d, s))) => {
+                    assert_eq!(label_values, v);
+                    assert_eq!(measurements.duration, d);
+                    assert_eq!(measurements.response_body_size, s);
+                }
+            }
+    }
+
+    #[test]
+    fn test_process() {
+        let log_parser = LogParser::from_format(
+            r#"$host $remote_addr - $remote_user [$time_local] "$request" $status $request_time $body_bytes_sent "$http_referer" "$http_user_agent""#,
+        ).unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(
+            &processor,
+            r#"example.org 1.2.3.4 - - [11/Nov/2021:02:34:39 +0000] "GET /api/v4/pets/1 HTTP/1.1" 200 0.092 263 "-" "Mozilla/5.0 (Linux)""#,
+            Some((
+                &["example.org", "no", "200"],
+                Some(0.092),
+                Some(263),
+            )),
+        );
+        test_parse(
+            &processor,
+            r#"remram.fr 8.8.8.8 - person [11/Nov/2021:02:34:41 +0000] "POST /api/v4/pets HTTP/1.1" 201 0.132 14 "-" "Mozilla/5.0 (Linux)""#,
+            Some((
+                &["remram.fr", "yes", "201"],
+                Some(0.132),
+                Some(14),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_country() {
+        let log_parser = LogParser::from_format(
+            r#"$host $remote_addr [$time_local] "$request" $status "$geoip_country_code""#,
+        ).unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(
+            &processor,
+            r#"example.org 1.2.3.4 [11/Nov/2021:02:34:39 +0000] "GET / HTTP/1.1" 200 "US""#,
+            Some((&["example.org", "200", "US"], None, None)),
+        );
+        test_parse(
+            &processor,
+            r#"example.org 10.0.0.1 [11/Nov/2021:02:34:39 +0000] "GET / HTTP/1.1" 200 """#,
+            Some((&["example.org", "200", "unknown"], None, None)),
+        );
+    }
+
+    #[test]
+    fn test_server_name() {
+        let log_parser = LogParser::from_format("$host $server_name $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "evil.example.com example.org 200", Some((&["evil.example.com", "example.org", "200"], None, None)));
+    }
+
+    #[test]
+    fn test_lowercase_labels() {
+        let log_parser = LogParser::from_format("$host $request_method $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            lowercase_labels: true,
+            ..Default::default()
+        });
+        collector_builder.set_label_default("vhost", "Unknown".to_owned()).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        test_parse(&processor, "Example.org GET 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "- GET 200", Some((&["unknown", "200"], None, None)));
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "Example.org GET 200");
+        processor.handle_line(&data, "example.org GET 200");
+        processor.handle_line(&data, "EXAMPLE.ORG GET 200");
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 1);
+    }
+
+    #[test]
+    fn test_server_port() {
+        let log_parser = LogParser::from_format("$host $scheme $server_port $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org https 8443 200", Some((&["example.org", "https", "8443", "200"], None, None)));
+        test_parse(&processor, "example.org http 80 200", Some((&["example.org", "http", "80", "200"], None, None)));
+        test_parse(&processor, "example.org - abc 200", Some((&["example.org", "unk", "unk", "200"], None, None)));
+        test_parse(&processor, "example.org http 99999 200", Some((&["example.org", "http", "unk", "200"], None, None)));
+    }
+
+    #[test]
+    fn test_label_default() {
+        let log_parser = LogParser::from_format("$host $server_name $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_label_default("vhost", "default_server".to_owned()).unwrap();
+        assert!(collector_builder.set_label_default("country", "unknown".to_owned()).is_err());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org example.org 200", Some((&["example.org", "example.org", "200"], None, None)));
+        test_parse(&processor, "- _ 400", Some((&["default_server", "_", "400"], None, None)));
+        // Labels without a default keep the raw value
+        test_parse(&processor, "- - 400", Some((&["default_server", "-", "400"], None, None)));
+    }
+
+    #[test]
+    fn test_status_alias() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        // --status-alias 301,302,307,308=redirect
+        for status in &["301", "302", "307", "308"] {
+            collector_builder.add_status_alias(status.to_string(), "redirect".to_owned()).unwrap();
+        }
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 302", Some((&["example.org", "redirect"], None, None)));
+        test_parse(&processor, "example.org 308", Some((&["example.org", "redirect"], None, None)));
+        test_parse(&processor, "example.org 304", Some((&["example.org", "304"], None, None)));
+        test_parse(&processor, "example.org 200", Some((&["example.org", "200"], None, None)));
+    }
+
+    #[test]
+    fn test_vhost_allow() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_vhost_allow(&["example.org", "app.example.org"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "App.Example.org 200", Some((&["App.Example.org", "200"], None, None)));
+        test_parse(&processor, "remram.fr 200", None);
+        test_parse(&processor, "www.example.org 200", None);
+
+        // Falls back to $server_name
+        let log_parser = LogParser::from_format("$server_name $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_vhost_allow(&["example.org"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "remram.fr 200", None);
+
+        let log_parser = LogParser::from_format("$status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_vhost_allow(&["example.org"]).is_err());
+    }
+
+    #[test]
+    fn test_https_filter() {
+        // Without the automatic labels, so $scheme is not a label
+        let formats = [
+            ("$scheme $status", "https 200", "http 200"),
+            ("$https $status", "on 200", "- 200"),
+            ("$ssl_protocol $status", "TLSv1.3 200", "- 200"),
+        ];
+        for &(format, tls_line, plain_line) in &formats {
+            let log_parser = LogParser::from_format(format).unwrap();
+            let mut collector_builder = LogCollectorBuilder::new_bare(log_parser, "/tmp/access.log".into());
+            collector_builder.add_https_filter(true).unwrap();
+            let data = Arc::new(Mutex::new(collector_builder.build_data()));
+            let processor = collector_builder.build_processor(data);
+            test_parse(&processor, tls_line, Some((&[], None, None)));
+            test_parse(&processor, plain_line, None);
+
+            let log_parser = LogParser::from_format(format).unwrap();
+            let mut collector_builder = LogCollectorBuilder::new_bare(log_parser, "/tmp/access.log".into());
+            collector_builder.add_https_filter(false).unwrap();
+            let data = Arc::new(Mutex::new(collector_builder.build_data()));
+            let processor = collector_builder.build_processor(data);
+            test_parse(&processor, tls_line, None);
+            test_parse(&processor, plain_line, Some((&[], None, None)));
+        }
+
+        // An empty value is not TLS either
+        let log_parser = LogParser::from_format("\"$https\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_https_filter(true).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "\"on\" 200", Some((&["200"], None, None)));
+        test_parse(&processor, "\"\" 200", None);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_https_filter(true).is_err());
+    }
+
+    #[test]
+    fn test_sni_mismatch() {
+        let log_parser = LogParser::from_format("$ssl_server_name $host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        assert_eq!(processor.labels, vec!["vhost", "status", "sni_mismatch"]);
+
+        test_parse(&processor, "example.org example.org 200", Some((&["example.org", "200", "no"], None, None)));
+        test_parse(&processor, "example.org Example.ORG 200", Some((&["Example.ORG", "200", "no"], None, None)));
+        test_parse(&processor, "example.org remram.fr 200", Some((&["remram.fr", "200", "yes"], None, None)));
+        // No SNI, e.g. plain HTTP
+        test_parse(&processor, "- remram.fr 200", Some((&["remram.fr", "200", "no"], None, None)));
+    }
+
+    #[test]
+    fn test_filter_range() {
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter("request_time".to_owned(), FilterFunc::Range { min: 1.0, max: 5.0 }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200 0.5", None);
+        test_parse(&processor, "example.org 200 1.0", Some((&["example.org", "200"], Some(1.0), None)));
+        test_parse(&processor, "example.org 200 2.5", Some((&["example.org", "200"], Some(2.5), None)));
+        test_parse(&processor, "example.org 200 5.0", Some((&["example.org", "200"], Some(5.0), None)));
+        test_parse(&processor, "example.org 200 5.5", None);
+        test_parse(&processor, "example.org 200 -", None);
+    }
+
+    #[test]
+    fn test_exclude() {
+        let log_parser = LogParser::from_format("$host $uri $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter(
+            "uri".to_owned(),
+            FilterFunc::ExcludeExact { values: ["/healthz", "/ping"].iter().map(|v| v.to_string()).collect() },
+        ).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org /healthz 200", None);
+        test_parse(&processor, "example.org /ping 200", None);
+        // Only exact matches are dropped
+        test_parse(&processor, "example.org /healthz/db 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org /PING 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org /api/ping 200", Some((&["example.org", "200"], None, None)));
+    }
+
+    #[test]
+    fn test_has_value() {
+        let log_parser = LogParser::from_format("$host $status \"$upstream_response_time\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter("upstream_response_time".to_owned(), FilterFunc::HasValue { present: true }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200 \"0.5\"", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org 200 \"-\"", None);
+        test_parse(&processor, "example.org 200 \"\"", None);
+
+        let log_parser = LogParser::from_format("$host $status \"$upstream_response_time\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter("upstream_response_time".to_owned(), FilterFunc::HasValue { present: false }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200 \"0.5\"", None);
+        test_parse(&processor, "example.org 304 \"-\"", Some((&["example.org", "304"], None, None)));
+    }
+
+    #[test]
+    fn test_set_match() {
+        use crate::value_set::SetFile;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-clients.txt");
+        std::fs::write(&path, "10.0.0.1\n10.0.0.2\n").unwrap();
+        let file = SetFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let log_parser = LogParser::from_format("$remote_addr $host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter("remote_addr".to_owned(), FilterFunc::SetMatch { set: file.set.clone(), exclude: false }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "10.0.0.1 example.org 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "10.0.0.3 example.org 200", None);
+
+        let log_parser = LogParser::from_format("$remote_addr $host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_filter("remote_addr".to_owned(), FilterFunc::SetMatch { set: file.set.clone(), exclude: true }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "10.0.0.1 example.org 200", None);
+        test_parse(&processor, "10.0.0.3 example.org 200", Some((&["example.org", "200"], None, None)));
+
+        // Changes to the set apply right away
+        file.set.write().unwrap().insert("10.0.0.3".to_owned());
+        test_parse(&processor, "10.0.0.3 example.org 200", None);
+    }
+
+    #[test]
+    fn test_method_allow() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_method_allow(&["get", "POST"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org \"GET / HTTP/1.1\" 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org \"POST /api HTTP/1.1\" 201", Some((&["example.org", "201"], None, None)));
+        test_parse(&processor, "example.org \"DELETE /api/1 HTTP/1.1\" 204", None);
+        test_parse(&processor, "example.org \"GETS / HTTP/1.1\" 400", None);
+        test_parse(&processor, "example.org \"-\" 400", None);
+
+        // Uses $request_method if available
+        let log_parser = LogParser::from_format("$host $request_method $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_method_allow(&["GET"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org GET 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org HEAD 200", None);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_method_allow(&["GET"]).is_err());
+    }
+
+    #[test]
+    fn test_served_from() {
+        let format = "$host $status $upstream_cache_status";
+        // Not added unless asked for, so existing series keep their labels
+        assert_eq!(LogCollectorBuilder::new(LogParser::from_format(format).unwrap(), "/tmp/access.log".into()).labels, vec!["vhost", "status"]);
+        let log_parser = LogParser::from_format(format).unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_served_from().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        assert_eq!(processor.labels, vec!["vhost", "status", "served_from"]);
+
+        for cache_status in &["HIT", "STALE", "UPDATING", "REVALIDATED"] {
+            let line = format!("example.org 200 {}", cache_status);
+            test_parse(&processor, &line, Some((&["example.org", "200", "cache"], None, None)));
+        }
+        for cache_status in &["MISS", "BYPASS", "EXPIRED", "-"] {
+            let line = format!("example.org 200 {}", cache_status);
+            test_parse(&processor, &line, Some((&["example.org", "200", "origin"], None, None)));
+        }
+        test_parse(&processor, "example.org 502 MISS", Some((&["example.org", "502", "error"], None, None)));
+        test_parse(&processor, "example.org 500 -", Some((&["example.org", "500", "error"], None, None)));
+        test_parse(&processor, "example.org 404 HIT", Some((&["example.org", "404", "cache"], None, None)));
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_served_from().is_err());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let log_parser = LogParser::from_format("$host [$time_local] $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_time_window(Some(3600.0), Some(60.0)).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let line = |offset: i64| {
+            let time = chrono::TimeZone::timestamp_opt(&chrono::Utc, crate::timestamp::now() as i64 + offset, 0).unwrap();
+            format!("example.org [{}] 200", time.format("%d/%b/%Y:%H:%M:%S %z"))
+        };
+        let data = data.lock().unwrap();
+        // In window
+        processor.handle_line(&data, &line(0));
+        processor.handle_line(&data, &line(-1800));
+        // Too old
+        processor.handle_line(&data, &line(-7200));
+        processor.handle_line(&data, "example.org [11/Nov/2021:02:34:39 +0000] 200");
+        // In the future
+        processor.handle_line(&data, &line(600));
+        // Not a timestamp
+        processor.handle_line(&data, "example.org [yesterday] 200");
+
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 2);
+        assert_eq!(data.out_of_time_window_count.get(), 3);
+        assert_eq!(data.error_count.get(), 1);
+    }
+
+    #[test]
+    fn test_map_label() {
+        let log_parser = LogParser::from_format("$host $status $pipe").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let mut values = std::collections::HashMap::new();
+        values.insert("p".to_owned(), "yes".to_owned());
+        values.insert(".".to_owned(), "no".to_owned());
+        collector_builder.add_extractor(Some("pipelined".to_owned()), "pipe".to_owned(), ExtractorFunc::Map { values }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200 p", Some((&["example.org", "200", "yes"], None, None)));
+        test_parse(&processor, "example.org 200 .", Some((&["example.org", "200", "no"], None, None)));
+        test_parse(&processor, "example.org 200 x", Some((&["example.org", "200", "other"], None, None)));
+    }
+
+    #[test]
+    fn test_split_label() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_extractor(Some("path".to_owned()), "request".to_owned(), ExtractorFunc::Split { delimiter: " ".to_owned(), index: 1 }).unwrap();
+        collector_builder.add_extractor(Some("tenant".to_owned()), "host".to_owned(), ExtractorFunc::Split { delimiter: ".".to_owned(), index: 0 }).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "acme.example.org \"GET /pets HTTP/1.1\" 200", Some((&["acme.example.org", "200", "/pets", "acme"], None, None)));
+        // Out of range
+        test_parse(&processor, "localhost \"-\" 400", Some((&["localhost", "400", "unk", "localhost"], None, None)));
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_extractor(Some("path".to_owned()), "request".to_owned(), ExtractorFunc::Split { delimiter: " ".to_owned(), index: 1 }).is_err());
+    }
+
+    #[test]
+    fn test_path_templating() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_path_templating().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org \"GET /api/v4/pets/12 HTTP/1.1\" 200", Some((&["example.org", "200", "/api/v4/pets/:id"], None, None)));
+        test_parse(
+            &processor,
+            "example.org \"GET /users/123e4567-e89b-12d3-a456-426614174000?tab=1 HTTP/1.1\" 200",
+            Some((&["example.org", "200", "/users/:uuid"], None, None)),
+        );
+        test_parse(&processor, "example.org \"GET /blob/507f1f77bcf86cd799439011 HTTP/1.1\" 200", Some((&["example.org", "200", "/blob/:id"], None, None)));
+        test_parse(&processor, "example.org \"-\" 400", Some((&["example.org", "400", "other"], None, None)));
+
+        // $uri is preferred
+        let log_parser = LogParser::from_format("$host \"$request\" $uri $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_path_templating().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org \"GET /a%2F1 HTTP/1.1\" /a/1 200", Some((&["example.org", "200", "/a/:id"], None, None)));
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_path_templating().is_err());
+    }
+
+    #[test]
+    fn test_path_trailing_slash() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_path_templating().unwrap();
+        collector_builder.strip_path_trailing_slash().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org \"GET /api/v4/pets HTTP/1.1\" 200", Some((&["example.org", "200", "/api/v4/pets"], None, None)));
+        test_parse(&processor, "example.org \"GET /api/v4/pets/ HTTP/1.1\" 200", Some((&["example.org", "200", "/api/v4/pets"], None, None)));
+        test_parse(&processor, "example.org \"GET /api/v4/pets/12/?page=2 HTTP/1.1\" 200", Some((&["example.org", "200", "/api/v4/pets/:id"], None, None)));
+        test_parse(&processor, "example.org \"GET / HTTP/1.1\" 200", Some((&["example.org", "200", "/"], None, None)));
+        test_parse(&processor, "example.org \"GET // HTTP/1.1\" 200", Some((&["example.org", "200", "/"], None, None)));
+
+        // Kept by default
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_path_templating().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org \"GET /api/v4/pets/ HTTP/1.1\" 200", Some((&["example.org", "200", "/api/v4/pets/"], None, None)));
+
+        // With another extractor, before the label is used in composites
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.strip_path_trailing_slash().is_err());
+        collector_builder.add_extractor(Some("path".to_owned()), "request".to_owned(), ExtractorFunc::Split { delimiter: " ".to_owned(), index: 1 }).unwrap();
+        collector_builder.add_composite_label("status_path", ("status", "path"), " ").unwrap();
+        collector_builder.strip_path_trailing_slash().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org \"GET /static/ HTTP/1.1\" 200", Some((&["example.org", "200", "/static", "200 /static"], None, None)));
+    }
+
+    #[test]
+    fn test_hash_label() {
+        let log_parser = LogParser::from_format("$host $status $http_x_api_key").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_extractor(Some("api_key".to_owned()), "http_x_api_key".to_owned(), ExtractorFunc::Hash).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        // sha256("abc") = ba7816bf...
+        test_parse(&processor, "example.org 200 abc", Some((&["example.org", "200", "ba7816bf"], None, None)));
+        test_parse(&processor, "example.org 200 -", Some((&["example.org", "200", "none"], None, None)));
+    }
+
+    #[test]
+    fn test_bot_detect() {
+        let log_parser = LogParser::from_format("$host $status \"$http_user_agent\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_bot_detect(&["MyMonitor"], true).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(
+            &processor,
+            "example.org 200 \"Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)\"",
+            Some((&["example.org", "200", "yes"], None, None)),
+        );
+        test_parse(
+            &processor,
+            "example.org 200 \"Mozilla/5.0 (X11; Linux x86_64; rv:94.0) Gecko/20100101 Firefox/94.0\"",
+            Some((&["example.org", "200", "no"], None, None)),
+        );
+        test_parse(&processor, "example.org 200 \"curl/7.79.1\"", Some((&["example.org", "200", "yes"], None, None)));
+        test_parse(&processor, "example.org 200 \"mymonitor 1.0\"", Some((&["example.org", "200", "yes"], None, None)));
+        test_parse(&processor, "example.org 200 \"\"", Some((&["example.org", "200", "yes"], None, None)));
+        test_parse(&processor, "example.org 200 \"-\"", Some((&["example.org", "200", "yes"], None, None)));
+
+        // Empty user agents can be counted as browsers
+        let log_parser = LogParser::from_format("$host $status \"$http_user_agent\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_bot_detect(&[], false).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org 200 \"\"", Some((&["example.org", "200", "no"], None, None)));
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_bot_detect(&[], true).is_err());
+    }
+
+    #[test]
+    fn test_upstream_duration() {
+        let log_parser = LogParser::from_format("$host $status $request_time \"$upstream_response_time\"").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 502 0.750 \"0.250, 0.500\"");
+        processor.handle_line(&data, "example.org 502 0.500 \"-, 0.500\"");
+        let upstream_duration = data.upstream_duration.as_ref().unwrap().with_label_values(&["example.org", "502"]);
+        assert_eq!(upstream_duration.get_sample_count(), 2);
+        assert_eq!(upstream_duration.get_sample_sum(), 1.25);
+        assert_eq!(data.error_count.get(), 0);
+
+        // Use the last upstream
+        let log_parser = LogParser::from_format("$host $status $request_time \"$upstream_response_time\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_upstream_times(UpstreamTimes::Last).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 502 0.750 \"0.250, 0.500\"");
+        let upstream_duration = data.upstream_duration.as_ref().unwrap().with_label_values(&["example.org", "502"]);
+        assert_eq!(upstream_duration.get_sample_sum(), 0.5);
+
+        // No histogram without the field
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.build_data().upstream_duration.is_none());
+        assert!(collector_builder.set_upstream_times(UpstreamTimes::Last).is_err());
+    }
+
+    #[test]
+    fn test_upstream_connect_time() {
+        let log_parser = LogParser::from_format("$host $status \"$upstream_connect_time\"").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 \"0.004\"");
+        processor.handle_line(&data, "example.org 502 \"0.5, 0.25\"");
+        processor.handle_line(&data, "example.org 200 \"-\"");
+        let connect_time = data.upstream_connect_time.as_ref().unwrap();
+        assert_eq!(connect_time.with_label_values(&["example.org", "200"]).get_sample_count(), 1);
+        assert_eq!(connect_time.with_label_values(&["example.org", "200"]).get_sample_sum() as f32, 0.004);
+        assert_eq!(connect_time.with_label_values(&["example.org", "502"]).get_sample_sum(), 0.75);
+        assert_eq!(data.error_count.get(), 0);
+        assert!(data.upstream_duration.is_none());
+        assert!(data.upstream_header_time.is_none());
+    }
+
+    #[test]
+    fn test_upstream_header_time() {
+        let log_parser = LogParser::from_format("$host $status \"$upstream_header_time\"").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 \"0.125\"");
+        processor.handle_line(&data, "example.org 200 \"-, 0.250\"");
+        processor.handle_line(&data, "example.org 200 \"-\"");
+        let upstream_header_time = data.upstream_header_time.as_ref().unwrap().with_label_values(&["example.org", "200"]);
+        assert_eq!(upstream_header_time.get_sample_count(), 2);
+        assert_eq!(upstream_header_time.get_sample_sum(), 0.375);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.error_count.get(), 0);
+        assert!(data.upstream_duration.is_none());
+    }
+
+    #[test]
+    fn test_exemplars() {
+        use prometheus::core::Collector;
+
+        let log_parser = LogParser::from_format("$host $status $request_time $request_id").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let mut processor = collector_builder.build_processor(data.clone());
+        processor.clock = || 1636598079.5;
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 0.250 4a0f1d3e");
+        processor.handle_line(&data, "example.org 200 3.000 9be8c227");
+        processor.handle_line(&data, "example.org 200 0.200 -");
+
+        let exemplars = data.request_duration_exemplars.as_ref().unwrap().lock().unwrap();
+        let text = crate::openmetrics::encode(&data.request_duration.collect(), &[&exemplars]);
+        assert!(text.contains("request_duration_bucket{status=\"200\",vhost=\"example.org\",le=\"0.25\"} 2 # {request_id=\"4a0f1d3e\"} 0.25 1636598079.5\n"));
+        assert!(text.contains("request_duration_bucket{status=\"200\",vhost=\"example.org\",le=\"5\"} 3 # {request_id=\"9be8c227\"} 3 1636598079.5\n"));
+        assert!(text.contains("request_duration_bucket{status=\"200\",vhost=\"example.org\",le=\"1\"} 2\n"));
+    }
+
+    #[test]
+    fn test_response_body_size_per_status() {
+        let log_parser = LogParser::from_format("$host $status $body_bytes_sent").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 1200");
+        processor.handle_line(&data, "example.org 200 800");
+        processor.handle_line(&data, "example.org 502 150000");
+
+        let ok = data.response_body_size.with_label_values(&["example.org", "200"]);
+        assert_eq!(ok.get_sample_count(), 2);
+        assert_eq!(ok.get_sample_sum(), 2000.0);
+        let error = data.response_body_size.with_label_values(&["example.org", "502"]);
+        assert_eq!(error.get_sample_count(), 1);
+        assert_eq!(error.get_sample_sum(), 150000.0);
+    }
+
+    #[test]
+    fn test_requests_per_connection() {
+        let log_parser = LogParser::from_format("$host $status $connection $connection_requests").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 1234 1");
+        processor.handle_line(&data, "example.org 200 1234 2");
+        processor.handle_line(&data, "example.org 200 1234 7");
+        processor.handle_line(&data, "example.org 200 1234 -");
+
+        let requests_per_connection = data.requests_per_connection.as_ref().unwrap().with_label_values(&["example.org", "200"]);
+        assert_eq!(requests_per_connection.get_sample_count(), 3);
+        assert_eq!(requests_per_connection.get_sample_sum(), 10.0);
+        assert_eq!(data.error_count.get(), 1);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+    }
+
+    #[test]
+    fn test_json_output() {
+        /// Writes to a buffer that can be read from the test.
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let log_parser = LogParser::from_format(r#"$host "$request" $status"#).unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let buffer = SharedBuffer::default();
+        collector_builder.set_json_output(Box::new(buffer.clone()));
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, r#"example.org "GET / HTTP/1.1" 200"#);
+        processor.handle_line(&data, "invalid line");
+        processor.handle_line(&data, r#"remram.fr "POST /login HTTP/1.1" 302"#);
+
+        assert_eq!(
+            std::str::from_utf8(&buffer.0.lock().unwrap()).unwrap(),
+            concat!(
+                r#"{"host":"example.org","request":"GET / HTTP/1.1","status":"200"}"#, "\n",
+                r#"{"host":"remram.fr","request":"POST /login HTTP/1.1","status":"302"}"#, "\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn test_duration_sum() {
+        let log_parser = LogParser::from_format(
+            r#"$host $remote_addr - $remote_user [$time_local] "$request" $status $request_time $body_bytes_sent "$http_referer" "$http_user_agent""#,
+        ).unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            duration_sum: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, r#"example.org 1.2.3.4 - - [11/Nov/2021:02:34:39 +0000] "GET /api/v4/pets/1 HTTP/1.1" 200 0.25 263 "-" "Mozilla/5.0 (Linux)""#);
+        processor.handle_line(&data, r#"example.org 8.8.8.8 - - [11/Nov/2021:02:34:41 +0000] "GET /api/v4/pets/2 HTTP/1.1" 200 0.5 14 "-" "Mozilla/5.0 (Linux)""#);
+        let sum = data.request_duration_sum.as_ref().unwrap();
+        assert_eq!(sum.with_label_values(&["example.org", "no", "200"]).get(), 0.75);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "no", "200"]).get(), 2);
+    }
+
+    #[test]
+    fn test_profile_extractors() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_transform("path", Some("request"), TransformStep::Uri).unwrap();
+        collector_builder.set_data_options(LogDataOptions {
+            profile_extractors: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org \"GET /pets HTTP/1.1\" 200 0.25");
+        processor.handle_line(&data, "example.org \"GET /toys HTTP/1.1\" 404 0.5");
+        let extractor_duration = data.extractor_duration.as_ref().unwrap();
+        for &label in &["vhost", "path", "status", "request_time"] {
+            assert_eq!(extractor_duration.with_label_values(&[label]).get_sample_count(), 2, "{}", label);
+        }
+
+        // Not recorded by default
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.build_data().extractor_duration.is_none());
+    }
+
+    #[test]
+    fn test_duration_status() {
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_status_alias("304".to_owned(), "not_modified".to_owned()).unwrap();
+        collector_builder.set_duration_statuses(&["2xx", "304"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        test_parse(&processor, "example.org 200 0.25", Some((&["example.org", "200"], Some(0.25), None)));
+        test_parse(&processor, "example.org 500 1.5", Some((&["example.org", "500"], None, None)));
+        // Matched on the code, not the alias
+        test_parse(&processor, "example.org 304 0.125", Some((&["example.org", "not_modified"], Some(0.125), None)));
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 0.25");
+        processor.handle_line(&data, "example.org 500 1.5");
+        assert_eq!(data.request_count.with_label_values(&["example.org", "500"]).get(), 1);
+        assert_eq!(data.request_duration.with_label_values(&["example.org", "500"]).get_sample_count(), 0);
+        assert_eq!(data.request_duration.with_label_values(&["example.org", "200"]).get_sample_count(), 1);
+
+        let log_parser = LogParser::from_format("$host $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.set_duration_statuses(&["2xx"]).is_err());
+    }
+
+    #[test]
+    fn test_histogram_counters() {
+        use prometheus::Encoder;
+
+        use crate::collector::LogCollector;
+
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            histogram_counters: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        {
+            let data = data.lock().unwrap();
+            processor.handle_line(&data, "example.org 200 0.25");
+            processor.handle_line(&data, "example.org 200 0.5");
+        }
+        data.lock().unwrap().active = true;
+        let collector = LogCollector { data, desc: Vec::new(), exemplars: None };
+
+        let registry = prometheus::Registry::new();
+        registry.register(Box::new(collector)).unwrap();
+        let mut buffer = Vec::new();
+        prometheus::TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("# TYPE request_duration_seconds_count counter\n"));
+        assert!(output.contains("request_duration_seconds_count{status=\"200\",vhost=\"example.org\"} 2\n"));
+        assert!(output.contains("# TYPE request_duration_seconds_sum counter\n"));
+        assert!(output.contains("request_duration_seconds_sum{status=\"200\",vhost=\"example.org\"} 0.75\n"));
+        // Alongside the histogram
+        assert!(output.contains("request_duration_count{status=\"200\",vhost=\"example.org\"} 2\n"));
+        assert!(output.contains("request_duration_sum{status=\"200\",vhost=\"example.org\"} 0.75\n"));
+
+        // Not recorded by default
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let data = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).build_data();
+        assert!(data.request_duration_count_counter.is_none());
+        assert!(data.request_duration_sum_counter.is_none());
+    }
+
+    #[test]
+    fn test_byte_counters() {
+        let log_parser = LogParser::from_format("$host $status $body_bytes_sent $request_length").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            byte_counters: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 263 410");
+        processor.handle_line(&data, "example.org 200 14 1200");
+        processor.handle_line(&data, "example.org 404 150 380");
+        let sent = data.bytes_sent.as_ref().unwrap();
+        let received = data.bytes_received.as_ref().unwrap();
+        assert_eq!(sent.with_label_values(&["example.org", "200"]).get(), 277);
+        assert_eq!(received.with_label_values(&["example.org", "200"]).get(), 1610);
+        assert_eq!(sent.with_label_values(&["example.org", "404"]).get(), 150);
+        assert_eq!(received.with_label_values(&["example.org", "404"]).get(), 380);
+
+        // Not recorded if the format doesn't have the size
+        let log_parser = LogParser::from_format("$host $status $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            byte_counters: true,
+            ..Default::default()
+        });
+        let data = collector_builder.build_data();
+        assert!(data.bytes_sent.is_some());
+        assert!(data.bytes_received.is_none());
+    }
+
+    #[test]
+    fn test_last_values() {
+        let log_parser = LogParser::from_format("$host $status $request_time $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            last_values: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 0.25 263");
+        processor.handle_line(&data, "example.org 200 0.5 14");
+        processor.handle_line(&data, "example.org 404 0.125 80");
+        let duration = data.last_request_duration.as_ref().unwrap();
+        let body_size = data.last_response_body_size.as_ref().unwrap();
+        assert_eq!(duration.with_label_values(&["example.org", "200"]).get(), 0.5);
+        assert_eq!(body_size.with_label_values(&["example.org", "200"]).get(), 14.0);
+        assert_eq!(duration.with_label_values(&["example.org", "404"]).get(), 0.125);
+        assert_eq!(body_size.with_label_values(&["example.org", "404"]).get(), 80.0);
+
+        // Not recorded by default
+        let log_parser = LogParser::from_format("$host $status $request_time $body_bytes_sent").unwrap();
+        let data = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).build_data();
+        assert!(data.last_request_duration.is_none());
+        assert!(data.last_response_body_size.is_none());
+    }
+
+    #[test]
+    fn test_error_ratio() {
+        use prometheus::core::Collector;
+
+        use crate::collector::LogCollector;
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            error_ratio: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        data.lock().unwrap().active = true;
+        let collector = LogCollector { data: data.clone(), desc: Vec::new(), exemplars: None };
+        let ratio = || {
+            let families = collector.collect();
+            let family = families.iter().find(|f| f.get_name() == "error_ratio").unwrap();
+            family.get_metric()[0].get_gauge().get_value()
+        };
+
+        // No lines yet
+        assert_eq!(ratio(), 0.0);
+
+        {
+            let data = data.lock().unwrap();
+            processor.handle_line(&data, "example.org 200");
+            processor.handle_line(&data, "example.org 404");
+            processor.handle_line(&data, "remram.fr 200");
+            processor.handle_line(&data, "invalid");
+        }
+        assert_eq!(ratio(), 0.25);
+
+        // Not reported by default
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).build_data().error_ratio.is_none());
+    }
+
+    #[test]
+    fn test_const_labels() {
+        use prometheus::core::Collector;
+
+        use crate::collector::LogCollector;
+
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/var/log/nginx/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            const_labels: vec![("logfile".to_owned(), "access.log".to_owned())].into_iter().collect(),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        processor.handle_line(&data.lock().unwrap(), "example.org 200 0.5");
+        data.lock().unwrap().active = true;
+        let collector = LogCollector { data, desc: Vec::new(), exemplars: None };
+
+        let families = collector.collect();
+        for name in &["requests", "request_duration", "errors", "log_file_present"] {
+            let family = families.iter().find(|f| f.get_name() == *name).unwrap();
+            for metric in family.get_metric() {
+                let label = metric.get_label().iter().find(|p| p.get_name() == "logfile").unwrap();
+                assert_eq!(label.get_value(), "access.log");
+            }
+        }
+    }
+
+    #[test]
+    fn test_help() {
+        use prometheus::core::Collector;
+
+        let log_parser = LogParser::from_format("$host $remote_user $status $request_time").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = collector_builder.build_data();
+        assert_eq!(data.request_count.desc()[0].help, "The total number of requests per vhost, user and status");
+        assert_eq!(data.request_duration.desc()[0].help, "Duration of HTTP requests in seconds per vhost, user and status");
+
+        let log_parser = LogParser::from_format("$status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = collector_builder.build_data();
+        assert_eq!(data.request_count.desc()[0].help, "The total number of requests per status");
+
+        let log_parser = LogParser::from_format("$request_time").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = collector_builder.build_data();
+        assert_eq!(data.request_count.desc()[0].help, "The total number of requests");
+    }
+
+    #[test]
+    fn test_buckets() {
+        use prometheus::core::Collector;
+
+        let log_parser = LogParser::from_format("$status $request_time $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let mut buckets = std::collections::HashMap::new();
+        buckets.insert("request_duration".to_owned(), vec![0.1, 1.0]);
+        collector_builder.set_data_options(LogDataOptions {
+            buckets,
+            ..Default::default()
+        });
+        let data = collector_builder.build_data();
+        data.request_duration.with_label_values(&["200"]).observe(0.5);
+        data.response_body_size.with_label_values(&["200"]).observe(500.0);
+
+        let families = data.request_duration.collect();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        let bounds: Vec<f64> = histogram.get_bucket().iter().map(|b| b.get_upper_bound()).collect();
+        assert_eq!(bounds, vec![0.1, 1.0]);
+
+        // Unlisted histograms keep the default buckets
+        let families = data.response_body_size.collect();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_bucket().len(), 10);
+    }
+
+    #[test]
+    fn test_vhost_buckets() {
+        use prometheus::core::Collector;
+
+        let log_parser = LogParser::from_format("$host $status $request_time $request_id").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let mut vhosts = std::collections::HashMap::new();
+        vhosts.insert("api.example.org".to_owned(), vec![0.01, 0.05]);
+        vhosts.insert("files.example.org".to_owned(), vec![1.0, 10.0, 60.0]);
+        let mut vhost_buckets = std::collections::HashMap::new();
+        vhost_buckets.insert("request_duration".to_owned(), vhosts);
+        collector_builder.set_data_options(LogDataOptions {
+            vhost_buckets,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "api.example.org 200 0.03125 4a0f1d3e");
+        processor.handle_line(&data, "files.example.org 200 12.5 9be8c227");
+        processor.handle_line(&data, "example.org 200 0.250 -");
+
+        // A single metric, with different buckets for each vhost
+        let families = data.request_duration.collect();
+        assert_eq!(families.len(), 1);
+        let bounds = |vhost: &str| -> Vec<f64> {
+            let metric = families[0].get_metric().iter()
+                .find(|m| m.get_label().iter().any(|l| l.get_name() == "vhost" && l.get_value() == vhost))
+                .unwrap();
+            assert_eq!(metric.get_histogram().get_sample_count(), 1);
+            metric.get_histogram().get_bucket().iter().map(|b| b.get_upper_bound()).collect()
+        };
+        assert_eq!(bounds("api.example.org"), vec![0.01, 0.05]);
+        assert_eq!(bounds("files.example.org"), vec![1.0, 10.0, 60.0]);
+        assert_eq!(bounds("example.org"), prometheus::DEFAULT_BUCKETS.to_vec());
+        assert_eq!(data.request_duration.with_label_values(&["files.example.org", "200"]).get_sample_sum(), 12.5);
+
+        // Exemplars use the buckets of their vhost
+        let exemplars = data.request_duration_exemplars.as_ref().unwrap().lock().unwrap();
+        let text = crate::openmetrics::encode(&families, &[&exemplars]);
+        assert!(text.contains("request_duration_bucket{status=\"200\",vhost=\"api.example.org\",le=\"0.05\"} 1 # {request_id=\"4a0f1d3e\"} 0.03125 "));
+        assert!(text.contains("request_duration_bucket{status=\"200\",vhost=\"files.example.org\",le=\"60\"} 1 # {request_id=\"9be8c227\"} 12.5 "));
+    }
+
+    #[test]
+    fn test_processing_lag() {
+        let log_parser = LogParser::from_format("$host [$time_local] $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let mut processor = collector_builder.build_processor(data.clone());
+        // 2021-11-11T02:34:49+00:00
+        processor.clock = || 1636598089.0;
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org [11/Nov/2021:02:34:39 +0000] 200");
+        processor.handle_line(&data, "example.org [10/Nov/2021:21:34:47 -0500] 200");
+        // Timestamps in the future count as no lag
+        processor.handle_line(&data, "example.org [11/Nov/2021:02:35:39 +0000] 200");
+
+        let processing_lag = data.processing_lag.as_ref().unwrap();
+        assert_eq!(processing_lag.get_sample_count(), 3);
+        assert_eq!(processing_lag.get_sample_sum(), 12.0);
+
+        // No histogram without a time field
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.build_data().processing_lag.is_none());
+    }
+
+    #[test]
+    fn test_last_timestamp() {
+        // $time_iso8601 is preferred over $time_local
+        let log_parser = LogParser::from_format("$host [$time_local] $time_iso8601 $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        let last_timestamp = data.last_timestamp.as_ref().unwrap();
+        processor.handle_line(&data, "example.org [01/Jan/2000:00:00:00 +0000] 2021-11-11T04:34:39+02:00 200");
+        assert_eq!(last_timestamp.get(), 1636598079.0);
+        processor.handle_line(&data, "example.org [01/Jan/2000:00:00:00 +0000] 2021-11-10T21:34:49-05:00 200");
+        assert_eq!(last_timestamp.get(), 1636598089.0);
+        // Lines out of order don't make it go back
+        processor.handle_line(&data, "example.org [01/Jan/2000:00:00:00 +0000] 2021-11-11T02:34:40Z 200");
+        assert_eq!(last_timestamp.get(), 1636598089.0);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).build_data().last_timestamp.is_none());
+    }
+
+    #[test]
+    fn test_size_class() {
+        let log_parser = LogParser::from_format("$host $status $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_size_class([100, 1000, 10000, 100000]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org 200 0", Some((&["example.org", "200", "tiny"], None, Some(0))));
+        test_parse(&processor, "example.org 200 100", Some((&["example.org", "200", "tiny"], None, Some(100))));
+        test_parse(&processor, "example.org 200 101", Some((&["example.org", "200", "small"], None, Some(101))));
+        test_parse(&processor, "example.org 200 1000", Some((&["example.org", "200", "small"], None, Some(1000))));
+        test_parse(&processor, "example.org 200 1001", Some((&["example.org", "200", "medium"], None, Some(1001))));
+        test_parse(&processor, "example.org 200 10000", Some((&["example.org", "200", "medium"], None, Some(10000))));
+        test_parse(&processor, "example.org 200 10001", Some((&["example.org", "200", "large"], None, Some(10001))));
+        test_parse(&processor, "example.org 200 100000", Some((&["example.org", "200", "large"], None, Some(100000))));
+        test_parse(&processor, "example.org 200 100001", Some((&["example.org", "200", "huge"], None, Some(100001))));
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_size_class(DEFAULT_SIZE_THRESHOLDS).is_err());
+    }
+
+    #[test]
+    fn test_keepalive_label() {
+        let log_parser = LogParser::from_format("$host $status $connection_requests").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_keepalive_label().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        test_parse(&processor, "example.org 200 1", Some((&["example.org", "200", "no"], None, None)));
+        test_parse(&processor, "example.org 200 5", Some((&["example.org", "200", "yes"], None, None)));
+
+        // requests_per_connection is still recorded
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 5");
+        assert_eq!(data.requests_per_connection.as_ref().unwrap().with_label_values(&["example.org", "200", "yes"]).get_sample_sum(), 5.0);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_keepalive_label().is_err());
+    }
+
+    #[test]
+    fn test_debug_lines() {
+        use crate::processor::DebugLine;
+
+        let log_parser = LogParser::from_format("$host $status $request_method").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            debug_lines: Some(3),
+            ..Default::default()
+        });
+        collector_builder.add_method_allow(&["GET"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 GET");
+        processor.handle_line(&data, "example.org 200 GET");
+        processor.handle_line(&data, "example.org 200 POST");
+        processor.handle_line(&data, "garbage");
+        let labels = |host: &str, status: &str| vec![("vhost".to_owned(), host.to_owned()), ("status".to_owned(), status.to_owned())];
+        {
+            let debug_lines = data.debug_lines.as_ref().unwrap().lock().unwrap();
+            // Only the last 3 are kept
+            assert_eq!(
+                debug_lines.lines.iter().cloned().collect::<Vec<_>>(),
+                vec![
+                    DebugLine { line: "example.org 200 GET".to_owned(), status: "accepted", labels: labels("example.org", "200"), error: None },
+                    DebugLine { line: "example.org 200 POST".to_owned(), status: "filtered", labels: vec![], error: None },
+                    DebugLine { line: "garbage".to_owned(), status: "error", labels: vec![], error: Some("Parse error: Missing separator Char(' ')".to_owned()) },
+                ],
+            );
+            assert_eq!(
+                debug_lines.to_json(),
+                concat!(
+                    r#"[{"line":"example.org 200 GET","status":"accepted","labels":{"vhost":"example.org","status":"200"},"error":null},"#,
+                    r#"{"line":"example.org 200 POST","status":"filtered","labels":{},"error":null},"#,
+                    r#"{"line":"garbage","status":"error","labels":{},"error":"Parse error: Missing separator Char(' ')"}]"#,
+                ),
+            );
+        }
+
+        // Not kept by default
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).build_data().debug_lines.is_none());
+    }
+
+    #[test]
+    fn test_cache_hit_ratio() {
+        use prometheus::core::Collector;
+
+        use crate::collector::LogCollector;
+
+        let log_parser = LogParser::from_format("$host $status $upstream_cache_status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_cache_hit_ratio("$upstream_cache_status").unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        data.lock().unwrap().active = true;
+        let collector = LogCollector { data: data.clone(), desc: Vec::new(), exemplars: None };
+        let ratio = || {
+            let families = collector.collect();
+            let family = families.iter().find(|f| f.get_name() == "cache_hit_ratio").unwrap();
+            family.get_metric()[0].get_gauge().get_value()
+        };
+
+        // No cacheable requests yet
+        assert_eq!(ratio(), 0.0);
+        {
+            let data = data.lock().unwrap();
+            processor.handle_line(&data, "example.org 200 BYPASS");
+            processor.handle_line(&data, "example.org 200 -");
+        }
+        assert_eq!(ratio(), 0.0);
+
+        {
+            let data = data.lock().unwrap();
+            processor.handle_line(&data, "example.org 200 HIT");
+            processor.handle_line(&data, "example.org 200 HIT");
+            processor.handle_line(&data, "example.org 200 STALE");
+            processor.handle_line(&data, "example.org 200 MISS");
+            processor.handle_line(&data, "example.org 404 EXPIRED");
+        }
+        assert_eq!(ratio(), 0.6);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_cache_hit_ratio("upstream_cache_status").is_err());
+    }
+
+    #[test]
+    fn test_response_header_count() {
+        let log_parser = LogParser::from_format("$host $status $sent_http_x_cache $sent_http_x_variant").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_response_header_count("X-Cache").unwrap();
+        collector_builder.add_response_header_count("$sent_http_x_variant").unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 HIT a");
+        processor.handle_line(&data, "example.org 200 HIT b");
+        processor.handle_line(&data, "example.org 404 MISS -");
+        for i in 0..30 {
+            processor.handle_line(&data, &format!("example.org 200 - v{}", i));
+        }
+        let counter = data.response_header_count.as_ref().unwrap();
+        assert_eq!(counter.with_label_values(&["x_cache", "HIT"]).get(), 2);
+        assert_eq!(counter.with_label_values(&["x_cache", "MISS"]).get(), 1);
+        assert_eq!(counter.with_label_values(&["x_variant", "a"]).get(), 1);
+        assert_eq!(counter.with_label_values(&["x_variant", "v17"]).get(), 1);
+        // Only the first values of a header get their own series
+        assert_eq!(counter.with_label_values(&["x_variant", "v18"]).get(), 0);
+        assert_eq!(counter.with_label_values(&["x_variant", "other"]).get(), 12);
+        // The header is not a label on the other metrics
+        assert_eq!(data.labels, &["vhost", "status"]);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_response_header_count("x_cache").is_err());
+    }
+
+    #[test]
+    fn test_http_version_metric() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_http_version_metric().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org \"GET / HTTP/1.1\" 200");
+        processor.handle_line(&data, "example.org \"GET /a HTTP/1.1\" 404");
+        processor.handle_line(&data, "example.org \"GET / HTTP/2.0\" 200");
+        processor.handle_line(&data, "example.org \"GET / HTTP/1.0\" 200");
+        processor.handle_line(&data, "example.org \"GET / HTTP/9.9\" 200");
+        processor.handle_line(&data, "example.org \"\\x16\\x03\\x01\" 400");
+        let counter = data.http_version_requests.as_ref().unwrap();
+        assert_eq!(counter.with_label_values(&["HTTP/1.1"]).get(), 2);
+        assert_eq!(counter.with_label_values(&["HTTP/2.0"]).get(), 1);
+        assert_eq!(counter.with_label_values(&["HTTP/1.0"]).get(), 1);
+        assert_eq!(counter.with_label_values(&["other"]).get(), 1);
+        // Requests without a protocol are still counted in requests
+        assert_eq!(data.request_count.with_label_values(&["example.org", "400"]).get(), 1);
+        // The version is not a label on the other metrics
+        assert_eq!(data.labels, &["vhost", "status"]);
+
+        // Uses $server_protocol if available, e.g. "HTTP/3" from nginx
+        let log_parser = LogParser::from_format("$host $server_protocol $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_http_version_metric().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org HTTP/3 200");
+        processor.handle_line(&data, "example.org HTTP/2.0 200");
+        let counter = data.http_version_requests.as_ref().unwrap();
+        assert_eq!(counter.with_label_values(&["HTTP/3.0"]).get(), 1);
+        assert_eq!(counter.with_label_values(&["HTTP/2.0"]).get(), 1);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_http_version_metric().is_err());
+    }
+
+    #[test]
+    fn test_label_order() {
+        let log_parser = LogParser::from_format("$host $ssl_server_name $status $remote_user").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            debug_lines: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(collector_builder.labels, &["vhost", "status", "user", "sni_mismatch"]);
+        assert_eq!(collector_builder.set_label_order(&["path"]), Err("path"));
+        collector_builder.set_label_order(&["status", "sni_mismatch"]).unwrap();
+        assert_eq!(collector_builder.labels, &["status", "sni_mismatch", "vhost", "user"]);
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        test_parse(&processor, "example.org other.org 404 -", Some((&["404", "yes", "example.org", "no"], None, None)));
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org other.org 404 -");
+        assert_eq!(processor.labels, &["status", "sni_mismatch", "vhost", "user"]);
+        let debug_lines = data.debug_lines.as_ref().unwrap().lock().unwrap();
+        let labels: Vec<&str> = debug_lines.lines[0].labels.iter().map(|(l, _)| l.as_str()).collect();
+        assert_eq!(labels, &["status", "sni_mismatch", "vhost", "user"]);
+        assert_eq!(data.request_count.with_label_values(&["404", "yes", "example.org", "no"]).get(), 1);
+    }
+
+    #[test]
+    fn test_composite_label() {
+        let log_parser = LogParser::from_format("$host $request_method $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_extractor(Some("method".to_owned()), "request_method".to_owned(), ExtractorFunc::Pipeline { steps: Vec::new() }).unwrap();
+        assert_eq!(collector_builder.add_composite_label("status_path", ("status", "path"), "_"), Err("path"));
+        collector_builder.add_composite_label("status_method", ("status", "method"), "_").unwrap();
+        // Composites can be used in other composites
+        collector_builder.add_composite_label("full", ("vhost", "status_method"), "/").unwrap();
+        collector_builder.set_label_order(&["full", "method"]).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        assert_eq!(processor.labels, &["full", "method", "vhost", "status", "status_method"]);
+        test_parse(&processor, "example.org GET 200", Some((&["example.org/200_GET", "GET", "example.org", "200", "200_GET"], None, None)));
+        test_parse(&processor, "example.org POST 404", Some((&["example.org/404_POST", "POST", "example.org", "404", "404_POST"], None, None)));
+    }
+
+    #[test]
+    fn test_transform() {
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.add_transform("path", Some("request"), TransformStep::Uri).unwrap();
+        collector_builder.add_transform("path", None, TransformStep::StripQuery).unwrap();
+        collector_builder.add_transform("normalized", Some("request"), TransformStep::Lowercase).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, "example.org \"GET /Pets/12?page=2 HTTP/1.1\" 200", Some((&["example.org", "200", "/Pets/12", "get /pets/12?page=2 http/1.1"], None, None)));
+        test_parse(&processor, "example.org \"GET /pets HTTP/1.1\" 200", Some((&["example.org", "200", "/pets", "get /pets http/1.1"], None, None)));
+
+        // The steps are applied in order
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        for &step in &[TransformStep::Uri, TransformStep::UrlDecode, TransformStep::Template, TransformStep::Lowercase] {
+            collector_builder.add_transform("path", Some("request"), step).unwrap();
+        }
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+        test_parse(&processor, "example.org \"GET /API/%70ets/12?q HTTP/1.1\" 200", Some((&["example.org", "200", "/api/pets/:id"], None, None)));
+
+        // Needs a field, and the same one for all the steps
+        let log_parser = LogParser::from_format("$host \"$request\" $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.add_transform("path", None, TransformStep::Uri).is_err());
+        assert!(collector_builder.add_transform("path", Some("uri"), TransformStep::Uri).is_err());
+        collector_builder.add_transform("path", Some("request"), TransformStep::Uri).unwrap();
+        assert!(collector_builder.add_transform("path", Some("host"), TransformStep::Template).is_err());
+    }
+
+    #[test]
+    fn test_new_bare() {
+        let format = r#"$host $server_name $ssl_server_name $remote_user "$request" $status $request_time $body_bytes_sent $upstream_cache_status $msec"#;
+        let line = r#"example.org example.org example.org remi "GET / HTTP/1.1" 200 0.25 263 HIT 1636590000.000"#;
+        let collector_builder = LogCollectorBuilder::new_bare(LogParser::from_format(format).unwrap(), "/tmp/access.log".into());
+        assert!(collector_builder.labels.is_empty());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        test_parse(&processor, line, Some((&[], Some(0.25), Some(263))));
+        assert!(data.lock().unwrap().last_timestamp.is_some());
+
+        // Explicit labels still work
+        let mut collector_builder = LogCollectorBuilder::new_bare(LogParser::from_format(format).unwrap(), "/tmp/access.log".into());
+        collector_builder.add_path_templating().unwrap();
+        assert_eq!(collector_builder.labels, &["path"]);
+
+        // Compare with the default
+        let collector_builder = LogCollectorBuilder::new(LogParser::from_format(format).unwrap(), "/tmp/access.log".into());
+        assert_eq!(collector_builder.labels, &["vhost", "server_name", "user", "status", "sni_mismatch"]);
+    }
+
+    #[test]
+    fn test_unique_clients() {
+        let log_parser = LogParser::from_format("$host $remote_addr $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            unique_clients_window: Some(60.0),
+            ..Default::default()
+        });
+        collector_builder.add_unique_clients().unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        for i in 0..500 {
+            processor.handle_line(&data, &format!("example.org 10.0.{}.{} 200", i / 100, i % 100));
+            processor.handle_line(&data, "example.org 10.0.0.0 200");
+        }
+        // The gauge is computed when collecting
+        data.update_unique_clients();
+        let estimate = data.unique_clients.as_ref().unwrap().get();
+        assert!((estimate - 500.0).abs() < 15.0, "estimate is {}", estimate);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        assert!(LogCollectorBuilder::new(log_parser, "/tmp/access.log".into()).add_unique_clients().is_err());
+    }
+
+    #[test]
+    fn test_max_series() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            max_series: Some(2),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200");
+        processor.handle_line(&data, "example.org 404");
+        assert_eq!(data.cardinality_limit_reached.get(), 0);
+        processor.handle_line(&data, "example.org 500");
+        processor.handle_line(&data, "remram.fr 200");
+        assert_eq!(data.cardinality_limit_reached.get(), 1);
+        // Series seen before the limit was reached keep being counted
+        processor.handle_line(&data, "example.org 200");
+
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 2);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        assert_eq!(data.request_count.with_label_values(&["overflow", "overflow"]).get(), 2);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "500"]).get(), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let log_parser = LogParser::from_format("$host [$time_iso8601] $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            max_series: Some(1),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let mut data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org [2021-11-11T02:34:39Z] 200 0.092");
+        processor.handle_line(&data, "remram.fr [2021-11-11T02:34:40Z] 404 0.132");
+        processor.handle_line(&data, "garbage");
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 1);
+        assert_eq!(data.cardinality_limit_reached.get(), 1);
+
+        data.reset();
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 0);
+        assert_eq!(data.request_count.with_label_values(&["overflow", "overflow"]).get(), 0);
+        assert_eq!(data.request_duration.with_label_values(&["example.org", "200"]).get_sample_count(), 0);
+        assert_eq!(data.processing_lag.as_ref().unwrap().get_sample_count(), 0);
+        assert_eq!(data.last_timestamp.as_ref().unwrap().get(), 0.0);
+        assert_eq!(data.error_count.get(), 0);
+        assert_eq!(data.cardinality_limit_reached.get(), 0);
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 0);
+
+        // The processor forgets the series it saw before
+        processor.handle_line(&data, "remram.fr [2021-11-11T02:34:41Z] 404 0.132");
+        assert_eq!(data.request_count.with_label_values(&["remram.fr", "404"]).get(), 1);
+        assert_eq!(data.cardinality_limit_reached.get(), 0);
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 1);
+    }
+
+    #[test]
+    fn test_label_cardinality() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 0);
+        processor.handle_line(&data, "example.org 200");
+        processor.handle_line(&data, "example.org 404");
+        processor.handle_line(&data, "remram.fr 200");
+        processor.handle_line(&data, "app.example.org 200");
+        processor.handle_line(&data, "example.org 500");
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 3);
+        assert_eq!(data.label_cardinality.with_label_values(&["status"]).get(), 3);
+        // Lines that are dropped don't count
+        processor.handle_line(&data, "invalid");
+        assert_eq!(data.label_cardinality.with_label_values(&["vhost"]).get(), 3);
+    }
+
+    #[test]
+    fn test_max_buffer() {
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-max-buffer.log");
+        std::fs::write(&path, "example.org 200\nexample.org 404\nexample.org 500\nexample.org 301\nexample.org 302\nexample").unwrap();
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        collector_builder.set_processor_options(ProcessorOptions {
+            max_buffer: Some(40),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        // Catching up reads 40 bytes at a time, nothing is dropped since we
+        // are not falling behind
+        let (tx, rx) = std::sync::mpsc::channel();
+        drop(tx);
+        let mut file = std::fs::File::open(&path).unwrap();
+        processor.follow_log(&mut file, 0, &rx).unwrap_err();
+        let data = data.lock().unwrap();
+        assert_eq!(data.dropped_lines_count.get(), 0);
+        for status in &["200", "404", "500", "301", "302"] {
+            assert_eq!(data.request_count.with_label_values(&["example.org", status]).get(), 1);
+        }
+        // The incomplete line is still waiting
+        assert_eq!(data.buffer_size.get(), 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let log_parser = LogParser::from_format("$host $status $request_time").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            dedup_window: Some(2),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 0.1");
+        processor.handle_line(&data, "example.org 200 0.1");
+        processor.handle_line(&data, "example.org 200 0.2");
+        // Still in the window
+        processor.handle_line(&data, "example.org 200 0.1");
+        processor.handle_line(&data, "example.org 404 0.1");
+        // Not anymore, the window holds the last 2 distinct lines
+        processor.handle_line(&data, "example.org 200 0.1");
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        assert_eq!(data.duplicate_lines_count.get(), 2);
+    }
+
+    #[test]
+    fn test_batch() {
+        let log_parser = LogParser::from_format("$host $status $request_time $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            last_values: true,
+            ..Default::default()
+        });
+        collector_builder.set_processor_options(ProcessorOptions {
+            batch_lines: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let mut buffer = b"example.org 200 0.25 263\nexample.org 404 0.5 14\nexample.org 200 0.5 80\ninvalid\nexample.org 200 0.125 12\nexam".to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        assert_eq!(buffer, b"exam");
+        let data = data.lock().unwrap();
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        assert_eq!(data.error_count.get(), 1);
+        let duration = data.request_duration.with_label_values(&["example.org", "200"]);
+        assert_eq!(duration.get_sample_count(), 3);
+        assert_eq!(duration.get_sample_sum(), 0.875);
+        assert_eq!(data.response_body_size.with_label_values(&["example.org", "200"]).get_sample_sum(), 355.0);
+        // The gauges get the last value of the batch
+        assert_eq!(data.last_request_duration.as_ref().unwrap().with_label_values(&["example.org", "200"]).get(), 0.125);
+    }
+
+    #[test]
+    fn test_batch_without_measurements() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            batch_lines: true,
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let mut buffer = b"example.org 200\nexample.org 404\n".to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 1);
+        // No empty histograms for a format without these variables
+        assert!(data.request_duration.collect()[0].get_metric().is_empty());
+        assert!(data.response_body_size.collect()[0].get_metric().is_empty());
     }
 
     #[test]
-    fn test_process() {
-        let log_parser = LogParser::from_format(
-            r#"$host $remote_addr - $remote_user [$time_local] "$request" $status $request_time $body_bytes_sent "$http_referer" "$http_user_agent""#,
-        ).unwrap();
+    fn test_workers() {
+        let log_parser = LogParser::from_format("$host $status $request_time $body_bytes_sent").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_data_options(LogDataOptions {
+            last_values: true,
+            ..Default::default()
+        });
+        collector_builder.set_processor_options(ProcessorOptions {
+            workers: Some(3),
+            dedup_window: Some(10),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = Arc::new(collector_builder.build_processor(data.clone()));
+        LogProcessor::start_workers(&processor);
+
+        let mut buffer = b"example.org 200 0.25 263\nexample.org 404 0.5 14\nexample.org 200 0.5 80\ninvalid\nexample.org 200 0.5 80\nexample.org 200 0.125 12\nexam".to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        assert_eq!(buffer, b"exam");
+        let data = data.lock().unwrap();
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        assert_eq!(data.error_count.get(), 1);
+        assert_eq!(data.duplicate_lines_count.get(), 1);
+        assert_eq!(data.request_duration.with_label_values(&["example.org", "200"]).get_sample_sum(), 0.875);
+        // The lines are still recorded in order
+        assert_eq!(data.last_request_duration.as_ref().unwrap().with_label_values(&["example.org", "200"]).get(), 0.125);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            idle_timeout: Some(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let idle = || data.lock().unwrap().idle.as_ref().unwrap().get();
+
+        processor.check_idle();
+        assert_eq!(idle(), 0);
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        processor.check_idle();
+        assert_eq!(idle(), 1);
+
+        // Incomplete lines are not activity
+        let mut buffer = b"example.org".to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        assert_eq!(idle(), 1);
+
+        // Even an invalid line is
+        buffer.extend_from_slice(b"\n");
+        processor.process_lines(&mut buffer).unwrap();
+        assert_eq!(idle(), 0);
+        processor.check_idle();
+        assert_eq!(idle(), 0);
+
+        // Not reported without the option
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.build_data().idle.is_none());
+    }
+
+    #[test]
+    fn test_skip_blank_and_comment_lines() {
+        let lines = b"example.org 200\n\n\r\n#rotated\nexample.org 404\n#\n\t\n";
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            skip_blank_lines: true,
+            comment_prefix: Some("#".to_owned()),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let mut buffer = lines.to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        {
+            let data = data.lock().unwrap();
+            assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 1);
+            assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+            assert_eq!(data.error_count.get(), 0);
+        }
+
+        // They are errors by default
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let mut buffer = lines.to_vec();
+        processor.process_lines(&mut buffer).unwrap();
+        assert_eq!(data.lock().unwrap().error_count.get(), 5);
+    }
+
+    /// Compare the throughput with and without batching, run with:
+    /// `cargo test --release -- --ignored bench_batch --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_batch() {
+        let mut log = Vec::new();
+        for i in 0..200_000 {
+            let status = if i % 10 == 0 { 404 } else { 200 };
+            log.extend_from_slice(format!("example.org 1.2.3.4 [11/Nov/2021:02:34:39 +0000] \"GET /api HTTP/1.1\" {} 0.012 263\n", status).as_bytes());
+        }
+
+        for &batch_lines in &[false, true] {
+            let log_parser = LogParser::from_format(r#"$host $remote_addr [$time_local] "$request" $status $request_time $body_bytes_sent"#).unwrap();
+            let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+            collector_builder.set_processor_options(ProcessorOptions {
+                batch_lines,
+                ..Default::default()
+            });
+            let data = Arc::new(Mutex::new(collector_builder.build_data()));
+            let processor = collector_builder.build_processor(data.clone());
+
+            let mut buffer = log.clone();
+            let start = std::time::Instant::now();
+            processor.process_lines(&mut buffer).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(data.lock().unwrap().request_count.with_label_values(&["example.org", "200"]).get(), 180_000);
+            println!("batch_lines={}: {:.0} lines/s", batch_lines, 200_000.0 / elapsed.as_secs_f64());
+        }
+    }
+
+    /// Compare the throughput with different numbers of worker threads, run
+    /// with: `cargo test --release -- --ignored bench_workers --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_workers() {
+        let mut log = Vec::new();
+        for i in 0..200_000 {
+            let status = if i % 10 == 0 { 404 } else { 200 };
+            log.extend_from_slice(format!("example.org 1.2.3.4 [11/Nov/2021:02:34:39 +0000] \"GET /api/{} HTTP/1.1\" {} 0.012 263 \"Mozilla/5.0 (X11; Linux x86_64)\"\n", i % 100, status).as_bytes());
+        }
+
+        for &workers in &[None, Some(1), Some(2), Some(4), Some(8)] {
+            let log_parser = LogParser::from_format(r#"$host $remote_addr [$time_local] "$request" $status $request_time $body_bytes_sent "$http_user_agent""#).unwrap();
+            let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+            collector_builder.add_path_templating().unwrap();
+            collector_builder.add_bot_detect(&[], false).unwrap();
+            collector_builder.set_processor_options(ProcessorOptions {
+                batch_lines: true,
+                workers,
+                ..Default::default()
+            });
+            let data = Arc::new(Mutex::new(collector_builder.build_data()));
+            let processor = Arc::new(collector_builder.build_processor(data.clone()));
+            LogProcessor::start_workers(&processor);
+
+            let mut buffer = log.clone();
+            let start = std::time::Instant::now();
+            processor.process_lines(&mut buffer).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(data.lock().unwrap().error_count.get(), 0);
+            println!("workers={:?}: {:.0} lines/s", workers, 200_000.0 / elapsed.as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            encoding: encoding_rs::Encoding::for_label(b"latin1"),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let line = processor.options.decode_line(b"caf\xe9.example.org 200").unwrap();
+        assert_eq!(line, "caf\u{e9}.example.org 200");
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, &line);
+        assert_eq!(data.request_count.with_label_values(&["caf\u{e9}.example.org", "200"]).get(), 1);
+
+        // UTF-8 is the default, and invalid sequences are errors
+        let log_parser = LogParser::from_format("$host $status").unwrap();
         let collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
         let data = Arc::new(Mutex::new(collector_builder.build_data()));
         let processor = collector_builder.build_processor(data);
+        assert_eq!(processor.options.decode_line("caf\u{e9} 200".as_bytes()).unwrap(), "caf\u{e9} 200");
+        assert!(processor.options.decode_line(b"caf\xe9 200").is_err());
+    }
 
-        test_parse(
-            &processor,
-            r#"example.org 1.2.3.4 - - [11/Nov/2021:02:34:39 +0000] "GET /api/v4/pets/1 HTTP/1.1" 200 0.092 263 "-" "Mozilla/5.0 (Linux)""#,
-            Some((
-                &["example.org", "no", "200"],
-                Some(0.092),
-                Some(263),
-            )),
-        );
-        test_parse(
-            &processor,
-            r#"remram.fr 8.8.8.8 - person [11/Nov/2021:02:34:41 +0000] "POST /api/v4/pets HTTP/1.1" 201 0.132 14 "-" "Mozilla/5.0 (Linux)""#,
-            Some((
-                &["remram.fr", "yes", "201"],
-                Some(0.132),
-                Some(14),
-            )),
+    #[test]
+    fn test_poll_interval() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-poll.log");
+        std::fs::write(&path, "").unwrap();
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        collector_builder.set_processor_options(ProcessorOptions {
+            poll_interval: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        // No events will ever be sent on this channel
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut file = std::fs::File::open(&path).unwrap();
+        std::thread::spawn(move || {
+            processor.follow_log(&mut file, 0, &rx).ok();
+        });
+
+        let mut writer = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(writer, "example.org 200").unwrap();
+        let mut count = 0;
+        for _ in 0..100 {
+            count = data.lock().unwrap().request_count.with_label_values(&["example.org", "200"]).get();
+            if count > 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(count, 1);
+        drop(tx);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_offset_file() {
+        use std::io::Write;
+        use crate::offset::{FileIdentity, SavedOffset, read_offset, write_offset};
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-resume.log");
+        let offset_path = std::env::temp_dir().join("access-log-to-prometheus-metrics-resume.offset");
+        std::fs::write(&path, "example.org 200\nexample.org 404\n").unwrap();
+        let _ = std::fs::remove_file(&offset_path);
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        collector_builder.set_processor_options(ProcessorOptions {
+            poll_interval: Some(std::time::Duration::from_millis(50)),
+            offset_file: Some(offset_path.clone()),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let mut file = std::fs::File::open(&path).unwrap();
+        let identity = FileIdentity::of(&file).unwrap().unwrap();
+
+        // No offset file, start at the end
+        assert_eq!(processor.start_offset(&mut file).unwrap(), 32);
+
+        // Resume from the saved offset
+        write_offset(&offset_path, &SavedOffset { identity, offset: 16 }).unwrap();
+        assert_eq!(processor.start_offset(&mut file).unwrap(), 16);
+
+        // Different file (e.g. rotated), start at the end
+        let other = std::fs::File::open(&offset_path).unwrap();
+        let other_identity = FileIdentity::of(&other).unwrap().unwrap();
+        write_offset(&offset_path, &SavedOffset { identity: other_identity, offset: 16 }).unwrap();
+        assert_eq!(processor.start_offset(&mut file).unwrap(), 32);
+
+        // The offset gets saved as lines are processed
+        write_offset(&offset_path, &SavedOffset { identity, offset: 16 }).unwrap();
+        let offset = processor.start_offset(&mut file).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            processor.follow_log(&mut file, offset, &rx).ok();
+        });
+        let mut writer = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(writer, "example.org 500").unwrap();
+        let mut saved = None;
+        for _ in 0..100 {
+            saved = read_offset(&offset_path).unwrap();
+            if saved.map(|s| s.offset) == Some(48) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(saved, Some(SavedOffset { identity, offset: 48 }));
+        {
+            let data = data.lock().unwrap();
+            assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 0);
+            assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+            assert_eq!(data.request_count.with_label_values(&["example.org", "500"]).get(), 1);
+        }
+        drop(tx);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&offset_path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_speed() {
+        let log_parser = LogParser::from_format("$msec $host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_replay_speed(100.0).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let mut processor = collector_builder.build_processor(data.clone());
+        processor.clock = || 1700000000.0;
+
+        // 20 seconds of log, and a line without a timestamp
+        let mut buffer = b"1636590000.000 example.org 200\n1636590010.000 example.org 200\n- example.org 200\n1636590020.000 example.org 404\n".to_vec();
+        let start = std::time::Instant::now();
+        processor.process_lines(&mut buffer).unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((0.2..1.0).contains(&elapsed), "took {}s", elapsed);
+
+        // The timestamps are moved to the time of the replay
+        let data = data.lock().unwrap();
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 3);
+        assert_eq!(data.last_timestamp.as_ref().unwrap().get(), 1700000000.2);
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.set_replay_speed(100.0).is_err());
+    }
+
+    #[test]
+    fn test_warmup() {
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-warmup.log");
+        let mut content = String::new();
+        // One line every 10 seconds, and one without a timestamp
+        for i in 0..20_000 {
+            content.push_str(&format!("{}.000 example.org 200\n", 1636590000 + i * 10));
+        }
+        content.push_str("- example.org 200\n");
+        content.push_str("1636790000.000 example.org 404\n");
+        std::fs::write(&path, &content).unwrap();
+
+        let log_parser = LogParser::from_format("$msec $host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        collector_builder.set_warmup(60.0).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let mut processor = collector_builder.build_processor(data.clone());
+        processor.clock = || 1636790015.0;
+
+        // The lines from 1636789960, past the first chunk
+        let mut file = std::fs::File::open(&path).unwrap();
+        let offset = processor.start_offset(&mut file).unwrap();
+        assert_eq!(&content[offset as usize..offset as usize + 14], "1636789960.000");
+        let mut rest = &content.as_bytes()[offset as usize..];
+        processor.follow_stream(&mut rest).unwrap();
+        {
+            let data = data.lock().unwrap();
+            assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 5);
+            assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        }
+
+        // All the lines are new enough, read the whole file
+        processor.clock = || 1636590000.0;
+        assert_eq!(processor.start_offset(&mut file).unwrap(), 0);
+
+        // All the lines are too old, start at the end
+        processor.clock = || 1656590000.0;
+        assert_eq!(processor.start_offset(&mut file).unwrap(), content.len() as u64);
+
+        std::fs::remove_file(&path).unwrap();
+
+        // Needs a time field
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        assert!(collector_builder.set_warmup(60.0).is_err());
+    }
+
+    #[test]
+    fn test_line_terminators() {
+        let run = |name: &str, content: &[u8], line_terminator: Option<u8>| {
+            let path = std::env::temp_dir().join(format!("access-log-to-prometheus-metrics-{}.log", name));
+            std::fs::write(&path, content).unwrap();
+            let log_parser = LogParser::from_format("$host $status").unwrap();
+            let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+            collector_builder.set_processor_options(ProcessorOptions {
+                poll_interval: Some(std::time::Duration::from_millis(10)),
+                line_terminator,
+                ..Default::default()
+            });
+            let data = Arc::new(Mutex::new(collector_builder.build_data()));
+            let processor = collector_builder.build_processor(data.clone());
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            let mut file = std::fs::File::open(&path).unwrap();
+            std::thread::spawn(move || {
+                processor.follow_log(&mut file, 0, &rx).ok();
+            });
+            let mut count = 0;
+            for _ in 0..100 {
+                count = data.lock().unwrap().request_count.with_label_values(&["example.org", "200"]).get();
+                if count >= 2 {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            drop(tx);
+            std::fs::remove_file(&path).unwrap();
+            let data = data.lock().unwrap();
+            (count, data.error_count.get())
+        };
+
+        // The last field doesn't get a stray "\r"
+        assert_eq!(run("crlf", b"example.org 200\r\nexample.org 200\r\n", None), (2, 0));
+        assert_eq!(run("nul", b"example.org 200\0example.org 200\0", Some(0)), (2, 0));
+    }
+
+    #[test]
+    fn test_watchdog() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-watchdog.log");
+        std::fs::write(&path, "").unwrap();
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        collector_builder.set_processor_options(ProcessorOptions {
+            poll_interval: Some(std::time::Duration::from_millis(20)),
+            watchdog: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        data.lock().unwrap().watch_active.set(1);
+        let processor = collector_builder.build_processor(data.clone());
+
+        // No events will ever be sent on this channel, as if the watch died
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let mut file = std::fs::File::open(&path).unwrap();
+        std::thread::spawn(move || {
+            processor.follow_log(&mut file, 0, &rx).ok();
+            done_tx.send(()).unwrap();
+        });
+
+        // New data keeps it going
+        let mut writer = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        for _ in 0..3 {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            writeln!(writer, "example.org 200").unwrap();
+        }
+        let count = || data.lock().unwrap().request_count.with_label_values(&["example.org", "200"]).get();
+        let wait_for_count = |n| {
+            for _ in 0..100 {
+                if count() == n {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("expected {} lines, got {}", n, count());
+        };
+        wait_for_count(3);
+
+        // Then it sets up the watch again, without losing the lines written
+        // right after, or reading the old ones again
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        writeln!(writer, "example.org 200").unwrap();
+        wait_for_count(4);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(count(), 4);
+        assert!(done_rx.try_recv().is_err());
+        {
+            let data = data.lock().unwrap();
+            assert_eq!(data.watch_active.get(), 1);
+            assert_eq!(data.rotation_count.get(), 0);
+        }
+
+        // The new watch sees the file being removed
+        drop(tx);
+        std::fs::remove_file(&path).unwrap();
+        done_rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(data.lock().unwrap().watch_active.get(), 0);
+    }
+
+    #[test]
+    fn test_rotation_count() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-rotation.log");
+        std::fs::write(&path, "example.org 200\n").unwrap();
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut file = std::fs::File::open(&path).unwrap();
+        let thread = std::thread::spawn(move || {
+            processor.follow_log(&mut file, 0, &rx).unwrap();
+        });
+        let event = |op| notify::RawEvent { path: Some(path.clone()), op: Ok(op), cookie: None };
+        let wait_for = |condition: &dyn Fn(&super::LogData) -> bool| {
+            for _ in 0..100 {
+                if condition(&data.lock().unwrap()) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("Timed out");
+        };
+        wait_for(&|d| d.request_count.with_label_values(&["example.org", "200"]).get() == 1);
+
+        // Truncated and written again
+        std::fs::File::create(&path).unwrap();
+        tx.send(event(notify::op::Op::WRITE)).unwrap();
+        wait_for(&|d| d.truncation_count.get() == 1);
+        writeln!(std::fs::OpenOptions::new().append(true).open(&path).unwrap(), "example.org 404").unwrap();
+        tx.send(event(notify::op::Op::WRITE)).unwrap();
+        wait_for(&|d| d.request_count.with_label_values(&["example.org", "404"]).get() == 1);
+        assert_eq!(data.lock().unwrap().rotation_count.get(), 0);
+
+        // Renamed away, the watch is restarted to open the new file
+        tx.send(event(notify::op::Op::RENAME)).unwrap();
+        thread.join().unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.rotation_count.get(), 1);
+        assert_eq!(data.truncation_count.get(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-fifo.log");
+        let _ = std::fs::remove_file(&path);
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        let reader = std::thread::spawn(move || processor.watch_log().ok());
+
+        let wait_for_count = |expected: u64| {
+            for _ in 0..100 {
+                if data.lock().unwrap().request_count.with_label_values(&["example.org", "200"]).get() == expected {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            panic!("Lines were not read from the FIFO");
+        };
+
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writeln!(writer, "example.org 200").unwrap();
+        writeln!(writer, "example.org 200").unwrap();
+        drop(writer);
+        wait_for_count(2);
+
+        // The FIFO is opened again for the next writer, and its last line
+        // doesn't need a terminator
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writeln!(writer, "example.org 200").unwrap();
+        write!(writer, "example.org 200").unwrap();
+        // Reopening fails once this writer is gone, which stops the reader
+        std::fs::remove_file(&path).unwrap();
+        drop(writer);
+        wait_for_count(4);
+        reader.join().unwrap();
+        assert_eq!(data.lock().unwrap().error_count.get(), 0);
+    }
+
+    #[test]
+    fn test_tcp_listener() {
+        use std::io::Write;
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        collector_builder.set_tcp_listener(listener);
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        std::thread::spawn(move || processor.watch_log().ok());
+
+        // Two senders, with lines split across writes
+        let mut first = std::net::TcpStream::connect(addr).unwrap();
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        first.write_all(b"example.org 200\nexample.o").unwrap();
+        first.flush().unwrap();
+        second.write_all(b"example.org 404\nexam").unwrap();
+        second.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        first.write_all(b"rg 200\n").unwrap();
+        second.write_all(b"ple.org 404").unwrap();
+        drop(first);
+        // The last line is terminated by closing the connection
+        drop(second);
+
+        for _ in 0..100 {
+            {
+                let data = data.lock().unwrap();
+                let ok = data.request_count.with_label_values(&["example.org", "200"]).get();
+                let not_found = data.request_count.with_label_values(&["example.org", "404"]).get();
+                if ok == 2 && not_found == 2 {
+                    assert_eq!(data.error_count.get(), 0);
+                    assert!(data.active);
+                    return;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("Lines were not read from the connections");
+    }
+
+    #[test]
+    fn test_tcp_listener_long_line() {
+        use std::io::Write;
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            max_buffer: Some(100),
+            ..Default::default()
+        });
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        collector_builder.set_tcp_listener(listener);
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        std::thread::spawn(move || processor.watch_log().ok());
+
+        // A line without end is dropped, reading resumes after its end
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"example.org 200\n").unwrap();
+        for _ in 0..10 {
+            stream.write_all(&[b'a'; 50]).unwrap();
+            stream.flush().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        stream.write_all(b"\nexample.org 404\n").unwrap();
+        drop(stream);
+
+        for _ in 0..100 {
+            {
+                let data = data.lock().unwrap();
+                if data.request_count.with_label_values(&["example.org", "404"]).get() == 1 {
+                    assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 1);
+                    assert_eq!(data.error_count.get(), 1);
+                    return;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("Lines were not read from the connection");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-gzip.log.1.gz");
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"example.org 200\nexample.org 200\nexample.org 404\ngarbage\n").unwrap();
+        encoder.finish().unwrap();
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(log_parser, path.clone());
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+        // The whole file is read, from the start
+        processor.read_gzip().unwrap();
+
+        let data = data.lock().unwrap();
+        assert!(data.active);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "200"]).get(), 2);
+        assert_eq!(data.request_count.with_label_values(&["example.org", "404"]).get(), 1);
+        assert_eq!(data.error_count.get(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let log_parser = LogParser::from_format("$remote_addr $status").unwrap();
+        let collector_builder = LogCollectorBuilder::new(
+            log_parser,
+            std::env::temp_dir().join("access-log-to-prometheus-metrics-missing.log"),
         );
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        data.lock().unwrap().file_present.set(1);
+        let processor = collector_builder.build_processor(data.clone());
+
+        processor.watch_log().unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.file_present.get(), 0);
+        assert_eq!(data.watch_active.get(), 0);
+    }
+
+    #[cfg(feature = "re")]
+    #[test]
+    fn test_match_alternatives() {
+        use crate::processor::FilterFunc;
+
+        let log_parser = LogParser::from_format("$host $status").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        // --match status:^2 --match status:^3 --match host:\.org$
+        for (field, pattern) in &[("status", "^2"), ("status", "^3"), ("host", "\\.org$")] {
+            collector_builder.add_filter(field.to_string(), FilterFunc::regex(pattern, false).unwrap()).unwrap();
+        }
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        // Either status
+        test_parse(&processor, "example.org 200", Some((&["example.org", "200"], None, None)));
+        test_parse(&processor, "example.org 304", Some((&["example.org", "304"], None, None)));
+        test_parse(&processor, "example.org 404", None);
+        // But both fields have to match
+        test_parse(&processor, "remram.fr 200", None);
+    }
+
+    #[cfg(feature = "re")]
+    #[test]
+    fn test_url_decode() {
+        use crate::processor::FilterFunc;
+
+        let log_parser = LogParser::from_format(r#""$request" $status"#).unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_processor_options(ProcessorOptions {
+            url_decode: true,
+            ..Default::default()
+        });
+        collector_builder.add_filter(
+            "request".to_owned(),
+            FilterFunc::Regex { regex: regex::Regex::new("^GET /files/my report\\.pdf ").unwrap() },
+        ).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(&processor, r#""GET /files/my%20report%2Epdf HTTP/1.1" 200"#, Some((&["200"], None, None)));
+        test_parse(&processor, r#""GET /files/my report.pdf HTTP/1.1" 200"#, Some((&["200"], None, None)));
+        test_parse(&processor, r#""GET /files/other%20report.pdf HTTP/1.1" 200"#, None);
     }
 
     #[cfg(feature = "re")]
@@ -299,4 +3814,37 @@ mod tests {
             None,
         );
     }
+
+    #[cfg(feature = "re")]
+    #[test]
+    fn test_process_re_case_insensitive() {
+        use crate::processor::FilterFunc;
+
+        let log_parser = LogParser::from_format(
+            r#"$host $remote_addr - $remote_user [$time_local] "$request" $status $request_time $body_bytes_sent "$http_referer" "$http_user_agent""#,
+        ).unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        // --match-ci 'http_user_agent:bot'
+        collector_builder.add_filter(
+            "http_user_agent".to_owned(),
+            FilterFunc::regex("bot", true).unwrap(),
+        ).unwrap();
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data);
+
+        test_parse(
+            &processor,
+            r#"example.org 1.2.3.4 - - [11/Nov/2021:02:34:39 +0000] "GET /robots.txt HTTP/1.1" 200 0.002 58 "-" "Mozilla/5.0 (compatible; Googlebot/2.1)""#,
+            Some((
+                &["example.org", "no", "200"],
+                Some(0.002),
+                Some(58),
+            )),
+        );
+        test_parse(
+            &processor,
+            r#"example.org 8.8.8.8 - - [11/Nov/2021:02:34:41 +0000] "GET / HTTP/1.1" 200 0.012 612 "-" "Mozilla/5.0 (Linux)""#,
+            None,
+        );
+    }
 }
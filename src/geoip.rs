@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::processor::strip_port;
+
+/// Number of addresses to remember before the cache is cleared.
+const CACHE_SIZE: usize = 10000;
+
+/// Looks up the country of IP addresses in a MaxMind database.
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+    cache: Mutex<HashMap<IpAddr, Option<String>>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, maxminddb::MaxMindDbError> {
+        Ok(GeoIp::from_reader(maxminddb::Reader::open_readfile(path)?))
+    }
+
+    fn from_reader(reader: maxminddb::Reader<Vec<u8>>) -> GeoIp {
+        GeoIp {
+            reader,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the ISO code of the country for an address, if it can be found.
+    /// The address can have a port, e.g. `1.2.3.4:5678` or `[::1]:5678`.
+    pub fn country(&self, addr: &str) -> Option<String> {
+        let addr: IpAddr = strip_port(addr).parse().ok()?;
+        if is_private(&addr) {
+            return None;
+        }
+
+        if let Some(country) = self.cache.lock().unwrap().get(&addr) {
+            return country.clone();
+        }
+
+        let country = match self.reader.lookup(addr).and_then(|r| r.decode::<maxminddb::geoip2::Country>()) {
+            Ok(Some(record)) => record.country.iso_code.map(|c| c.to_owned()),
+            _ => None,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(addr, country.clone());
+        country
+    }
+}
+
+fn is_private(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(a) => a.is_private() || a.is_loopback() || a.is_link_local() || a.is_unspecified(),
+        IpAddr::V6(a) => {
+            a.is_loopback()
+                || a.is_unspecified()
+                // Unique local, fc00::/7
+                || (a.segments()[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (a.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GeoIp;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.push(0x40 | s.len() as u8);
+        out.extend(s.as_bytes());
+    }
+
+    fn uint16(out: &mut Vec<u8>, key: &str, value: u16) {
+        string(out, key);
+        out.push(0xA2);
+        out.extend(&value.to_be_bytes());
+    }
+
+    /// Build a tiny IPv4 MaxMind database mapping /8 networks to countries.
+    fn build_db(networks: &[(u8, &str)]) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+
+        // Data section
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for &(_, country) in networks {
+            offsets.push(data.len());
+            data.push(0xE1);
+            string(&mut data, "country");
+            data.push(0xE1);
+            string(&mut data, "iso_code");
+            string(&mut data, country);
+        }
+
+        // Search tree
+        let mut nodes = vec![[Record::Empty; 2]];
+        for (&(first_octet, _), &offset) in networks.iter().zip(&offsets) {
+            let mut node = 0;
+            for bit in (0..8).rev() {
+                let side = ((first_octet >> bit) & 1) as usize;
+                if bit == 0 {
+                    nodes[node][side] = Record::Data(offset);
+                } else {
+                    node = match nodes[node][side] {
+                        Record::Node(n) => n,
+                        _ => {
+                            nodes.push([Record::Empty; 2]);
+                            nodes[node][side] = Record::Node(nodes.len() - 1);
+                            nodes.len() - 1
+                        }
+                    };
+                }
+            }
+        }
+        let node_count = nodes.len();
+        let mut out = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match *record {
+                    Record::Empty => node_count,
+                    Record::Node(n) => n,
+                    Record::Data(offset) => node_count + 16 + offset,
+                } as u32;
+                out.extend(&value.to_be_bytes()[1..]);
+            }
+        }
+        out.extend(&[0; 16]);
+        out.extend(&data);
+
+        // Metadata
+        out.extend(b"\xab\xcd\xefMaxMind.com");
+        out.push(0xE9);
+        string(&mut out, "node_count");
+        out.push(0xC4);
+        out.extend(&(node_count as u32).to_be_bytes());
+        uint16(&mut out, "record_size", 24);
+        uint16(&mut out, "ip_version", 4);
+        string(&mut out, "database_type");
+        string(&mut out, "Test-Country");
+        string(&mut out, "languages");
+        out.extend(&[0x01, 0x04]);
+        string(&mut out, "en");
+        uint16(&mut out, "binary_format_major_version", 2);
+        uint16(&mut out, "binary_format_minor_version", 0);
+        string(&mut out, "build_epoch");
+        out.extend(&[0x08, 0x02]);
+        out.extend(&1636598079u64.to_be_bytes());
+        string(&mut out, "description");
+        out.push(0xE1);
+        string(&mut out, "en");
+        string(&mut out, "Test database");
+        out
+    }
+
+    #[test]
+    fn test_country() {
+        let reader = maxminddb::Reader::from_source(build_db(&[(1, "US"), (81, "FR")])).unwrap();
+        let geoip = GeoIp::from_reader(reader);
+
+        assert_eq!(geoip.country("1.2.3.4"), Some("US".to_owned()));
+        assert_eq!(geoip.country("81.0.0.1"), Some("FR".to_owned()));
+        // Cached
+        assert_eq!(geoip.country("1.2.3.4"), Some("US".to_owned()));
+        assert_eq!(geoip.cache.lock().unwrap().len(), 2);
+        // With a port
+        assert_eq!(geoip.country("1.2.3.4:5678"), Some("US".to_owned()));
+        assert_eq!(geoip.cache.lock().unwrap().len(), 2);
+        assert_eq!(geoip.country("[::1]:5678"), None);
+        // Not in database
+        assert_eq!(geoip.country("8.8.8.8"), None);
+        // Private and invalid
+        assert_eq!(geoip.country("192.168.1.10"), None);
+        assert_eq!(geoip.country("127.0.0.1"), None);
+        assert_eq!(geoip.country("::1"), None);
+        assert_eq!(geoip.country("-"), None);
+        assert_eq!(geoip.country("not an ip"), None);
+    }
+}
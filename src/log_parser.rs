@@ -1,11 +1,18 @@
 use log::debug;
 
 #[derive(Debug)]
-pub struct ParseError(pub String);
+pub enum ParseError {
+    Invalid(String),
+    /// The line has data after the end of the format
+    TrailingData(String),
+}
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(fmt, "Parse error: {}", self.0)
+        match self {
+            ParseError::Invalid(msg) => write!(fmt, "Parse error: {}", msg),
+            ParseError::TrailingData(msg) => write!(fmt, "Trailing data: {}", msg),
+        }
     }
 }
 
@@ -17,10 +24,7 @@ trait PeekableCharIndicesPosExt {
 
 impl<'a> PeekableCharIndicesPosExt for std::iter::Peekable<std::str::CharIndices<'a>> {
     fn pos(&mut self) -> Option<usize> {
-        match self.peek() {
-            None => None,
-            Some((i, _)) => Some(*i),
-        }
+        self.peek().map(|(i, _)| *i)
     }
 }
 
@@ -28,6 +32,39 @@ impl<'a> PeekableCharIndicesPosExt for std::iter::Peekable<std::str::CharIndices
 enum LogToken {
     Str(String),
     Field(String),
+    /// A field that is read over but not returned.
+    Skip(String),
+    /// A field that is exactly this many characters, so it doesn't need a
+    /// separator after it; the flag is set if it is not returned.
+    Fixed(String, usize, bool),
+    /// A run of one or more whitespace characters.
+    Whitespace,
+}
+
+#[derive(Debug)]
+enum Separator {
+    Char(char),
+    Whitespace,
+}
+
+impl Separator {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Separator::Char(sep) => c == *sep,
+            Separator::Whitespace => c.is_whitespace(),
+        }
+    }
+}
+
+/// Options changing how the format is turned into tokens.
+#[derive(Clone, Debug, Default)]
+pub struct LogParserOptions {
+    /// Match whitespace in the format against any run of whitespace.
+    pub loose_whitespace: bool,
+    /// Fail on lines that have data left after the end of the format.
+    pub reject_trailing_data: bool,
+    /// Variables that are not returned, and are not in `fields()`.
+    pub ignore_fields: Vec<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -39,23 +76,30 @@ pub struct LogValue<'a> {
 pub struct LogParser {
     tokens: Vec<LogToken>,
     fields: Vec<String>,
+    reject_trailing_data: bool,
 }
 
 impl LogParser {
+    #[cfg(test)]
     pub fn from_format(format: &str) -> Result<LogParser, ParseError> {
-        let tokens = LogFormatParser::new(format).parse()?;
+        Self::from_format_with_options(format, &LogParserOptions::default())
+    }
+
+    pub fn from_format_with_options(format: &str, options: &LogParserOptions) -> Result<LogParser, ParseError> {
+        let tokens = LogFormatParser::new(format, options).parse()?;
         let fields = tokens.iter().filter_map(|token| match token {
-            LogToken::Str(_) => None,
-            LogToken::Field(s) => Some(s.clone()),
+            LogToken::Field(s) | LogToken::Fixed(s, _, false) => Some(s.clone()),
+            _ => None,
         }).collect();
         Ok(LogParser {
             tokens,
             fields,
+            reject_trailing_data: options.reject_trailing_data,
         })
     }
 
     pub fn parse<'a>(&'a self, log: &'a str) -> Result<Vec<LogValue<'a>>, ParseError> {
-        LogParserInner::new(&self.tokens, log).parse()
+        LogParserInner::new(&self.tokens, log).parse(self.reject_trailing_data)
     }
 
     pub fn fields(&self) -> &[String] {
@@ -63,6 +107,17 @@ impl LogParser {
     }
 }
 
+/// Build a format where the fields are separated by tabs, from their names
+/// separated by spaces or commas, e.g. `host status request_time`.
+pub fn tsv_format(names: &str) -> String {
+    names.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|n| n.trim_start_matches('$'))
+        .filter(|n| !n.is_empty())
+        .map(|n| format!("${}", n))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
 struct LogParserInner<'a> {
     tokens: &'a [LogToken],
     log: &'a str,
@@ -80,13 +135,13 @@ impl<'a> LogParserInner<'a> {
         }
     }
 
-    fn parse(mut self) -> Result<Vec<LogValue<'a>>, ParseError> {
+    fn parse(mut self, reject_trailing_data: bool) -> Result<Vec<LogValue<'a>>, ParseError> {
         for i in 0..self.tokens.len() {
             let token = &self.tokens[i];
             debug!("Matching token {:?}", token);
 
             match token {
-                &LogToken::Str(ref s) => {
+                LogToken::Str(s) => {
                     let start = self.iter.pos().unwrap_or(self.log.len());
                     let mut it = s.chars();
                     loop {
@@ -96,36 +151,61 @@ impl<'a> LogParserInner<'a> {
                                 if e == a {
                                     self.iter.next();
                                 } else {
-                                    return Err(ParseError(format!("Expected {:?}, found {:?}", s, &self.log[start..i])));
+                                    return Err(ParseError::Invalid(format!("Expected {:?}, found {:?}", s, &self.log[start..i])));
                                 }
                             }
                             (None, Some(_)) => break,
-                            (Some(_), None) => return Err(ParseError(format!("Expected {:?}, found {:?}", s, &self.log[start..]))),
+                            (Some(_), None) => return Err(ParseError::Invalid(format!("Expected {:?}, found {:?}", s, &self.log[start..]))),
+                        }
+                    }
+                }
+                LogToken::Whitespace => {
+                    let mut found = false;
+                    while let Some(&(_, c)) = self.iter.peek() {
+                        if c.is_whitespace() {
+                            self.iter.next();
+                            found = true;
+                        } else {
+                            break;
                         }
                     }
+                    if !found {
+                        let rest = match self.iter.pos() {
+                            Some(i) => &self.log[i..],
+                            None => "",
+                        };
+                        return Err(ParseError::Invalid(format!("Expected whitespace, found {:?}", rest)));
+                    }
                 }
-                &LogToken::Field(ref f) => {
+                LogToken::Field(f) | LogToken::Skip(f) => {
                     let next = match self.tokens.get(i + 1) {
                         None => None,
-                        Some(&LogToken::Str(ref s)) => Some(s.chars().next().unwrap()),
-                        Some(n) => return Err(ParseError(format!("Can't parse, no separator between {:?} and {:?}", f, n))),
+                        Some(LogToken::Str(s)) => Some(Separator::Char(s.chars().next().unwrap())),
+                        Some(LogToken::Whitespace) => Some(Separator::Whitespace),
+                        Some(n) => return Err(ParseError::Invalid(format!("Can't parse, no separator between {:?} and {:?}", f, n))),
                     };
 
                     let value = match next {
                         Some(sep) => {
                             debug!("Reading to separator {:?}", sep);
+                            // In quoted fields, a backslash escapes the next
+                            // character, e.g. with `escape=json`
+                            let quoted = matches!(sep, Separator::Char('"'));
                             match self.iter.pos() {
                                 Some(start) => {
                                     loop {
                                         match self.iter.peek() {
                                             Some(&(i, c)) => {
-                                                if c == sep {
+                                                if sep.matches(c) {
                                                     break &self.log[start..i];
                                                 } else {
                                                     self.iter.next();
+                                                    if quoted && c == '\\' {
+                                                        self.iter.next();
+                                                    }
                                                 }
                                             }
-                                            None => return Err(ParseError(format!("Missing separator {:?}", sep))),
+                                            None => return Err(ParseError::Invalid(format!("Missing separator {:?}", sep))),
                                         }
                                     }
                                 }
@@ -134,33 +214,70 @@ impl<'a> LogParserInner<'a> {
                                 }
                             }
                         }
+                        None if reject_trailing_data => {
+                            // Stop at whitespace, so what follows is trailing
+                            // data rather than part of the last field
+                            debug!("Last token, reading to whitespace");
+                            let start = self.iter.pos().unwrap_or(self.log.len());
+                            while let Some(&(_, c)) = self.iter.peek() {
+                                if c.is_whitespace() {
+                                    break;
+                                }
+                                self.iter.next();
+                            }
+                            &self.log[start..self.iter.pos().unwrap_or(self.log.len())]
+                        }
                         None => {
                             debug!("Last token, reading to end");
                             match self.iter.pos() {
-                                Some(i) => &self.log[i..],
+                                Some(i) => {
+                                    self.iter.by_ref().for_each(drop);
+                                    &self.log[i..]
+                                }
                                 None => "",
                             }
                         }
                     };
 
-                    self.values.push(LogValue { variable: f, value });
+                    if let LogToken::Field(_) = token {
+                        self.values.push(LogValue { variable: f, value });
+                    }
+                }
+                LogToken::Fixed(f, width, skip) => {
+                    let start = self.iter.pos().unwrap_or(self.log.len());
+                    for _ in 0..*width {
+                        if self.iter.next().is_none() {
+                            return Err(ParseError::Invalid(format!("Expected {} characters for {:?}, found {:?}", width, f, &self.log[start..])));
+                        }
+                    }
+                    let end = self.iter.pos().unwrap_or(self.log.len());
+                    if !skip {
+                        self.values.push(LogValue { variable: f, value: &self.log[start..end] });
+                    }
                 }
             }
         }
+        if reject_trailing_data {
+            if let Some(i) = self.iter.pos() {
+                return Err(ParseError::TrailingData(format!("Unexpected {:?} after the last field", &self.log[i..])));
+            }
+        }
         Ok(self.values)
     }
 }
 
 struct LogFormatParser<'a> {
     format: &'a str,
+    options: &'a LogParserOptions,
     iter: std::iter::Peekable<std::str::CharIndices<'a>>,
     tokens: Vec<LogToken>,
 }
 
 impl<'a> LogFormatParser<'a> {
-    fn new(format: &'a str) -> LogFormatParser<'a> {
+    fn new(format: &'a str, options: &'a LogParserOptions) -> LogFormatParser<'a> {
         LogFormatParser {
             format,
+            options,
             iter: format.char_indices().peekable(),
             tokens: Vec::new(),
         }
@@ -169,7 +286,7 @@ impl<'a> LogFormatParser<'a> {
     fn parse(mut self) -> Result<Vec<LogToken>, ParseError> {
         self.skip_whitespace();
         if self.iter.peek().is_none() {
-            return Err(ParseError("Empty string".to_owned()));
+            return Err(ParseError::Invalid("Empty string".to_owned()));
         }
         if self.maybe_consume("log_format") {
             debug!("Starts with log_format");
@@ -179,30 +296,30 @@ impl<'a> LogFormatParser<'a> {
             }
             match self.iter.next() {
                 Some((_, '\'')) => {}
-                _ => return Err(ParseError("Missing \'".to_owned())),
+                _ => return Err(ParseError::Invalid("Missing \'".to_owned())),
             }
             self.parse_format()?;
             debug!("Finishing up: \"{}\"", if let Some(i) = self.iter.pos() { &self.format[i..] } else { "" });
             match self.iter.next() {
                 Some((_, '\'')) => {},
-                _ => return Err(ParseError("Missing final '".to_owned())),
+                _ => return Err(ParseError::Invalid("Missing final '".to_owned())),
             }
             match self.iter.next() {
                 None => {}
                 Some((_, ';')) => {
                     self.skip_whitespace();
                     if self.iter.next().is_some() {
-                        return Err(ParseError("Unexpected characters at the end".to_owned()));
+                        return Err(ParseError::Invalid("Unexpected characters at the end".to_owned()));
                     }
                 }
                 Some(_) => {
-                    return Err(ParseError("Unexpected characters at the end".to_owned()));
+                    return Err(ParseError::Invalid("Unexpected characters at the end".to_owned()));
                 }
             }
         } else {
             self.parse_format()?;
             if self.iter.next().is_some() {
-                return Err(ParseError("Unexpected characters at the end".to_owned()));
+                return Err(ParseError::Invalid("Unexpected characters at the end".to_owned()));
             }
         }
         Ok(self.tokens)
@@ -216,25 +333,52 @@ impl<'a> LogFormatParser<'a> {
             } else if c == '$' {
                 debug!("Found variable");
                 self.iter.next();
+                // "${name:N}" is a field of exactly N characters
+                let braced = self.maybe_consume("{");
                 let var = self.read_identifier()?;
                 debug!("Read identifier: {}", var);
-                self.tokens.push(LogToken::Field(var.to_owned()));
-            } else {
-                debug!("Found character {:?}", c);
-                self.iter.next();
-                match self.tokens.last_mut() {
-                    Some(LogToken::Str(ref mut s)) => s.push(c),
-                    _ => {
-                        let mut s = String::new();
-                        s.push(c);
-                        self.tokens.push(LogToken::Str(s));
-                    }
+                let width = if braced { self.read_width()? } else { None };
+                let skip = self.options.ignore_fields.iter().any(|f| f == var);
+                if let Some(width) = width {
+                    self.tokens.push(LogToken::Fixed(var.to_owned(), width, skip));
+                } else if skip {
+                    self.tokens.push(LogToken::Skip(var.to_owned()));
+                } else {
+                    self.tokens.push(LogToken::Field(var.to_owned()));
                 }
+            } else {
+                // "\t" is a tab, which is hard to type on the command line
+                let c = if self.maybe_consume("\\t") {
+                    '\t'
+                } else {
+                    self.iter.next();
+                    c
+                };
+                self.push_char(c);
             }
         }
         Ok(())
     }
 
+    fn push_char(&mut self, c: char) {
+        if self.options.loose_whitespace && c.is_whitespace() {
+            debug!("Found whitespace");
+            if self.tokens.last() != Some(&LogToken::Whitespace) {
+                self.tokens.push(LogToken::Whitespace);
+            }
+        } else {
+            debug!("Found character {:?}", c);
+            match self.tokens.last_mut() {
+                Some(LogToken::Str(ref mut s)) => s.push(c),
+                _ => {
+                    let mut s = String::new();
+                    s.push(c);
+                    self.tokens.push(LogToken::Str(s));
+                }
+            }
+        }
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.iter.peek() {
@@ -280,8 +424,8 @@ impl<'a> LogFormatParser<'a> {
         let identifier = loop {
             match self.iter.peek() {
                 Some(&(i, c)) => {
-                    if ('a' <= c && c <= 'z')
-                        || ('0' <= c && c <= '9')
+                    if c.is_ascii_lowercase()
+                        || c.is_ascii_digit()
                         || c == '_' {
                         self.iter.next();
                     } else {
@@ -292,10 +436,44 @@ impl<'a> LogFormatParser<'a> {
             }
         };
         if identifier.is_empty() {
-            return Err(ParseError("Expected identifier".to_owned()));
+            return Err(ParseError::Invalid("Expected identifier".to_owned()));
         }
         Ok(identifier)
     }
+
+    /// Read the optional width and the closing brace after "${name".
+    fn read_width(&mut self) -> Result<Option<usize>, ParseError> {
+        let width = if self.maybe_consume(":") {
+            let mut digits = String::new();
+            while let Some(&(_, c)) = self.iter.peek() {
+                if c.is_ascii_digit() {
+                    digits.push(c);
+                    self.iter.next();
+                } else {
+                    break;
+                }
+            }
+            match digits.parse() {
+                Ok(w) if w > 0 => Some(w),
+                _ => return Err(ParseError::Invalid("Expected a width after ':'".to_owned())),
+            }
+        } else {
+            None
+        };
+        if !self.maybe_consume("}") {
+            return Err(ParseError::Invalid("Missing }".to_owned()));
+        }
+        Ok(width)
+    }
+}
+
+/// An expected value, for the tests.
+#[cfg(test)]
+fn v(n: &'static str, d: &'static str) -> LogValue<'static> {
+    LogValue {
+        variable: n,
+        value: d,
+    }
 }
 
 #[test]
@@ -308,15 +486,15 @@ fn test_format_parser() {
     }
 
     assert_eq!(
-        LogFormatParser::new("log_format combined '$remote_addr - $remote_user [$time_local]';").parse().unwrap(),
+        LogFormatParser::new("log_format combined '$remote_addr - $remote_user [$time_local]';", &LogParserOptions::default()).parse().unwrap(),
         vec![f("remote_addr"), s(" - "), f("remote_user"), s(" ["), f("time_local"), s("]")],
     );
     assert_eq!(
-        LogFormatParser::new("    log_format '$remote_addr - $remote_user [$time_local]';  ").parse().unwrap(),
+        LogFormatParser::new("    log_format '$remote_addr - $remote_user [$time_local]';  ", &LogParserOptions::default()).parse().unwrap(),
         vec![f("remote_addr"), s(" - "), f("remote_user"), s(" ["), f("time_local"), s("]")],
     );
     assert_eq!(
-        LogFormatParser::new("$remote_addr - $remote_user [$time_local]").parse().unwrap(),
+        LogFormatParser::new("$remote_addr - $remote_user [$time_local]", &LogParserOptions::default()).parse().unwrap(),
         vec![f("remote_addr"), s(" - "), f("remote_user"), s(" ["), f("time_local"), s("]")],
     );
 }
@@ -329,16 +507,11 @@ fn test_parser() {
     fn s(r: &str) -> LogToken {
         LogToken::Str(r.to_owned())
     }
-    fn v(n: &'static str, d: &'static str) -> LogValue<'static> {
-        LogValue {
-            variable: n,
-            value: d,
-        }
-    }
 
     let parser = LogParser {
         tokens: vec![f("remote_addr"), s(" - "), f("remote_user"), s(" "), f("request_time"), s(" ["), f("time_local"), s("]")],
         fields: vec!["remote_addr".to_owned(), "remote_user".to_owned(), "request_time".to_owned(), "time_local".to_owned()],
+        reject_trailing_data: false,
     };
 
     assert_eq!(
@@ -346,3 +519,153 @@ fn test_parser() {
         vec![v("remote_addr", "216.165.95.86"), v("remote_user", "remi"), v("request_time", "0.012"), v("time_local", "15/Oct/2021:15:39:52 +0000")],
     );
 }
+
+#[test]
+fn test_parser_empty_quoted() {
+    let parser = LogParser::from_format(r#"$remote_addr "$http_referer" "$http_user_agent" $status"#).unwrap();
+    assert_eq!(
+        parser.parse(r#"1.2.3.4 "" "curl/7.79.1" 200"#).unwrap(),
+        vec![v("remote_addr", "1.2.3.4"), v("http_referer", ""), v("http_user_agent", "curl/7.79.1"), v("status", "200")],
+    );
+    assert_eq!(
+        parser.parse(r#"1.2.3.4 "" "" 200"#).unwrap(),
+        vec![v("remote_addr", "1.2.3.4"), v("http_referer", ""), v("http_user_agent", ""), v("status", "200")],
+    );
+
+    // Empty field at the end of the line
+    let parser = LogParser::from_format(r#"$status "$http_referer""#).unwrap();
+    assert_eq!(parser.parse(r#"200 """#).unwrap(), vec![v("status", "200"), v("http_referer", "")]);
+}
+
+#[test]
+fn test_parser_escaped_quotes() {
+    // Escaped quotes don't end a quoted field, values are not unescaped
+    let parser = LogParser::from_format(r#"$remote_addr "$http_user_agent" $status"#).unwrap();
+    assert_eq!(
+        parser.parse(r#"1.2.3.4 "Mozilla/5.0 (\"quoted\" agent)" 200"#).unwrap(),
+        vec![v("remote_addr", "1.2.3.4"), v("http_user_agent", r#"Mozilla/5.0 (\"quoted\" agent)"#), v("status", "200")],
+    );
+    assert_eq!(
+        parser.parse(r#"1.2.3.4 "ends with a backslash \\" 200"#).unwrap(),
+        vec![v("remote_addr", "1.2.3.4"), v("http_user_agent", r#"ends with a backslash \\"#), v("status", "200")],
+    );
+
+    // Backslashes have no special meaning in unquoted fields
+    let parser = LogParser::from_format(r#"$request_uri $status"#).unwrap();
+    assert_eq!(parser.parse(r#"/a\ 200"#).unwrap(), vec![v("request_uri", r#"/a\"#), v("status", "200")]);
+}
+
+#[test]
+fn test_parser_ignore_fields() {
+    let options = LogParserOptions { ignore_fields: vec!["time_local".to_owned(), "http_referer".to_owned()], ..Default::default() };
+    let parser = LogParser::from_format_with_options(r#"$remote_addr [$time_local] "$request" $status "$http_referer""#, &options).unwrap();
+    assert_eq!(parser.fields(), &["remote_addr".to_owned(), "request".to_owned(), "status".to_owned()]);
+    assert_eq!(
+        parser.parse(r#"1.2.3.4 [11/Nov/2021:02:34:39 +0000] "GET / HTTP/1.1" 200 "https://example.org/""#).unwrap(),
+        vec![v("remote_addr", "1.2.3.4"), v("request", "GET / HTTP/1.1"), v("status", "200")],
+    );
+    // Ignored fields still have to be there
+    assert!(parser.parse(r#"1.2.3.4 "GET / HTTP/1.1" 200"#).is_err());
+}
+
+#[test]
+fn test_parser_fixed_width() {
+    let options = LogParserOptions { ignore_fields: vec!["pad".to_owned()], ..Default::default() };
+    let parser = LogParser::from_format_with_options("${status:3}${pad:1}${request_time:5}$request_uri ${host}", &options).unwrap();
+    assert_eq!(parser.fields(), &["status".to_owned(), "request_time".to_owned(), "request_uri".to_owned(), "host".to_owned()]);
+    assert_eq!(
+        parser.parse("404_0.012/index.html example.org").unwrap(),
+        vec![v("status", "404"), v("request_time", "0.012"), v("request_uri", "/index.html"), v("host", "example.org")],
+    );
+    // Widths are in characters
+    assert_eq!(
+        parser.parse("200 1.500/é x").unwrap(),
+        vec![v("status", "200"), v("request_time", "1.500"), v("request_uri", "/é"), v("host", "x")],
+    );
+    assert!(parser.parse("2000").is_err());
+
+    assert!(LogParser::from_format("${status:0}").is_err());
+    assert!(LogParser::from_format("${status:3").is_err());
+    // Other fields still need a separator
+    assert!(LogParser::from_format("$status$request_time").unwrap().parse("200 0.1").is_err());
+}
+
+#[test]
+fn test_parser_tabs() {
+    let expected = vec![v("host", "example.org"), v("request", "GET / HTTP/1.1"), v("status", "200")];
+    let parser = LogParser::from_format("$host\\t$request\\t$status").unwrap();
+    assert_eq!(parser.tokens[1], LogToken::Str("\t".to_owned()));
+    assert_eq!(parser.parse("example.org\tGET / HTTP/1.1\t200").unwrap(), expected);
+    assert!(parser.parse("example.org GET / HTTP/1.1 200").is_err());
+
+    // Literal tabs work the same
+    let parser = LogParser::from_format("$host\t$request\t$status").unwrap();
+    assert_eq!(parser.parse("example.org\tGET / HTTP/1.1\t200").unwrap(), expected);
+
+    // Other backslashes are left alone
+    let parser = LogParser::from_format("$host\\$status").unwrap();
+    assert_eq!(parser.tokens[1], LogToken::Str("\\".to_owned()));
+
+    assert_eq!(tsv_format("host request status"), "$host\t$request\t$status");
+    assert_eq!(tsv_format("$host, $request,$status"), "$host\t$request\t$status");
+    let parser = LogParser::from_format(&tsv_format("host request status")).unwrap();
+    assert_eq!(parser.parse("example.org\tGET / HTTP/1.1\t200").unwrap(), expected);
+}
+
+#[test]
+fn test_parser_loose_whitespace() {
+    fn f(n: &str) -> LogToken {
+        LogToken::Field(n.to_owned())
+    }
+    fn s(r: &str) -> LogToken {
+        LogToken::Str(r.to_owned())
+    }
+
+    let options = LogParserOptions { loose_whitespace: true, ..Default::default() };
+    assert_eq!(
+        LogFormatParser::new("$remote_addr  -  $status [$time_local]", &options).parse().unwrap(),
+        vec![f("remote_addr"), LogToken::Whitespace, s("-"), LogToken::Whitespace, f("status"), LogToken::Whitespace, s("["), f("time_local"), s("]")],
+    );
+
+    let parser = LogParser::from_format_with_options("$remote_addr - $status $request_time [$time_local]", &options).unwrap();
+    let expected = vec![v("remote_addr", "216.165.95.86"), v("status", "200"), v("request_time", "0.012"), v("time_local", "15/Oct/2021:15:39:52 +0000")];
+    assert_eq!(
+        parser.parse("216.165.95.86 - 200 0.012 [15/Oct/2021:15:39:52 +0000]").unwrap(),
+        expected,
+    );
+    assert_eq!(
+        parser.parse("216.165.95.86   -  200\t0.012     [15/Oct/2021:15:39:52 +0000]").unwrap(),
+        expected,
+    );
+    assert!(parser.parse("216.165.95.86 -200 0.012 [15/Oct/2021:15:39:52 +0000]").is_err());
+}
+
+#[test]
+fn test_parser_trailing_data() {
+    let format = r#"$remote_addr [$time_local] "$request" $status"#;
+    let line = r#"1.2.3.4 [11/Nov/2021:02:34:39 +0000] "GET / HTTP/1.1" 200"#;
+
+    let parser = LogParser::from_format_with_options(format, &LogParserOptions { reject_trailing_data: true, ..Default::default() }).unwrap();
+    assert!(parser.parse(line).is_ok());
+
+    // Extra data is only an error with the option
+    let format = r#"$remote_addr [$time_local] "$request""#;
+    let lenient = LogParser::from_format(format).unwrap();
+    assert!(lenient.parse(line).is_ok());
+    let parser = LogParser::from_format_with_options(format, &LogParserOptions { reject_trailing_data: true, ..Default::default() }).unwrap();
+    match parser.parse(line) {
+        Err(ParseError::TrailingData(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    // A last field doesn't take in the extra data
+    let format = r#"$remote_addr [$time_local] "$request" $status"#;
+    let line = r#"1.2.3.4 [11/Nov/2021:02:34:39 +0000] "GET / HTTP/1.1" 200 extra"#;
+    let lenient = LogParser::from_format(format).unwrap();
+    assert_eq!(lenient.parse(line).unwrap()[3].value, "200 extra");
+    let parser = LogParser::from_format_with_options(format, &LogParserOptions { reject_trailing_data: true, ..Default::default() }).unwrap();
+    match parser.parse(line) {
+        Err(ParseError::TrailingData(msg)) => assert_eq!(msg, "Unexpected \" extra\" after the last field"),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
@@ -1,137 +1,1596 @@
+mod buckets;
 mod collector;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod hyperloglog;
 mod log_parser;
+mod offset;
+mod openmetrics;
 mod processor;
+mod pushgateway;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+mod sha256;
+#[cfg(feature = "statsd")]
+mod statsd;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+mod timestamp;
+mod value_set;
 
-use clap::{App, Arg};
-use hyper::header::CONTENT_TYPE;
+use clap::{App, Arg, ArgMatches};
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Method, Request, Response, Server};
 use log::info;
-use prometheus::{Encoder, Registry, TextEncoder, default_registry, gather};
-use std::path::Path;
+use prometheus::{Encoder, Registry, TextEncoder, default_registry};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
 
-use crate::collector::LogCollectorBuilder;
-use crate::log_parser::LogParser;
+use crate::buckets::read_buckets_file;
+use crate::collector::{LogCollector, LogCollectorBuilder, LogDataOptions};
+use crate::log_parser::{LogParser, LogParserOptions, ParseError, tsv_format};
+use crate::openmetrics::{ExemplarStore, OPENMETRICS_FORMAT, SharedExemplars};
+use crate::processor::{DEFAULT_SIZE_THRESHOLDS, FilterFunc, ProcessorOptions, TransformStep, UpstreamTimes, is_fifo, is_gzip, is_status_pattern};
+use crate::pushgateway::PushTarget;
+use crate::value_set::{SetFile, reload_on_sighup};
 
-async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    let encoder = TextEncoder::new();
+/// How many recent lines are shown on "/debug/last".
+const DEBUG_LINES: usize = 50;
 
-    let metric_families = gather();
-    let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+/// A registry to serve, with the exemplars of its log.
+struct Endpoint {
+    registry: Registry,
+    exemplars: Option<SharedExemplars>,
+    /// The collector of the log, also registered in `registry`
+    collector: Option<LogCollector>,
+}
+
+struct Endpoints {
+    main: Endpoint,
+    /// Additional sources, served on "/metrics/<name>"
+    sources: HashMap<String, Endpoint>,
+    /// Enables "/reset", for requests with this bearer token
+    reset_token: Option<String>,
+    /// Enables "/debug/snapshot" and "/debug/last", and the same with
+    /// "/<name>" for the sources
+    debug_endpoints: bool,
+}
+
+impl Endpoints {
+    /// The endpoint for a path, either the main log or "<prefix><name>".
+    fn select(&self, path: &str, prefix: &str) -> Option<&Endpoint> {
+        match path.strip_prefix(prefix) {
+            Some(name) => self.sources.get(name.trim_end_matches('/')),
+            None => Some(&self.main),
+        }
+    }
+}
+
+/// The metrics of a log as JSON, for debugging.
+fn snapshot(endpoint: Option<&Endpoint>) -> Response<Body> {
+    match endpoint.and_then(|e| e.collector.as_ref()) {
+        Some(collector) => Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(collector.snapshot()))
+            .unwrap(),
+        None => Response::builder()
+            .status(404)
+            .body(Body::from("No such source\n"))
+            .unwrap(),
+    }
+}
+
+/// The results of the recent lines of a log as JSON, for debugging.
+fn last_lines(endpoint: Option<&Endpoint>) -> Response<Body> {
+    match endpoint.and_then(|e| e.collector.as_ref()).and_then(|c| c.last_lines()) {
+        Some(json) => Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        None => Response::builder()
+            .status(404)
+            .body(Body::from("No such source\n"))
+            .unwrap(),
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A page linking to the metrics, for people opening the server in a
+/// browser.
+fn landing_page(endpoints: &Endpoints) -> Response<Body> {
+    let mut links = vec!["<li><a href=\"/metrics\">/metrics</a></li>".to_owned()];
+    let mut names: Vec<&String> = endpoints.sources.keys().collect();
+    names.sort();
+    for name in names {
+        let path = html_escape(&format!("/metrics/{}", name));
+        links.push(format!("<li><a href=\"{}\">{}</a></li>", path, path));
+    }
+    let page = format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head><title>access-log-to-prometheus-metrics</title></head>\n\
+         <body>\n\
+         <h1>access-log-to-prometheus-metrics {}</h1>\n\
+         <ul>\n{}\n</ul>\n\
+         </body>\n\
+         </html>\n",
+        env!("CARGO_PKG_VERSION"),
+        links.join("\n"),
+    );
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(page))
+        .unwrap()
+}
+
+/// Reset the metrics of all the logs, if the request has the token.
+fn reset(req: &Request<Body>, endpoints: &Endpoints, token: &str) -> Response<Body> {
+    if req.method() != Method::POST {
+        return Response::builder()
+            .status(405)
+            .body(Body::from("Use POST to reset the metrics\n"))
+            .unwrap();
+    }
+    let authorized = req.headers().get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        == Some(token);
+    if !authorized {
+        return Response::builder()
+            .status(401)
+            .body(Body::from("Invalid token\n"))
+            .unwrap();
+    }
+    for endpoint in std::iter::once(&endpoints.main).chain(endpoints.sources.values()) {
+        if let Some(collector) = &endpoint.collector {
+            collector.reset();
+        }
+    }
+    info!("Metrics were reset");
+    Response::new(Body::from("Metrics reset\n"))
+}
+
+async fn serve_req(req: Request<Body>, endpoints: Arc<Endpoints>) -> Result<Response<Body>, hyper::Error> {
+    if let Some(token) = &endpoints.reset_token {
+        if req.uri().path() == "/reset" {
+            return Ok(reset(&req, &endpoints, token));
+        }
+    }
+
+    let path = req.uri().path();
+    if path == "/" {
+        return Ok(landing_page(&endpoints));
+    }
+    if endpoints.debug_endpoints && (path == "/debug/snapshot" || path.starts_with("/debug/snapshot/")) {
+        return Ok(snapshot(endpoints.select(path, "/debug/snapshot/")));
+    }
+    if endpoints.debug_endpoints && (path == "/debug/last" || path.starts_with("/debug/last/")) {
+        return Ok(last_lines(endpoints.select(path, "/debug/last/")));
+    }
+
+    let endpoint = match endpoints.select(path, "/metrics/") {
+        Some(endpoint) => endpoint,
+        None => {
+            return Ok(Response::builder()
+                .status(404)
+                .body(Body::from("No such source\n"))
+                .unwrap());
+        }
+    };
+    let metric_families = endpoint.registry.gather();
+
+    // Exemplars can only be sent in the OpenMetrics format
+    let openmetrics = req.headers().get_all(ACCEPT).iter().any(|v| {
+        v.to_str().map_or(false, |v| v.contains("application/openmetrics-text"))
+    });
+    let (buffer, format_type) = if openmetrics {
+        let exemplars = endpoint.exemplars.as_ref().map(|e| e.lock().unwrap());
+        let stores: Vec<&ExemplarStore> = exemplars.iter().map(|e| -> &ExemplarStore { e }).collect();
+        (openmetrics::encode(&metric_families, &stores).into_bytes(), OPENMETRICS_FORMAT.to_owned())
+    } else {
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        encoder.encode(&metric_families, &mut buffer).unwrap();
+        (buffer, encoder.format_type().to_owned())
+    };
+
+    let response = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, format_type)
+        .body(Body::from(buffer))
+        .unwrap();
+
+    Ok(response)
+}
+
+fn cli() -> App<'static, 'static> {
+    App::new("access-log-to-prometheus-metrics")
+        .bin_name("access-log-to-prometheus-metrics")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("FILE")
+                .help("The log file to watch")
+                .env("ALP_FILE")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LOG_FORMAT")
+                .help("The nginx log_format setting")
+                .env("ALP_LOG_FORMAT")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("source")
+                .long("source")
+                .help("Also watch the log file <path>, serving its metrics separately on /metrics/<name> [env: ALP_SOURCE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("tcp-listen")
+                .long("tcp-listen")
+                .help("Read the log lines sent to this address:port over TCP instead of the file")
+                .env("ALP_TCP_LISTEN")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("statsd")
+                .long("statsd")
+                .help("Also send each request to this statsd host:port over UDP, with the labels as tags")
+                .env("ALP_STATSD")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("systemd-socket")
+                .long("systemd-socket")
+                .help("Serve on the sockets passed by systemd socket activation instead of --bind [env: ALP_SYSTEMD_SOCKET]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("remote-write")
+                .long("remote-write")
+                .help("Periodically send the metrics to this Prometheus remote-write URL")
+                .env("ALP_REMOTE_WRITE")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("pushgateway")
+                .long("pushgateway")
+                .help("Periodically push the metrics to the Pushgateway at this URL")
+                .env("ALP_PUSHGATEWAY")
+                .required(false)
+                .takes_value(true)
+                .requires("job")
+        )
+        .arg(
+            Arg::with_name("job")
+                .long("job")
+                .help("The job name to push the metrics under")
+                .env("ALP_JOB")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("grouping")
+                .long("grouping")
+                .help("Add <label>=<value> to the grouping key when pushing [env: ALP_GROUPING]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("push-interval")
+                .long("push-interval")
+                .help("How often to push the metrics, in seconds")
+                .env("ALP_PUSH_INTERVAL")
+                .required(false)
+                .takes_value(true)
+                .default_value("15")
+        )
+        .arg(
+            Arg::with_name("loose-whitespace")
+                .long("loose-whitespace")
+                .help("Match whitespace in the format against any run of whitespace [env: ALP_LOOSE_WHITESPACE]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("no-auto-extractors")
+                .long("no-auto-extractors")
+                .help("Don't add labels for the recognized variables, only those configured explicitly [env: ALP_NO_AUTO_EXTRACTORS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("ignore-field")
+                .long("ignore-field")
+                .help("Don't read the value of this variable, only skip over it [env: ALP_IGNORE_FIELD]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("tsv")
+                .long("tsv")
+                .help("The format is a list of variable names, separated by tabs in the log [env: ALP_TSV]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("reject-trailing-data")
+                .long("reject-trailing-data")
+                .help("Count lines with data after the end of the format as errors [env: ALP_REJECT_TRAILING_DATA]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .help("The character encoding of the log, e.g. latin1 or windows-1252 (default: utf-8)")
+                .env("ALP_ENCODING")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-buffer")
+                .long("max-buffer")
+                .help("Read at most <bytes> at a time, and drop the oldest lines when more is waiting and processing falls further behind")
+                .env("ALP_MAX_BUFFER")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("dedup-window")
+                .long("dedup-window")
+                .help("Drop lines identical to one of the last <lines> distinct lines")
+                .env("ALP_DEDUP_WINDOW")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .help("Parse the lines on this many threads, for busy logs")
+                .env("ALP_WORKERS")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("skip-blank-lines")
+                .long("skip-blank-lines")
+                .help("Ignore empty lines instead of counting them as errors [env: ALP_SKIP_BLANK_LINES]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("comment-prefix")
+                .long("comment-prefix")
+                .help("Ignore the lines starting with <prefix> instead of counting them as errors, e.g. #")
+                .env("ALP_COMMENT_PREFIX")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .help("Update the metrics once per label combination for all the lines read at once [env: ALP_BATCH]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("line-terminator")
+                .long("line-terminator")
+                .help("What ends each record: newlines, optionally preceded by \\r (lf), or NUL bytes (nul)")
+                .env("ALP_LINE_TERMINATOR")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["lf", "nul"])
+        )
+        .arg(
+            Arg::with_name("url-decode")
+                .long("url-decode")
+                .help("Decode percent-encoded characters in $request before matching it [env: ALP_URL_DECODE]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("lowercase-labels")
+                .long("lowercase-labels")
+                .help("Lowercase the values of labels, so that e.g. Example.org and example.org are the same series [env: ALP_LOWERCASE_LABELS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("emit-json")
+                .long("emit-json")
+                .help("Also write every parsed line of the main log to this file as a JSON object, or to stdout with \"-\"")
+                .env("ALP_EMIT_JSON")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Check the format against the start of the log on startup, and exit if too many lines don't match [env: ALP_STRICT]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("strict-lines")
+                .long("strict-lines")
+                .help("How many lines to check with --strict")
+                .env("ALP_STRICT_LINES")
+                .required(false)
+                .takes_value(true)
+                .default_value("100")
+        )
+        .arg(
+            Arg::with_name("strict-threshold")
+                .long("strict-threshold")
+                .help("The fraction of lines that have to match with --strict")
+                .env("ALP_STRICT_THRESHOLD")
+                .required(false)
+                .takes_value(true)
+                .default_value("0.9")
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .short("b")
+                .help("The address:port to listen on, can be repeated to listen on multiple addresses [env: ALP_BIND]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+                .default_value("127.0.0.1:9898")
+        )
+        .arg(
+            Arg::with_name("reset-token")
+                .long("reset-token")
+                .help("Allow resetting the metrics with POST /reset, using this bearer token (for testing)")
+                .env("ALP_RESET_TOKEN")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("enable-debug-endpoints")
+                .long("enable-debug-endpoints")
+                .help("Serve the metrics and the results of the last lines as JSON on /debug/snapshot and /debug/last, for debugging [env: ALP_ENABLE_DEBUG_ENDPOINTS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("duration-sum")
+                .long("duration-sum")
+                .help("Also record the total duration of requests as a counter [env: ALP_DURATION_SUM]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("profile-extractors")
+                .long("profile-extractors")
+                .help("Record the time spent extracting each label in a histogram, to find slow ones [env: ALP_PROFILE_EXTRACTORS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("duration-status")
+                .long("duration-status")
+                .help("Only record the duration of requests with these statuses, such as 2xx or 304, separated by commas")
+                .env("ALP_DURATION_STATUS")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("histogram-counters")
+                .long("histogram-counters")
+                .help("Also record the count and sum of request_duration as separate counters, for tools that don't handle histograms [env: ALP_HISTOGRAM_COUNTERS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("byte-counters")
+                .long("byte-counters")
+                .help("Also record the total bytes sent and received as counters [env: ALP_BYTE_COUNTERS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("last-values")
+                .long("last-values")
+                .help("Also record the duration and body size of the latest request as gauges [env: ALP_LAST_VALUES]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("error-ratio")
+                .long("error-ratio")
+                .help("Also report the fraction of lines that could not be parsed as a gauge [env: ALP_ERROR_RATIO]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("unique-clients")
+                .long("unique-clients")
+                .help("Estimate the number of distinct $remote_addr, counting again every <seconds>")
+                .env("ALP_UNIQUE_CLIENTS")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("upstream-times")
+                .long("upstream-times")
+                .help("How to combine the times of multiple upstreams in $upstream_response_time, $upstream_header_time, and $upstream_connect_time")
+                .env("ALP_UPSTREAM_TIMES")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["sum", "last"])
+        )
+        .arg(
+            Arg::with_name("buckets-file")
+                .long("buckets-file")
+                .help("A file setting the buckets of histograms, e.g. request_duration = [0.1, 1, 10]")
+                .env("ALP_BUCKETS_FILE")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("offset-file")
+                .long("offset-file")
+                .help("Save the position in the log to this file, to resume from it after a restart (<path>.<name> for each --source)")
+                .env("ALP_OFFSET_FILE")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-series")
+                .long("max-series")
+                .help("Count lines in an \"overflow\" series once there are that many label combinations")
+                .env("ALP_MAX_SERIES")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("poll-interval")
+                .long("poll-interval")
+                .help("Also check the file for new lines every <seconds>, even without notifications")
+                .env("ALP_POLL_INTERVAL")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("watchdog")
+                .long("watchdog")
+                .help("Re-establish the watch if there are no events or new lines for <seconds>")
+                .env("ALP_WATCHDOG")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .help("Set log_idle to 1 if no lines were processed for <seconds>")
+                .env("ALP_IDLE_TIMEOUT")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("status-alias")
+                .long("status-alias")
+                .help("Report the status codes <code>,<code>,... as <alias> [env: ALP_STATUS_ALIAS]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("map-label")
+                .long("map-label")
+                .help("Set <label> from <field>, mapping values with <value>=<label value>,... [env: ALP_MAP_LABEL]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("split-label")
+                .long("split-label")
+                .help("Set <label> to a part of <field> split on <delimiter>, counting from 0, as <label>:<field>:<delimiter>:<index> [env: ALP_SPLIT_LABEL]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("hash-label")
+                .long("hash-label")
+                .help("Set <label> to a short hash of <field>, as <label>:<field> [env: ALP_HASH_LABEL]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("compose-label")
+                .long("compose-label")
+                .help("Add <label> made of the values of two labels joined by <separator>, as <label>:<label1>:<label2>:<separator> [env: ALP_COMPOSE_LABEL]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("label-order")
+                .long("label-order")
+                .help("Put these labels first, in this order, separated by commas")
+                .env("ALP_LABEL_ORDER")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("label-default")
+                .long("label-default")
+                .help("Set <label> to <value> when its field is empty or \"-\" [env: ALP_LABEL_DEFAULT]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("vhost-allow")
+                .long("vhost-allow")
+                .help("Only count requests to these virtual hosts, separated by commas")
+                .env("ALP_VHOST_ALLOW")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("bot-detect")
+                .long("bot-detect")
+                .help("Add an is_bot label from $http_user_agent [env: ALP_BOT_DETECT]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("bot-pattern")
+                .long("bot-pattern")
+                .help("Also count user agents containing <pattern> as bots, ignoring case [env: ALP_BOT_PATTERN]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("bot-empty")
+                .long("bot-empty")
+                .help("Whether requests without a user agent count as bots")
+                .env("ALP_BOT_EMPTY")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["yes", "no"])
+                .default_value("yes")
+        )
+        .arg(
+            Arg::with_name("size-class")
+                .long("size-class")
+                .help("Add a size_class label from $body_bytes_sent (tiny, small, medium, large, huge) [env: ALP_SIZE_CLASS]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("file-label")
+                .long("file-label")
+                .help("Add a logfile label with the name of the file to all the metrics [env: ALP_FILE_LABEL]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("cache-hit-ratio")
+                .long("cache-hit-ratio")
+                .help("Report the fraction of cacheable requests served from the cache, from this variable, e.g. upstream_cache_status")
+                .env("ALP_CACHE_HIT_RATIO")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("count-header")
+                .long("count-header")
+                .help("Count the responses by value of this header, from $sent_http_<header> (can be repeated) [env: ALP_COUNT_HEADER]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("http-version-metric")
+                .long("http-version-metric")
+                .help("Count the requests by HTTP version, from $server_protocol or $request [env: ALP_HTTP_VERSION_METRIC]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("keepalive-label")
+                .long("keepalive-label")
+                .help("Add a keepalive label from $connection_requests, yes if the connection was reused [env: ALP_KEEPALIVE_LABEL]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("served-from")
+                .long("served-from")
+                .help("Add a served_from label from $upstream_cache_status and $status: cache, origin, or error [env: ALP_SERVED_FROM]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("size-thresholds")
+                .long("size-thresholds")
+                .help("The largest sizes in bytes of the tiny, small, medium, and large classes, separated by commas (default: 1000,10000,100000,1000000)")
+                .env("ALP_SIZE_THRESHOLDS")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("auto-path-templating")
+                .long("auto-path-templating")
+                .help("Add a path label, replacing numeric, UUID, and long hex segments with placeholders [env: ALP_AUTO_PATH_TEMPLATING]")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("path-trailing-slash")
+                .long("path-trailing-slash")
+                .help("Whether to strip the trailing slash of the path label, so /a/ is counted as /a")
+                .env("ALP_PATH_TRAILING_SLASH")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["strip", "keep"])
+                .default_value("keep")
+        )
+        .arg(
+            Arg::with_name("method")
+                .long("method")
+                .help("Only count requests with these methods, separated by commas")
+                .env("ALP_METHOD")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("filter-https")
+                .long("filter-https")
+                .help("Only count requests made over TLS (yes) or without it (no), from $scheme, $https, or $ssl_protocol")
+                .env("ALP_FILTER_HTTPS")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["yes", "no"])
+        )
+        .arg(
+            Arg::with_name("filter-range")
+                .long("filter-range")
+                .help("Only lines where <field> is a number between <min> and <max> (inclusive), as <field>:<min>:<max> [env: ALP_FILTER_RANGE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .help("Drop lines where <field> is exactly one of the values, as <field>:<value>,<value>... [env: ALP_EXCLUDE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("has-value")
+                .long("has-value")
+                .help("Only count lines where <field> has a value, not empty or - [env: ALP_HAS_VALUE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("no-value")
+                .long("no-value")
+                .help("Only count lines where <field> is empty or - [env: ALP_NO_VALUE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("match-file")
+                .long("match-file")
+                .help("Only count lines where <field> is exactly one of the lines of a file, as <field>:<path>. Reloaded on SIGHUP [env: ALP_MATCH_FILE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("exclude-file")
+                .long("exclude-file")
+                .help("Drop lines where <field> is exactly one of the lines of a file, as <field>:<path>. Reloaded on SIGHUP [env: ALP_EXCLUDE_FILE]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("warmup-seconds")
+                .long("warmup-seconds")
+                .help("On startup, first process the lines of the last <seconds> of the file, from their timestamps")
+                .env("ALP_WARMUP_SECONDS")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .long("replay-speed")
+                .help("Process the lines at the pace of their timestamps, <factor> times faster, as if they were written now")
+                .env("ALP_REPLAY_SPEED")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-age")
+                .long("max-age")
+                .help("Drop lines whose timestamp is more than <seconds> in the past")
+                .env("ALP_MAX_AGE")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-future")
+                .long("max-future")
+                .help("Drop lines whose timestamp is more than <seconds> in the future")
+                .env("ALP_MAX_FUTURE")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("match")
+                .long("match")
+                .short("m")
+                .help("Only lines where <field> matches <regex> [env: ALP_MATCH]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("match-ci")
+                .long("match-ci")
+                .help("Only lines where <field> matches <regex>, ignoring case [env: ALP_MATCH_CI]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("geoip-db")
+                .long("geoip-db")
+                .help("Look up $remote_addr in this MaxMind database to set the country label")
+                .env("ALP_GEOIP_DB")
+                .required(false)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("transform")
+                .long("transform")
+                .help("Add a step to the transforms setting <label>, from <field> or the variable with the same name, as <label>[=<field>]:<step> (can be repeated, in order) [env: ALP_TRANSFORM]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .short("l")
+                .help("Set <label> to <value> from <field> with <regex> [env: ALP_LABEL]")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+        )
+}
+
+/// Whether a flag is set, either on the command line or in the environment.
+fn flag(matches: &ArgMatches, name: &str) -> bool {
+    if matches.is_present(name) {
+        return true;
+    }
+    let var = format!("ALP_{}", name.to_uppercase().replace('-', "_"));
+    match std::env::var(var) {
+        Ok(v) => v == "1" || v.eq_ignore_ascii_case("true") || v.eq_ignore_ascii_case("yes"),
+        Err(_) => false,
+    }
+}
+
+/// The values of a repeatable option, from the command line if given there,
+/// else from the environment.
+///
+/// The environment variable is read here rather than by clap, which would add
+/// its value after the ones from the command line.
+fn values_of(matches: &ArgMatches, name: &str) -> Option<Vec<String>> {
+    if matches.occurrences_of(name) == 0 {
+        let var = format!("ALP_{}", name.to_uppercase().replace('-', "_"));
+        if let Ok(value) = std::env::var(var) {
+            return Some(vec![value]);
+        }
+    }
+    matches.values_of(name).map(|v| v.map(str::to_owned).collect())
+}
+
+/// Parse the first lines of the log, returning how many were read and the
+/// ones that didn't match the format.
+///
+/// The lines are read like the processor does. Named pipes and compressed
+/// files are not checked, since reading them here would take the lines from
+/// the processor, or not work.
+fn sample_lines(parser: &LogParser, options: &ProcessorOptions, path: &Path, max_lines: usize) -> std::io::Result<(usize, Vec<(String, ParseError)>)> {
+    use std::io::BufRead;
+
+    if is_fifo(path) || is_gzip(path) {
+        return Ok((0, Vec::new()));
+    }
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+        Err(e) => return Err(e),
+    };
+    let terminator = options.line_terminator.unwrap_or(b'\n');
+    let mut count = 0;
+    let mut failures = Vec::new();
+    for line in std::io::BufReader::new(file).split(terminator) {
+        if count >= max_lines {
+            break;
+        }
+        let line = line?;
+        let line = options.strip_cr(&line);
+        let line = match options.decode_line(line) {
+            Ok(line) => line,
+            Err(e) => {
+                count += 1;
+                failures.push((String::from_utf8_lossy(line).into_owned(), ParseError::Invalid(e.to_string())));
+                continue;
+            }
+        };
+        if options.is_skipped(&line) {
+            continue;
+        }
+        count += 1;
+        if let Err(e) = parser.parse(&line) {
+            failures.push((line.into_owned(), e));
+        }
+    }
+    Ok((count, failures))
+}
+
+/// Read an option that is a positive number of seconds, exiting if invalid.
+fn seconds_arg(matches: &ArgMatches, name: &str) -> Option<f64> {
+    match matches.value_of(name) {
+        // Also has to fit in a Duration
+        Some(s) => match s.parse::<f64>() {
+            Ok(n) if n > 0.0 && n.is_finite() && n < u64::MAX as f64 => Some(n),
+            _ => {
+                eprintln!("Invalid --{}, should be a number of seconds", name);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    }
+}
+
+/// Load the files of `--match-file` and `--exclude-file`, as the field, the
+/// file, and whether to exclude its values.
+fn set_filters(matches: &ArgMatches) -> Vec<(String, SetFile, bool)> {
+    let mut filters = Vec::new();
+    for &(arg, exclude) in &[("match-file", false), ("exclude-file", true)] {
+        if let Some(v) = values_of(matches, arg) {
+            for s in v {
+                let parts: Vec<&str> = s.splitn(2, ':').collect();
+                if parts.len() != 2 {
+                    eprintln!("--{} needs a field and a path separated by ':'", arg);
+                    std::process::exit(1);
+                }
+                match SetFile::open(Path::new(parts[1])) {
+                    Ok(file) => filters.push((parts[0].to_owned(), file, exclude)),
+                    Err(e) => {
+                        eprintln!("Can't read {:?}: {}", parts[1], e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+    filters
+}
+
+/// The settings for how lines are processed, from the command-line options.
+fn processor_options(matches: &ArgMatches, source: Option<&str>) -> ProcessorOptions {
+    let max_series = match matches.value_of("max-series") {
+        Some(s) => match s.parse() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                eprintln!("Invalid --max-series, should be a number");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let max_buffer = match matches.value_of("max-buffer") {
+        Some(s) => match s.parse() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                eprintln!("Invalid --max-buffer, should be a number of bytes");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let dedup_window = match matches.value_of("dedup-window") {
+        Some(s) => match s.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                eprintln!("Invalid --dedup-window, should be a number of lines");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let workers = match matches.value_of("workers") {
+        Some(s) => match s.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                eprintln!("Invalid --workers, should be a number of threads");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let poll_interval = seconds_arg(matches, "poll-interval").map(std::time::Duration::from_secs_f64);
+    let encoding = match matches.value_of("encoding") {
+        Some(label) => match encoding_rs::Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding == encoding_rs::UTF_8 => None,
+            Some(encoding) => Some(encoding),
+            None => {
+                eprintln!("Unknown --encoding {:?}", label);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let watchdog = seconds_arg(matches, "watchdog").map(std::time::Duration::from_secs_f64);
+    let idle_timeout = seconds_arg(matches, "idle-timeout").map(std::time::Duration::from_secs_f64);
+    ProcessorOptions {
+        max_series,
+        poll_interval,
+        watchdog,
+        // Each source saves its position to its own file
+        offset_file: matches.value_of_os("offset-file").map(|p| match source {
+            Some(name) => {
+                let mut p = p.to_owned();
+                p.push(".");
+                p.push(name);
+                PathBuf::from(p)
+            }
+            None => PathBuf::from(p),
+        }),
+        encoding,
+        url_decode: flag(matches, "url-decode"),
+        lowercase_labels: flag(matches, "lowercase-labels"),
+        line_terminator: match matches.value_of("line-terminator") {
+            Some("nul") => Some(0),
+            _ => None,
+        },
+        max_buffer,
+        batch_lines: flag(matches, "batch"),
+        dedup_window,
+        skip_blank_lines: flag(matches, "skip-blank-lines"),
+        comment_prefix: matches.value_of("comment-prefix").filter(|p| !p.is_empty()).map(|p| p.to_owned()),
+        workers,
+        idle_timeout,
+    }
+}
+
+/// Set up the collector for a log file from the command-line options.
+fn build_collector(matches: &ArgMatches, source: Option<&str>, file: &Path, set_filters: &[(String, SetFile, bool)], tcp_listener: Option<TcpListener>) -> Result<LogCollector, Box<dyn std::error::Error>> {
+    let parser_options = LogParserOptions {
+        loose_whitespace: flag(matches, "loose-whitespace"),
+        reject_trailing_data: flag(matches, "reject-trailing-data"),
+        ignore_fields: match values_of(matches, "ignore-field") {
+            Some(v) => v.iter().map(|f| f.trim_start_matches('$').to_owned()).collect(),
+            None => Vec::new(),
+        },
+    };
+    let format = matches.value_of("LOG_FORMAT").unwrap();
+    let parser = if flag(matches, "tsv") {
+        LogParser::from_format_with_options(&tsv_format(format), &parser_options)?
+    } else {
+        LogParser::from_format_with_options(format, &parser_options)?
+    };
+    let processor_options = processor_options(matches, source);
+    if flag(matches, "strict") {
+        let (max_lines, threshold) = match (matches.value_of("strict-lines").unwrap().parse(), matches.value_of("strict-threshold").unwrap().parse::<f64>()) {
+            (Ok(n), Ok(t)) if (0.0..=1.0).contains(&t) => (n, t),
+            _ => {
+                eprintln!("Invalid --strict-lines or --strict-threshold, should be a number of lines and a fraction");
+                std::process::exit(1);
+            }
+        };
+        let (count, failures) = if tcp_listener.is_some() { (0, Vec::new()) } else { sample_lines(&parser, &processor_options, file, max_lines)? };
+        if count > 0 && ((count - failures.len()) as f64) < threshold * count as f64 {
+            for (line, e) in &failures {
+                eprintln!("{}\n    {:?}", e, line);
+            }
+            eprintln!("{} of the first {} lines of {:?} don't match the format", failures.len(), count, file);
+            std::process::exit(1);
+        }
+    }
+    let mut collector = if flag(matches, "no-auto-extractors") {
+        LogCollectorBuilder::new_bare(parser, file.to_owned())
+    } else {
+        LogCollectorBuilder::new(parser, file.to_owned())
+    };
+    if let Some(listener) = tcp_listener {
+        collector.set_tcp_listener(listener);
+    }
+    let buckets = match matches.value_of_os("buckets-file") {
+        Some(path) => read_buckets_file(Path::new(path))?,
+        None => Default::default(),
+    };
+    collector.set_data_options(LogDataOptions {
+        buckets: buckets.histograms,
+        vhost_buckets: buckets.vhosts,
+        duration_sum: flag(matches, "duration-sum"),
+        profile_extractors: flag(matches, "profile-extractors"),
+        byte_counters: flag(matches, "byte-counters"),
+        histogram_counters: flag(matches, "histogram-counters"),
+        last_values: flag(matches, "last-values"),
+        error_ratio: flag(matches, "error-ratio"),
+        unique_clients_window: seconds_arg(matches, "unique-clients"),
+        const_labels: if flag(matches, "file-label") {
+            let name = file.file_name().unwrap_or(file.as_os_str());
+            vec![("logfile".to_owned(), name.to_string_lossy().into_owned())].into_iter().collect()
+        } else {
+            HashMap::new()
+        },
+        debug_lines: if flag(matches, "enable-debug-endpoints") { Some(DEBUG_LINES) } else { None },
+    });
+    if matches.is_present("unique-clients") {
+        if let Err(()) = collector.add_unique_clients() {
+            eprintln!("No field \"remote_addr\", can't use --unique-clients");
+            std::process::exit(1);
+        }
+    }
+    collector.set_processor_options(processor_options);
+
+    // Only the main log, so the lines of different logs don't get mixed
+    if let (Some(path), None) = (matches.value_of_os("emit-json"), source) {
+        if path == "-" {
+            collector.set_json_output(Box::new(std::io::stdout()));
+        } else {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            collector.set_json_output(Box::new(std::io::LineWriter::new(file)));
+        }
+    }
+
+    if let Some(policy) = matches.value_of("upstream-times") {
+        let policy = match policy {
+            "last" => UpstreamTimes::Last,
+            _ => UpstreamTimes::Sum,
+        };
+        if let Err(()) = collector.set_upstream_times(policy) {
+            eprintln!("No field \"upstream_response_time\", \"upstream_header_time\", or \"upstream_connect_time\", can't use --upstream-times");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = values_of(matches, "status-alias") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(2, '=').collect();
+            if parts.len() != 2 {
+                eprintln!("--status-alias needs status codes and an alias separated by '='");
+                std::process::exit(1);
+            }
+            for status in parts[0].split(',') {
+                if let Err(()) = collector.add_status_alias(status.to_owned(), parts[1].to_owned()) {
+                    eprintln!("No field \"status\", can't use --status-alias");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(v) = values_of(matches, "map-label") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(3, ':').collect();
+            if parts.len() != 3 {
+                eprintln!("--map-label needs 3 arguments separated by ':'");
+                std::process::exit(1);
+            }
+            let mut values = HashMap::new();
+            for mapping in parts[2].split(',') {
+                let mapping: Vec<&str> = mapping.splitn(2, '=').collect();
+                if mapping.len() != 2 {
+                    eprintln!("--map-label needs mappings of the form <value>=<label value>");
+                    std::process::exit(1);
+                }
+                values.insert(mapping[0].to_owned(), mapping[1].to_owned());
+            }
+            if let Err(()) = collector.add_extractor(
+                Some(parts[0].to_owned()),
+                parts[1].to_owned(),
+                crate::processor::ExtractorFunc::Map { values },
+            ) {
+                eprintln!("No field {:?}, can't add extractor", parts[1]);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(v) = matches.value_of("duration-status") {
+        let patterns: Vec<&str> = v.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()).collect();
+        if let Some(p) = patterns.iter().find(|p| !is_status_pattern(&p.to_ascii_lowercase())) {
+            eprintln!("Invalid status {:?} for --duration-status, use a code such as 304 or a class such as 2xx", p);
+            std::process::exit(1);
+        }
+        if let Err(()) = collector.set_duration_statuses(&patterns) {
+            eprintln!("No field \"status\", can't use --duration-status");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = values_of(matches, "split-label") {
+        for s in v {
+            // The delimiter can be ':', so the index is after the last one
+            let parts: Vec<&str> = s.splitn(3, ':').collect();
+            let (delimiter, index) = match parts.get(2).and_then(|rest| rest.rfind(':').map(|i| (&rest[..i], &rest[i + 1..]))) {
+                Some((d, i)) if !d.is_empty() => (d, i),
+                _ => {
+                    eprintln!("--split-label needs 4 arguments separated by ':'");
+                    std::process::exit(1);
+                }
+            };
+            let index = match index.parse() {
+                Ok(i) => i,
+                Err(_) => {
+                    eprintln!("Invalid index {:?} for --split-label", index);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(()) = collector.add_extractor(
+                Some(parts[0].to_owned()),
+                parts[1].to_owned(),
+                crate::processor::ExtractorFunc::Split { delimiter: delimiter.to_owned(), index },
+            ) {
+                eprintln!("No field {:?}, can't add extractor", parts[1]);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(v) = values_of(matches, "hash-label") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(2, ':').collect();
+            if parts.len() != 2 {
+                eprintln!("--hash-label needs 2 arguments separated by ':'");
+                std::process::exit(1);
+            }
+            if let Err(()) = collector.add_extractor(
+                Some(parts[0].to_owned()),
+                parts[1].to_owned(),
+                crate::processor::ExtractorFunc::Hash,
+            ) {
+                eprintln!("No field {:?}, can't add extractor", parts[1]);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(v) = matches.value_of("vhost-allow") {
+        let vhosts: Vec<&str> = v.split(',').map(|h| h.trim()).filter(|h| !h.is_empty()).collect();
+        if let Err(()) = collector.add_vhost_allow(&vhosts) {
+            eprintln!("No field \"host\" or \"server_name\", can't use --vhost-allow");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = matches.value_of("method") {
+        let methods: Vec<&str> = v.split(',').map(|m| m.trim()).filter(|m| !m.is_empty()).collect();
+        if let Err(()) = collector.add_method_allow(&methods) {
+            eprintln!("No field \"request_method\" or \"request\", can't use --method");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = matches.value_of("filter-https") {
+        if let Err(()) = collector.add_https_filter(v == "yes") {
+            eprintln!("No field \"scheme\", \"https\", or \"ssl_protocol\", can't use --filter-https");
+            std::process::exit(1);
+        }
+    }
+
+    let bot_patterns = values_of(matches, "bot-pattern");
+    if flag(matches, "bot-detect") || bot_patterns.is_some() {
+        let patterns: Vec<&str> = bot_patterns.iter().flatten().map(|p| p.as_str()).collect();
+        if let Err(()) = collector.add_bot_detect(&patterns, matches.value_of("bot-empty") == Some("yes")) {
+            eprintln!("No field \"http_user_agent\", can't use --bot-detect");
+            std::process::exit(1);
+        }
+    }
+
+    if flag(matches, "size-class") || matches.is_present("size-thresholds") {
+        let thresholds = match matches.value_of("size-thresholds") {
+            Some(v) => {
+                let values: Result<Vec<u64>, _> = v.split(',').map(|t| t.trim().parse()).collect();
+                match values {
+                    Ok(values) if values.len() == 4 && values.windows(2).all(|w| w[0] < w[1]) => {
+                        [values[0], values[1], values[2], values[3]]
+                    }
+                    _ => {
+                        eprintln!("--size-thresholds needs 4 increasing numbers of bytes separated by commas");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => DEFAULT_SIZE_THRESHOLDS,
+        };
+        if let Err(()) = collector.add_size_class(thresholds) {
+            eprintln!("No field \"body_bytes_sent\", can't use --size-class");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(field) = matches.value_of("cache-hit-ratio") {
+        if let Err(()) = collector.add_cache_hit_ratio(field) {
+            eprintln!("No field \"{}\", can't use --cache-hit-ratio", field.trim_start_matches('$'));
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = values_of(matches, "transform") {
+        for s in v {
+            let (target, step) = match s.rfind(':') {
+                Some(i) => (&s[..i], &s[i + 1..]),
+                None => {
+                    eprintln!("--transform needs a label and a step separated by ':'");
+                    std::process::exit(1);
+                }
+            };
+            let (label, field) = match target.find('=') {
+                Some(i) => (&target[..i], Some(&target[i + 1..])),
+                None => (target, None),
+            };
+            let step = match TransformStep::from_name(step) {
+                Some(step) => step,
+                None => {
+                    eprintln!("Unknown --transform step {:?}, should be one of: {}", step, TransformStep::NAMES.join(", "));
+                    std::process::exit(1);
+                }
+            };
+            if let Err(()) = collector.add_transform(label, field, step) {
+                eprintln!("No field {:?}, or not the same as for the previous steps, can't use --transform {}", field.unwrap_or(label), s);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(v) = values_of(matches, "count-header") {
+        for header in v {
+            if let Err(()) = collector.add_response_header_count(&header) {
+                eprintln!("No $sent_http_ field for header {:?}, can't use --count-header", header);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if flag(matches, "http-version-metric") {
+        if let Err(()) = collector.add_http_version_metric() {
+            eprintln!("No field \"server_protocol\" or \"request\", can't use --http-version-metric");
+            std::process::exit(1);
+        }
+    }
+
+    if flag(matches, "keepalive-label") {
+        if let Err(()) = collector.add_keepalive_label() {
+            eprintln!("No field \"connection_requests\", can't use --keepalive-label");
+            std::process::exit(1);
+        }
+    }
+
+    if flag(matches, "served-from") {
+        if let Err(()) = collector.add_served_from() {
+            eprintln!("No fields \"upstream_cache_status\" and \"status\", can't use --served-from");
+            std::process::exit(1);
+        }
+    }
+
+    if flag(matches, "auto-path-templating") {
+        if let Err(()) = collector.add_path_templating() {
+            eprintln!("No field \"uri\", \"request_uri\", or \"request\", can't use --auto-path-templating");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = values_of(matches, "filter-range") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(3, ':').collect();
+            if parts.len() != 3 {
+                eprintln!("--filter-range needs 3 arguments separated by ':'");
+                std::process::exit(1);
+            }
+            let (min, max) = match (parts[1].parse::<f64>(), parts[2].parse::<f64>()) {
+                (Ok(min), Ok(max)) if min <= max => (min, max),
+                _ => {
+                    eprintln!("Invalid range for --filter-range, expected <min>:<max>");
+                    std::process::exit(1);
+                }
+            };
+            if let Err(()) = collector.add_filter(parts[0].to_owned(), FilterFunc::Range { min, max }) {
+                eprintln!("No field {:?}, can't add filter", parts[0]);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(v) = values_of(matches, "exclude") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(2, ':').collect();
+            if parts.len() != 2 {
+                eprintln!("--exclude needs a field and values separated by ':'");
+                std::process::exit(1);
+            }
+            let values = parts[1].split(',').map(|v| v.to_owned()).collect();
+            if let Err(()) = collector.add_filter(parts[0].to_owned(), FilterFunc::ExcludeExact { values }) {
+                eprintln!("No field {:?}, can't add filter", parts[0]);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let response = Response::builder()
-        .status(200)
-        .header(CONTENT_TYPE, encoder.format_type())
-        .body(Body::from(buffer))
-        .unwrap();
+    for &(arg, present) in &[("has-value", true), ("no-value", false)] {
+        if let Some(v) = values_of(matches, arg) {
+            for field in v {
+                if let Err(()) = collector.add_filter(field.to_owned(), FilterFunc::HasValue { present }) {
+                    eprintln!("No field {:?}, can't use --{}", field, arg);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
 
-    Ok(response)
-}
+    for (field, file, exclude) in set_filters {
+        if let Err(()) = collector.add_filter(field.clone(), FilterFunc::SetMatch { set: file.set.clone(), exclude: *exclude }) {
+            eprintln!("No field {:?}, can't add filter", field);
+            std::process::exit(1);
+        }
+    }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // End the process if any thread panics
-    // https://stackoverflow.com/a/36031130
-    let orig_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |panic_info| {
-        // invoke the default handler and exit the process
-        orig_hook(panic_info);
-        std::process::exit(1);
-    }));
+    if let Some(seconds) = seconds_arg(matches, "warmup-seconds") {
+        if let Err(()) = collector.set_warmup(seconds) {
+            eprintln!("No time field ($time_iso8601, $time_local, or $msec), can't use --warmup-seconds");
+            std::process::exit(1);
+        }
+    }
 
-    let cli = App::new("access-log-to-prometheus-metrics")
-        .bin_name("access-log-to-prometheus-metrics")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author(env!("CARGO_PKG_AUTHORS"))
-        .about(env!("CARGO_PKG_DESCRIPTION"))
-        .arg(
-            Arg::with_name("FILE")
-                .help("The log file to watch")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("LOG_FORMAT")
-                .help("The nginx log_format setting")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("bind")
-                .long("bind")
-                .short("b")
-                .help("The address:port to listen on")
-                .required(false)
-                .takes_value(true)
-                .default_value("127.0.0.1:9898")
-        )
-        .arg(
-            Arg::with_name("match")
-                .long("match")
-                .short("m")
-                .help("Only lines where <field> matches <regex>")
-                .required(false)
-                .multiple(true)
-                .takes_value(true)
-                .number_of_values(1)
-        )
-        .arg(
-            Arg::with_name("label")
-                .long("label")
-                .short("l")
-                .help("Set <label> to <value> from <field> with <regex>")
-                .required(false)
-                .multiple(true)
-                .takes_value(true)
-                .number_of_values(1)
-        );
-    let matches = cli.get_matches();
+    if let Some(s) = matches.value_of("replay-speed") {
+        let speed = match s.parse() {
+            Ok(n) if n > 0.0 => n,
+            _ => {
+                eprintln!("Invalid --replay-speed, should be a positive number");
+                std::process::exit(1);
+            }
+        };
+        if let Err(()) = collector.set_replay_speed(speed) {
+            eprintln!("No time field ($time_iso8601, $time_local, or $msec), can't use --replay-speed");
+            std::process::exit(1);
+        }
+    }
+
+    let max_age = seconds_arg(matches, "max-age");
+    let max_future = seconds_arg(matches, "max-future");
+    if max_age.is_some() || max_future.is_some() {
+        if let Err(()) = collector.add_time_window(max_age, max_future) {
+            eprintln!("No time field ($time_iso8601, $time_local, or $msec), can't use --max-age or --max-future");
+            std::process::exit(1);
+        }
+    }
 
+    #[cfg(feature = "statsd")]
     {
-        let mut logger_builder = env_logger::Builder::from_default_env();
-        logger_builder.init();
+        if let Some(addr) = matches.value_of("statsd") {
+            match crate::statsd::StatsdClient::connect(addr) {
+                Ok(client) => collector.set_statsd(client),
+                Err(e) => {
+                    eprintln!("Invalid --statsd address {:?}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    #[cfg(not(feature = "statsd"))]
+    {
+        if matches.is_present("statsd") {
+            eprintln!("Support for --statsd was not compiled in");
+            std::process::exit(1);
+        }
     }
 
-    let parser = LogParser::from_format(matches.value_of("LOG_FORMAT").unwrap())?;
-    let collector = LogCollectorBuilder::new(parser, Path::new(matches.value_of_os("FILE").unwrap()).to_owned());
+    #[cfg(feature = "geoip")]
+    {
+        if let Some(path) = matches.value_of_os("geoip-db") {
+            let geoip = Box::new(crate::geoip::GeoIp::open(Path::new(path))?);
+            if let Err(()) = collector.add_extractor(
+                Some("country".to_owned()),
+                "remote_addr".to_owned(),
+                crate::processor::ExtractorFunc::ClientCountry { geoip },
+            ) {
+                eprintln!("No field \"remote_addr\", can't use --geoip-db");
+                std::process::exit(1);
+            }
+        }
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        if matches.is_present("geoip-db") {
+            eprintln!("Support for --geoip-db was not compiled in");
+            std::process::exit(1);
+        }
+    }
 
     #[cfg(feature = "re")]
-    let collector = {
-        use crate::processor::{FilterFunc, ExtractorFunc};
+    let mut collector = {
+        use crate::processor::{ExtractorFunc, user_regex};
 
         let mut collector = collector;
 
-        if let Some(v) = matches.values_of("match") {
-            for s in v {
-                let parts: Vec<&str> = s.splitn(2, ':').collect();
-                if parts.len() != 2 {
-                    eprintln!("--match needs 2 arguments separated by ':'");
-                    std::process::exit(1);
-                }
-                if let Err(()) = collector.add_filter(
-                    parts[0].to_owned(),
-                    FilterFunc::Regex { regex: regex::Regex::new(parts[1])? },
-                ) {
-                    eprintln!("No field {:?}, can't add filter", parts[0]);
-                    std::process::exit(1);
+        for &(arg, case_insensitive) in &[("match", false), ("match-ci", true)] {
+            if let Some(v) = values_of(matches, arg) {
+                for s in v {
+                    let parts: Vec<&str> = s.splitn(2, ':').collect();
+                    if parts.len() != 2 {
+                        eprintln!("--{} needs 2 arguments separated by ':'", arg);
+                        std::process::exit(1);
+                    }
+                    let filter = match FilterFunc::regex(parts[1], case_insensitive) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            eprintln!("Invalid pattern for --{}: {}", arg, e);
+                            std::process::exit(1);
+                        }
+                    };
+                    if let Err(()) = collector.add_filter(parts[0].to_owned(), filter) {
+                        eprintln!("No field {:?}, can't add filter", parts[0]);
+                        std::process::exit(1);
+                    }
                 }
             }
         }
 
-        if let Some(v) = matches.values_of("label") {
+        if let Some(v) = values_of(matches, "label") {
             for s in v {
                 let parts: Vec<&str> = s.splitn(4, ':').collect();
                 if parts.len() != 4 {
                     eprintln!("--label needs 4 arguments separated by ':'");
                     std::process::exit(1);
                 }
+                let regex = match user_regex(&format!("^.*{}.*$", parts[3]), false) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("Invalid pattern for --label: {}", e);
+                        std::process::exit(1);
+                    }
+                };
                 if let Err(()) = collector.add_extractor(
                     Some(parts[0].to_owned()),
                     parts[2].to_owned(),
                     ExtractorFunc::Regex {
                         target: parts[1].to_owned(),
-                        regex: regex::Regex::new(&format!("^.*{}.*$", parts[3]))?,
+                        regex,
                     },
                 ) {
                     eprintln!("No field {:?}, can't add extractor", parts[2]);
@@ -144,36 +1603,607 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     #[cfg(not(feature = "re"))]
     {
-        if let Some(mut v) = matches.values_of("match") {
-            if let Some(_) = v.next() {
-                eprintln!("Support for --match and --label was not compiled in");
+        for arg in &["match", "match-ci", "label"] {
+            if let Some(v) = values_of(matches, arg) {
+                if !v.is_empty() {
+                    eprintln!("Support for --match and --label was not compiled in");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // After all the extractors have been added
+    if let Some(v) = values_of(matches, "label-default") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(2, '=').collect();
+            if parts.len() != 2 {
+                eprintln!("--label-default needs a label and a value separated by '='");
+                std::process::exit(1);
+            }
+            if let Err(()) = collector.set_label_default(parts[0], parts[1].to_owned()) {
+                eprintln!("No label {:?}, can't set its default", parts[0]);
                 std::process::exit(1);
             }
         }
-        if let Some(mut v) = matches.values_of("label") {
-            if let Some(_) = v.next() {
-                eprintln!("Support for --match and --label was not compiled in");
+    }
+
+    if matches.value_of("path-trailing-slash") == Some("strip") {
+        if let Err(()) = collector.strip_path_trailing_slash() {
+            eprintln!("No path label, can't use --path-trailing-slash strip");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(v) = values_of(matches, "compose-label") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(4, ':').collect();
+            if parts.len() != 4 {
+                eprintln!("--compose-label needs a label, two labels, and a separator separated by ':'");
+                std::process::exit(1);
+            }
+            if let Err(label) = collector.add_composite_label(parts[0], (parts[1], parts[2]), parts[3]) {
+                eprintln!("No label {:?}, can't use --compose-label", label);
                 std::process::exit(1);
             }
         }
     }
 
-    let collector = collector.build()?;
+    if let Some(v) = matches.value_of("label-order") {
+        let order: Vec<&str> = v.split(',').map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+        if let Err(label) = collector.set_label_order(&order) {
+            eprintln!("No label {:?}, can't use it in --label-order", label);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(collector.build()?)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // End the process if any thread panics
+    // https://stackoverflow.com/a/36031130
+    let orig_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        // invoke the default handler and exit the process
+        orig_hook(panic_info);
+        std::process::exit(1);
+    }));
+
+    let matches = cli().get_matches();
+
+    // Before starting any thread, since this changes the environment
+    let systemd_listeners = if flag(&matches, "systemd-socket") { Some(systemd_listeners()) } else { None };
+
+    {
+        let mut logger_builder = env_logger::Builder::from_default_env();
+        logger_builder.init();
+    }
+
+    let set_filters = set_filters(&matches);
+    let tcp_listener = match matches.value_of("tcp-listen") {
+        Some(addr) => match TcpListener::bind(addr) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("Can't listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let collector = build_collector(&matches, None, Path::new(matches.value_of_os("FILE").unwrap()), &set_filters, tcp_listener)?;
+    let exemplars = collector.exemplars();
+    let main_collector = collector.clone();
 
     let registry: &Registry = default_registry();
     registry.register(Box::new(collector)).expect("register collector");
 
-    let addr = match matches.value_of("bind").unwrap().parse() {
-        Ok(a) => a,
-        Err(_) => {
-            eprintln!("Invalid address: use ip:port format, for example 127.0.0.1:9898");
+    // Report the start time, unless the process collector already does
+    #[cfg(not(all(feature = "process", target_os = "linux")))]
+    {
+        let start_time = prometheus::Gauge::new("process_start_time_seconds", "Start time of the process since unix epoch in seconds").unwrap();
+        start_time.set(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
+        registry.register(Box::new(start_time)).expect("register start time");
+    }
+
+    // Additional sources each get their own registry
+    let mut sources = HashMap::new();
+    if let Some(v) = values_of(&matches, "source") {
+        for s in v {
+            let parts: Vec<&str> = s.splitn(2, '=').collect();
+            if parts.len() != 2 || parts[0].is_empty() || parts[0].contains('/') {
+                eprintln!("--source needs a name and a path separated by '='");
+                std::process::exit(1);
+            }
+            if sources.contains_key(parts[0]) {
+                eprintln!("Source {:?} is specified more than once", parts[0]);
+                std::process::exit(1);
+            }
+            let collector = build_collector(&matches, Some(parts[0]), Path::new(parts[1]), &set_filters, None)?;
+            let exemplars = collector.exemplars();
+            let registry = Registry::new();
+            registry.register(Box::new(collector.clone())).expect("register collector");
+            sources.insert(parts[0].to_owned(), Endpoint { registry, exemplars, collector: Some(collector) });
+        }
+    }
+    // The sources share the sets, so each file is only reloaded once
+    reload_on_sighup(set_filters.into_iter().map(|(_, file, _)| file).collect());
+
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint {
+            registry: registry.clone(),
+            exemplars,
+            collector: Some(main_collector),
+        },
+        sources,
+        reset_token: matches.value_of("reset-token").map(|t| t.to_owned()),
+        debug_endpoints: flag(&matches, "enable-debug-endpoints"),
+    });
+
+    let push_interval = std::time::Duration::from_secs_f64(seconds_arg(&matches, "push-interval").unwrap());
+    if let Some(url) = matches.value_of("pushgateway") {
+        let mut grouping = Vec::new();
+        if let Some(v) = values_of(&matches, "grouping") {
+            for s in v {
+                let parts: Vec<&str> = s.splitn(2, '=').collect();
+                if parts.len() != 2 {
+                    eprintln!("--grouping needs a label and a value separated by '='");
+                    std::process::exit(1);
+                }
+                grouping.push((parts[0].to_owned(), parts[1].to_owned()));
+            }
+        }
+        let target = PushTarget {
+            url: url.to_owned(),
+            job: matches.value_of("job").unwrap().to_owned(),
+            grouping,
+        };
+        info!("Pushing metrics to {} every {:?}", target.group_url(), push_interval);
+        tokio::spawn(crate::pushgateway::run(target, push_interval));
+    }
+    #[cfg(feature = "remote-write")]
+    {
+        if let Some(url) = matches.value_of("remote-write") {
+            info!("Sending metrics to {} every {:?}", url, push_interval);
+            tokio::spawn(crate::remote_write::run(url.to_owned(), push_interval));
+        }
+    }
+    #[cfg(not(feature = "remote-write"))]
+    {
+        if matches.is_present("remote-write") {
+            eprintln!("Support for --remote-write was not compiled in");
+            std::process::exit(1);
+        }
+    }
+
+    let servers = if let Some(listeners) = systemd_listeners {
+        listeners.and_then(|l| start_listeners(l, endpoints))
+    } else {
+        let mut addrs = Vec::new();
+        // Several addresses can be given at once, separated by commas
+        for s in values_of(&matches, "bind").unwrap().iter().flat_map(|v| v.split(',')) {
+            match s.parse() {
+                Ok(a) => addrs.push(a),
+                Err(_) => {
+                    eprintln!("Invalid address {:?}: use ip:port format, for example 127.0.0.1:9898 or [::1]:9898", s);
+                    std::process::exit(1);
+                }
+            }
+        }
+        start_servers(&addrs, endpoints)
+    };
+    let servers = match servers {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     };
-    info!("Starting server at {}", addr);
-    Server::bind(&addr).serve(make_service_fn(|_| async {
-        Ok::<_, hyper::Error>(service_fn(serve_req))
+    for (addr, _) in &servers {
+        info!("Starting server at {}", addr);
+    }
+    // Stop at the first server that fails
+    futures_util::future::try_join_all(servers.into_iter().map(|(_, server)| async move {
+        server.await??;
+        Ok::<(), Box<dyn std::error::Error>>(())
     })).await?;
 
     Ok(())
 }
+
+/// A running server, with the address it is bound to.
+type RunningServer = (SocketAddr, JoinHandle<hyper::Result<()>>);
+
+/// Listen on each of the addresses, all serving the same endpoints.
+///
+/// Returns the address each server is bound to (which differs from the
+/// requested one for port 0), and the task running it.
+fn start_servers(addrs: &[SocketAddr], endpoints: Arc<Endpoints>) -> Result<Vec<RunningServer>, String> {
+    let mut servers = Vec::new();
+    for addr in addrs {
+        let builder = Server::try_bind(addr).map_err(|e| format!("Can't listen on {}: {}", addr, e))?;
+        servers.push(spawn_server(builder, endpoints.clone()));
+    }
+    Ok(servers)
+}
+
+/// Serve on sockets that are already listening, all serving the same
+/// endpoints.
+fn start_listeners(listeners: Vec<TcpListener>, endpoints: Arc<Endpoints>) -> Result<Vec<RunningServer>, String> {
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let builder = Server::from_tcp(listener).map_err(|e| format!("Can't use socket: {}", e))?;
+        servers.push(spawn_server(builder, endpoints.clone()));
+    }
+    Ok(servers)
+}
+
+fn spawn_server(builder: hyper::server::Builder<AddrIncoming>, endpoints: Arc<Endpoints>) -> RunningServer {
+    let server = builder.serve(make_service_fn(move |_| {
+        let endpoints = endpoints.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| serve_req(req, endpoints.clone())))
+        }
+    }));
+    (server.local_addr(), tokio::spawn(server))
+}
+
+/// The sockets passed by systemd socket activation.
+#[cfg(all(unix, feature = "systemd"))]
+fn systemd_listeners() -> Result<Vec<TcpListener>, String> {
+    let listeners = crate::systemd::listeners()?;
+    if listeners.is_empty() {
+        return Err("No socket was passed by systemd, can't use --systemd-socket".to_owned());
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(all(unix, feature = "systemd")))]
+fn systemd_listeners() -> Result<Vec<TcpListener>, String> {
+    Err("Support for --systemd-socket was not compiled in".to_owned())
+}
+
+#[test]
+fn test_env() {
+    std::env::set_var("ALP_BIND", "0.0.0.0:9000");
+    std::env::set_var("ALP_LOG_FORMAT", "$host $status");
+    std::env::set_var("ALP_DURATION_SUM", "1");
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "/var/log/nginx/access.log"]);
+    assert_eq!(matches.value_of("FILE"), Some("/var/log/nginx/access.log"));
+    assert_eq!(matches.value_of("LOG_FORMAT"), Some("$host $status"));
+    assert_eq!(values_of(&matches, "bind").unwrap(), vec!["0.0.0.0:9000"]);
+    assert!(flag(&matches, "duration-sum"));
+    assert!(!flag(&matches, "loose-whitespace"));
+
+    // The command line takes precedence
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log", "$remote_addr", "--bind", "127.0.0.1:9001"]);
+    assert_eq!(matches.value_of("LOG_FORMAT"), Some("$remote_addr"));
+    assert_eq!(values_of(&matches, "bind").unwrap(), vec!["127.0.0.1:9001"]);
+
+    // Multiple addresses
+    std::env::set_var("ALP_BIND", "127.0.0.1:9000,[::1]:9000");
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log"]);
+    assert_eq!(values_of(&matches, "bind").unwrap(), vec!["127.0.0.1:9000,[::1]:9000"]);
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log", "-b", "127.0.0.1:9001", "-b", "[::1]:9001,[::1]:9002"]);
+    assert_eq!(values_of(&matches, "bind").unwrap(), vec!["127.0.0.1:9001", "[::1]:9001,[::1]:9002"]);
+
+    // The environment variable gives a single value
+    std::env::set_var("ALP_EXCLUDE", "status:404");
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log"]);
+    assert_eq!(values_of(&matches, "exclude").unwrap(), vec!["status:404"]);
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log", "--exclude", "status:500", "--exclude", "host:example.org"]);
+    assert_eq!(values_of(&matches, "exclude").unwrap(), vec!["status:500", "host:example.org"]);
+
+    std::env::remove_var("ALP_EXCLUDE");
+    std::env::remove_var("ALP_BIND");
+    std::env::remove_var("ALP_LOG_FORMAT");
+    std::env::remove_var("ALP_DURATION_SUM");
+}
+
+#[tokio::test]
+async fn test_multiple_binds() {
+    let counter = prometheus::IntCounter::new("requests_total", "Requests").unwrap();
+    counter.inc();
+    let registry = Registry::new();
+    registry.register(Box::new(counter)).unwrap();
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint { registry, exemplars: None, collector: None },
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints: false,
+    });
+
+    let addrs: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+    let servers = start_servers(&addrs, endpoints.clone()).unwrap();
+    assert_eq!(servers.len(), 2);
+    assert_ne!(servers[0].0, servers[1].0);
+
+    // Both serve the same registry
+    let client = hyper::Client::new();
+    for (addr, _) in &servers {
+        let response = client.get(format!("http://{}/metrics", addr).parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("requests_total 1\n"));
+    }
+
+    // An address that is in use is an error, not a panic
+    assert!(start_servers(&[servers[0].0], endpoints).is_err());
+}
+
+#[tokio::test]
+async fn test_start_listeners() {
+    let registry = Registry::new();
+    let counter = prometheus::IntCounter::new("requests_total", "Requests").unwrap();
+    counter.inc();
+    registry.register(Box::new(counter)).unwrap();
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint { registry, exemplars: None, collector: None },
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints: false,
+    });
+
+    // Like a socket passed by systemd
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let servers = start_listeners(vec![listener], endpoints).unwrap();
+    assert_eq!(servers[0].0, addr);
+
+    let client = hyper::Client::new();
+    let response = client.get(format!("http://{}/metrics", addr).parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body).unwrap().contains("requests_total 1\n"));
+}
+
+#[tokio::test]
+async fn test_landing_page() {
+    let registry = Registry::new();
+    let counter = prometheus::IntCounter::new("requests", "The total number of requests").unwrap();
+    counter.inc();
+    registry.register(Box::new(counter)).unwrap();
+    let mut sources = HashMap::new();
+    sources.insert("api".to_owned(), Endpoint { registry: Registry::new(), exemplars: None, collector: None });
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint { registry, exemplars: None, collector: None },
+        sources,
+        reset_token: None,
+        debug_endpoints: false,
+    });
+
+    let response = serve_req(Request::get("/").body(Body::empty()).unwrap(), endpoints.clone()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("<a href=\"/metrics\">"));
+    assert!(body.contains("<a href=\"/metrics/api\">"));
+    assert!(body.contains(env!("CARGO_PKG_VERSION")));
+
+    let response = serve_req(Request::get("/metrics").body(Body::empty()).unwrap(), endpoints).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()[CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body).unwrap().contains("requests 1\n"));
+}
+
+#[tokio::test]
+async fn test_sources() {
+    let registry = Registry::new();
+    let counter = prometheus::IntCounter::new("requests", "The total number of requests").unwrap();
+    counter.inc();
+    registry.register(Box::new(counter)).unwrap();
+    let mut sources = HashMap::new();
+    sources.insert("api".to_owned(), Endpoint { registry, exemplars: None, collector: None });
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint { registry: Registry::new(), exemplars: None, collector: None },
+        sources,
+        reset_token: None,
+        debug_endpoints: false,
+    });
+
+    let get = |path: &str| {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        serve_req(req, endpoints.clone())
+    };
+
+    let response = get("/metrics/api").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body).unwrap().contains("requests 1\n"));
+
+    let response = get("/metrics/other").await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    // Other paths serve the main registry
+    let response = get("/metrics").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(!std::str::from_utf8(&body).unwrap().contains("requests 1\n"));
+}
+
+#[test]
+fn test_source_offset_file() {
+    let matches = cli().get_matches_from(vec!["access-log-to-prometheus-metrics", "access.log", "$host $status", "--offset-file", "/var/lib/alp/offset"]);
+    assert_eq!(processor_options(&matches, None).offset_file, Some(PathBuf::from("/var/lib/alp/offset")));
+    assert_eq!(processor_options(&matches, Some("api")).offset_file, Some(PathBuf::from("/var/lib/alp/offset.api")));
+}
+
+#[tokio::test]
+async fn test_openmetrics() {
+    let mut store = ExemplarStore::new("request_duration");
+    store.record(&[1.0], &[], &[], openmetrics::Exemplar {
+        request_id: "4a0f1d3e".to_owned(),
+        value: 0.5,
+        timestamp: 1636598079.5,
+    });
+    let histogram = prometheus::Histogram::with_opts(prometheus::HistogramOpts::new("request_duration", "Duration").buckets(vec![1.0])).unwrap();
+    histogram.observe(0.5);
+    let registry = Registry::new();
+    registry.register(Box::new(histogram)).unwrap();
+    let endpoints = Arc::new(Endpoints {
+        main: Endpoint { registry, exemplars: Some(Arc::new(std::sync::Mutex::new(store))), collector: None },
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints: false,
+    });
+
+    // Exemplars are only in the OpenMetrics format
+    let req = Request::get("/metrics")
+        .header(ACCEPT, "application/openmetrics-text; version=1.0.0,text/plain;version=0.0.4;q=0.5")
+        .body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints.clone()).await.unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], OPENMETRICS_FORMAT);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("request_duration_bucket{le=\"1\"} 1 # {request_id=\"4a0f1d3e\"} 0.5 1636598079.5\n"));
+    assert!(body.ends_with("# EOF\n"));
+
+    let req = Request::get("/metrics").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(!std::str::from_utf8(&body).unwrap().contains("request_id"));
+}
+
+#[tokio::test]
+async fn test_reset_endpoint() {
+    let endpoint = || Endpoint { registry: Registry::new(), exemplars: None, collector: None };
+    let endpoints = Arc::new(Endpoints {
+        main: endpoint(),
+        sources: HashMap::new(),
+        reset_token: Some("s3cret".to_owned()),
+        debug_endpoints: false,
+    });
+
+    let reset = |method: Method, token: Option<&str>| {
+        let mut req = Request::builder().method(method).uri("/reset");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        serve_req(req.body(Body::empty()).unwrap(), endpoints.clone())
+    };
+    assert_eq!(reset(Method::POST, Some("s3cret")).await.unwrap().status(), 200);
+    assert_eq!(reset(Method::POST, Some("wrong")).await.unwrap().status(), 401);
+    assert_eq!(reset(Method::POST, None).await.unwrap().status(), 401);
+    assert_eq!(reset(Method::GET, Some("s3cret")).await.unwrap().status(), 405);
+
+    // Without a token, it's just another path for the main registry
+    let endpoints = Arc::new(Endpoints {
+        main: endpoint(),
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints: false,
+    });
+    let req = Request::post("/reset").header(AUTHORIZATION, "Bearer s3cret").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints).await.unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], TextEncoder::new().format_type());
+}
+
+#[tokio::test]
+async fn test_debug_snapshot() {
+    let parser = LogParser::from_format("$host $status").unwrap();
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-snapshot.log");
+    let collector = LogCollectorBuilder::new(parser, path).build().unwrap();
+    let endpoints = |debug_endpoints| Arc::new(Endpoints {
+        main: Endpoint { registry: Registry::new(), exemplars: None, collector: Some(collector.clone()) },
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints,
+    });
+
+    // Disabled by default
+    let req = Request::get("/debug/snapshot").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints(false)).await.unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], TextEncoder::new().format_type());
+
+    let req = Request::get("/debug/snapshot").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints(true)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.starts_with("{\"active\":false,\"metrics\":{"));
+    assert!(body.contains("\"log_file_present\":[{\"labels\":{},\"value\":0}]"));
+
+    let req = Request::get("/debug/snapshot/api").body(Body::empty()).unwrap();
+    assert_eq!(serve_req(req, endpoints(true)).await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn test_debug_last() {
+    let parser = LogParser::from_format("$host $status").unwrap();
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-last.log");
+    let mut collector_builder = LogCollectorBuilder::new(parser, path);
+    collector_builder.set_data_options(LogDataOptions {
+        debug_lines: Some(DEBUG_LINES),
+        ..Default::default()
+    });
+    let collector = collector_builder.build().unwrap();
+    let endpoints = |debug_endpoints| Arc::new(Endpoints {
+        main: Endpoint { registry: Registry::new(), exemplars: None, collector: Some(collector.clone()) },
+        sources: HashMap::new(),
+        reset_token: None,
+        debug_endpoints,
+    });
+
+    // Disabled by default
+    let req = Request::get("/debug/last").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints(false)).await.unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], TextEncoder::new().format_type());
+
+    let req = Request::get("/debug/last").body(Body::empty()).unwrap();
+    let response = serve_req(req, endpoints(true)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"{\"lines\":[]}");
+
+    let req = Request::get("/debug/last/api").body(Body::empty()).unwrap();
+    assert_eq!(serve_req(req, endpoints(true)).await.unwrap().status(), 404);
+}
+
+#[test]
+fn test_sample_lines() {
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-strict.log");
+    std::fs::write(&path, "example.org 200\r\nexample.org 404\ngarbage\nexample.org 500\n").unwrap();
+    let parser = LogParser::from_format("$host $status").unwrap();
+
+    let options = ProcessorOptions::default();
+
+    let (count, failures) = sample_lines(&parser, &options, &path, 100).unwrap();
+    assert_eq!(count, 4);
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, "garbage");
+
+    let (count, failures) = sample_lines(&parser, &options, &path, 2).unwrap();
+    assert_eq!((count, failures.len()), (2, 0));
+
+    // Lines are read like the processor does
+    std::fs::write(&path, b"# rotated\0caf\xe9.org 200\0\0example.org 404\0").unwrap();
+    let options = ProcessorOptions {
+        line_terminator: Some(0),
+        skip_blank_lines: true,
+        comment_prefix: Some("#".to_owned()),
+        encoding: Some(encoding_rs::WINDOWS_1252),
+        ..Default::default()
+    };
+    let (count, failures) = sample_lines(&parser, &options, &path, 100).unwrap();
+    assert_eq!((count, failures.len()), (2, 0));
+    // Invalid text is a failure, not an error
+    let options = ProcessorOptions { encoding: None, ..options };
+    let (count, failures) = sample_lines(&parser, &options, &path, 100).unwrap();
+    assert_eq!((count, failures.len()), (2, 1));
+    assert_eq!(failures[0].0, "caf\u{fffd}.org 200");
+
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(sample_lines(&parser, &options, &path, 100).unwrap().0, 0);
+
+    // Compressed files are not checked
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-strict.log.gz");
+    std::fs::write(&path, b"\x1f\x8b garbage").unwrap();
+    assert_eq!(sample_lines(&parser, &options, &path, 100).unwrap().0, 0);
+    std::fs::remove_file(&path).unwrap();
+}
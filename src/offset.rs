@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Identifies a file across renames, to detect rotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileIdentity {
+    device: u64,
+    inode: u64,
+}
+
+impl FileIdentity {
+    /// Get the identity of an open file, if the platform supports it.
+    #[cfg(unix)]
+    pub fn of(file: &File) -> std::io::Result<Option<FileIdentity>> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = file.metadata()?;
+        Ok(Some(FileIdentity {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_file: &File) -> std::io::Result<Option<FileIdentity>> {
+        Ok(None)
+    }
+}
+
+/// A position in a specific file, persisted across restarts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SavedOffset {
+    pub identity: FileIdentity,
+    pub offset: u64,
+}
+
+/// Read the offset file, if it exists and is valid.
+pub fn read_offset(path: &Path) -> std::io::Result<Option<SavedOffset>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let numbers: Vec<u64> = match content.split_whitespace().map(|n| n.parse()).collect() {
+        Ok(n) => n,
+        Err(_) => return Ok(None),
+    };
+    match numbers[..] {
+        [device, inode, offset] => Ok(Some(SavedOffset {
+            identity: FileIdentity { device, inode },
+            offset,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Write the offset file atomically, by renaming a temporary file over it.
+pub fn write_offset(path: &Path, saved: &SavedOffset) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    {
+        let mut file = File::create(&temp_path)?;
+        writeln!(file, "{} {} {}", saved.identity.device, saved.identity.inode, saved.offset)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&temp_path, path)
+}
+
+#[test]
+fn test_save_restore() {
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-offset-test");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(read_offset(&path).unwrap(), None);
+
+    let saved = SavedOffset {
+        identity: FileIdentity { device: 2049, inode: 1234567 },
+        offset: 4096,
+    };
+    write_offset(&path, &saved).unwrap();
+    assert_eq!(read_offset(&path).unwrap(), Some(saved));
+
+    let saved = SavedOffset { offset: 8192, ..saved };
+    write_offset(&path, &saved).unwrap();
+    assert_eq!(read_offset(&path).unwrap(), Some(saved));
+
+    std::fs::write(&path, "garbage\n").unwrap();
+    assert_eq!(read_offset(&path).unwrap(), None);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,187 @@
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A request that was observed in a histogram bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    pub request_id: String,
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+/// The last exemplar of each bucket of a histogram, for each series.
+#[derive(Debug)]
+pub struct ExemplarStore {
+    metric: String,
+    /// Exemplars by label pairs sorted by name, then by bucket, with an
+    /// extra bucket for +Inf
+    exemplars: HashMap<Vec<(String, String)>, Vec<Option<Exemplar>>>,
+}
+
+pub type SharedExemplars = Arc<Mutex<ExemplarStore>>;
+
+impl ExemplarStore {
+    pub fn new(metric: &str) -> ExemplarStore {
+        ExemplarStore {
+            metric: metric.to_owned(),
+            exemplars: HashMap::new(),
+        }
+    }
+
+    /// Record an observation as the exemplar of its bucket, given the upper
+    /// bounds of the buckets of the series.
+    pub fn record(&mut self, bounds: &[f64], labels: &[String], label_values: &[&str], exemplar: Exemplar) {
+        let mut key: Vec<(String, String)> = labels.iter().cloned().zip(label_values.iter().map(|v| v.to_string())).collect();
+        key.sort();
+        let bucket = bounds.iter().position(|&b| exemplar.value <= b).unwrap_or(bounds.len());
+        let buckets = bounds.len() + 1;
+        self.exemplars.entry(key).or_insert_with(|| vec![None; buckets])[bucket] = Some(exemplar);
+    }
+
+    /// Forget all the exemplars.
+    pub fn clear(&mut self) {
+        self.exemplars.clear();
+    }
+
+    fn get(&self, labels: &[LabelPair], bucket: usize) -> Option<&Exemplar> {
+        let key: Vec<(String, String)> = labels.iter().map(|p| (p.get_name().to_owned(), p.get_value().to_owned())).collect();
+        self.exemplars.get(&key)?.get(bucket)?.as_ref()
+    }
+}
+
+fn escape(value: &str, quote: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '"' if quote => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[LabelPair], extra: Option<(&str, &str)>, value: &str, exemplar: Option<&Exemplar>) {
+    out.push_str(name);
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|p| format!("{}=\"{}\"", p.get_name(), escape(p.get_value(), true)))
+        .collect();
+    if let Some((k, v)) = extra {
+        pairs.push(format!("{}=\"{}\"", k, v));
+    }
+    if !pairs.is_empty() {
+        write!(out, "{{{}}}", pairs.join(",")).unwrap();
+    }
+    write!(out, " {}", value).unwrap();
+    if let Some(e) = exemplar {
+        write!(out, " # {{request_id=\"{}\"}} {} {}", escape(&e.request_id, true), float(e.value), e.timestamp).unwrap();
+    }
+    out.push('\n');
+}
+
+/// Encode metrics in the OpenMetrics text format, with exemplars.
+pub fn encode(families: &[MetricFamily], exemplars: &[&ExemplarStore]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let store = exemplars.iter().find(|s| s.metric == name);
+        writeln!(out, "# HELP {} {}", family_name, escape(family.get_help(), false)).unwrap();
+        writeln!(out, "# TYPE {} {}", family_name, kind).unwrap();
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    write_sample(&mut out, &format!("{}_total", family_name), labels, None, &float(metric.get_counter().get_value()), None);
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, labels, None, &float(metric.get_gauge().get_value()), None);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{}_bucket", name);
+                    let buckets = histogram.get_bucket();
+                    for (i, bucket) in buckets.iter().enumerate() {
+                        let exemplar = store.and_then(|s| s.get(labels, i));
+                        write_sample(&mut out, &bucket_name, labels, Some(("le", &float(bucket.get_upper_bound()))), &bucket.get_cumulative_count().to_string(), exemplar);
+                    }
+                    if buckets.last().map_or(true, |b| b.get_upper_bound() != f64::INFINITY) {
+                        let exemplar = store.and_then(|s| s.get(labels, buckets.len()));
+                        write_sample(&mut out, &bucket_name, labels, Some(("le", "+Inf")), &histogram.get_sample_count().to_string(), exemplar);
+                    }
+                    write_sample(&mut out, &format!("{}_sum", name), labels, None, &float(histogram.get_sample_sum()), None);
+                    write_sample(&mut out, &format!("{}_count", name), labels, None, &histogram.get_sample_count().to_string(), None);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        write_sample(&mut out, name, labels, Some(("quantile", &float(quantile.get_quantile()))), &float(quantile.get_value()), None);
+                    }
+                    write_sample(&mut out, &format!("{}_sum", name), labels, None, &float(summary.get_sample_sum()), None);
+                    write_sample(&mut out, &format!("{}_count", name), labels, None, &summary.get_sample_count().to_string(), None);
+                }
+                // Not produced by this program
+                MetricType::UNTYPED => {}
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[test]
+fn test_encode() {
+    use prometheus::core::Collector;
+
+    let counter = prometheus::IntCounterVec::new(prometheus::Opts::new("requests", "The total number of requests"), &["status"]).unwrap();
+    counter.with_label_values(&["200"]).inc_by(2);
+    let histogram = prometheus::HistogramVec::new(
+        prometheus::HistogramOpts::new("request_duration", "Duration").buckets(vec![0.1, 1.0]),
+        &["status"],
+    ).unwrap();
+    histogram.with_label_values(&["200"]).observe(0.5);
+    let mut store = ExemplarStore::new("request_duration");
+    store.record(&[0.1, 1.0], &["status".to_owned()], &["200"], Exemplar {
+        request_id: "abc123".to_owned(),
+        value: 0.5,
+        timestamp: 1636598079.5,
+    });
+
+    let mut families = counter.collect();
+    families.extend(histogram.collect());
+    assert_eq!(
+        encode(&families, &[&store]),
+        "# HELP requests The total number of requests\n\
+         # TYPE requests counter\n\
+         requests_total{status=\"200\"} 2\n\
+         # HELP request_duration Duration\n\
+         # TYPE request_duration histogram\n\
+         request_duration_bucket{status=\"200\",le=\"0.1\"} 0\n\
+         request_duration_bucket{status=\"200\",le=\"1\"} 1 # {request_id=\"abc123\"} 0.5 1636598079.5\n\
+         request_duration_bucket{status=\"200\",le=\"+Inf\"} 1\n\
+         request_duration_sum{status=\"200\"} 0.5\n\
+         request_duration_count{status=\"200\"} 1\n\
+         # EOF\n",
+    );
+}
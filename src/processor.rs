@@ -1,13 +1,75 @@
 use log::{debug, info, warn};
 use notify::{RecommendedWatcher, Watcher};
+use prometheus::HistogramVec;
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::borrow::Cow::*;
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender, SyncSender};
+use std::time::{Duration, Instant};
 
 use crate::collector::LogData;
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::hyperloglog;
 use crate::log_parser::{LogValue, LogParser, ParseError};
+use crate::openmetrics::Exemplar;
+use crate::offset::{FileIdentity, SavedOffset, read_offset, write_offset};
+use crate::sha256::short_hash;
+#[cfg(feature = "statsd")]
+use crate::statsd::StatsdClient;
+use crate::timestamp::TimestampFormat;
+use crate::value_set::SharedSet;
+
+/// Parts of user agents that are used by crawlers and tools, lowercase.
+pub const BOT_PATTERNS: &[&str] = &[
+    "bot", "crawl", "spider", "slurp", "archiver", "facebookexternalhit",
+    "curl", "wget", "python-requests", "python-urllib", "go-http-client",
+    "java/", "okhttp", "libwww-perl", "headlesschrome", "phantomjs",
+];
+
+/// The values of the `size_class` label, from the smallest responses.
+pub const SIZE_CLASSES: [&str; 5] = ["tiny", "small", "medium", "large", "huge"];
+
+/// The default upper bounds of the size classes, in bytes (inclusive).
+pub const DEFAULT_SIZE_THRESHOLDS: [u64; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+/// How often to write the offset file, at most.
+const OFFSET_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to check for `--idle-timeout`, at most.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the path is a named pipe, which can't be watched or seeked.
+#[cfg(unix)]
+pub(crate) fn is_fifo(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_fifo(_path: &std::path::Path) -> bool {
+    false
+}
+
+/// Whether the file is compressed with gzip, from its extension or its
+/// first bytes.
+pub(crate) fn is_gzip(path: &std::path::Path) -> bool {
+    if path.extension().map_or(false, |e| e == "gz") {
+        return true;
+    }
+    let mut magic = [0; 2];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+        Ok(()) => magic == [0x1f, 0x8b],
+        Err(_) => false,
+    }
+}
 
 pub struct Filter {
     pub(crate) field_index: usize,
@@ -19,34 +81,540 @@ pub enum FilterFunc {
     Regex {
         regex: regex::Regex,
     },
+    /// Only lines where the field is one of these values, ignoring case
+    Allow {
+        values: HashSet<String>,
+    },
+    /// Drop lines where the field is exactly one of these values
+    ExcludeExact {
+        values: HashSet<String>,
+    },
+    /// Only lines where the field has a value, or only those where it is
+    /// empty or `-` if `present` is false
+    HasValue {
+        present: bool,
+    },
+    /// Only lines where the field is exactly one of the values of the set,
+    /// or only the others if `exclude` is set
+    SetMatch {
+        set: SharedSet,
+        exclude: bool,
+    },
+    /// Only lines whose request method is one of these
+    Method {
+        methods: HashSet<String>,
+        /// Whether the field is `$request`, e.g. `GET /path HTTP/1.1`, rather
+        /// than `$request_method`
+        from_request: bool,
+    },
+    /// Only lines where the field is a number between the bounds, inclusive
+    Range {
+        min: f64,
+        max: f64,
+    },
+    /// Drop lines whose timestamp is too old or too far in the future
+    TimeWindow {
+        format: TimestampFormat,
+        /// Maximum age in seconds
+        max_age: Option<f64>,
+        /// Maximum time in the future in seconds
+        max_future: Option<f64>,
+    },
+}
+
+/// What happened to a line that was parsed successfully.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineStatus {
+    Accepted,
+    /// Dropped by a filter
+    Filtered,
+    /// Dropped because its timestamp is outside of the time window
+    OutOfTimeWindow,
+}
+
+/// The maximum size in bytes of a compiled user-supplied regular expression,
+/// and of its DFA cache, so a huge pattern can't use up the memory.
+#[cfg(feature = "re")]
+pub const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Compile a regular expression from the command line.
+///
+/// Fails with `regex::Error::CompiledTooBig` if the pattern is over
+/// `REGEX_SIZE_LIMIT`.
+#[cfg(feature = "re")]
+pub fn user_regex(pattern: &str, case_insensitive: bool) -> Result<regex::Regex, regex::Error> {
+    regex::RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+#[cfg(feature = "re")]
+impl FilterFunc {
+    pub fn regex(pattern: &str, case_insensitive: bool) -> Result<FilterFunc, regex::Error> {
+        let regex = user_regex(pattern, case_insensitive)?;
+        Ok(FilterFunc::Regex { regex })
+    }
 }
 
 impl Filter {
-    fn filter(&self, value: &str) -> bool {
+    fn filter(&self, value: &str, clock: fn() -> f64) -> bool {
         match &self.func {
             #[cfg(feature = "re")]
             FilterFunc::Regex { regex } => {
                 regex.is_match(value)
             }
-            // Can't happen, but "references are always considered inhabited"
+            FilterFunc::Allow { values } => {
+                values.contains(&value.to_ascii_lowercase())
+            }
+            FilterFunc::ExcludeExact { values } => {
+                !values.contains(value)
+            }
+            FilterFunc::HasValue { present } => {
+                (!value.is_empty() && value != "-") == *present
+            }
+            FilterFunc::SetMatch { set, exclude } => {
+                set.read().unwrap().contains(value) != *exclude
+            }
+            FilterFunc::Method { methods, from_request } => {
+                let method = if *from_request {
+                    value.split(' ').next().unwrap_or("")
+                } else {
+                    value
+                };
+                methods.contains(method)
+            }
+            FilterFunc::Range { min, max } => {
+                match value.parse::<f64>() {
+                    Ok(v) => *min <= v && v <= *max,
+                    Err(_) => false,
+                }
+            }
+            FilterFunc::TimeWindow { format, max_age, max_future } => {
+                let timestamp = match format.parse(value) {
+                    Some(t) => t,
+                    None => return false,
+                };
+                let now = clock();
+                if let Some(max_age) = max_age {
+                    if timestamp < now - max_age {
+                        return false;
+                    }
+                }
+                if let Some(max_future) = max_future {
+                    if timestamp > now + max_future {
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Whether this filter is one of alternatives, of which a line has to
+    /// match at least one on each field, rather than a requirement.
+    fn is_alternative(&self) -> bool {
+        match self.func {
+            #[cfg(feature = "re")]
+            FilterFunc::Regex { .. } => true,
+            FilterFunc::Allow { .. } | FilterFunc::ExcludeExact { .. } | FilterFunc::HasValue { .. } | FilterFunc::SetMatch { .. } | FilterFunc::Method { .. } | FilterFunc::Range { .. } | FilterFunc::TimeWindow { .. } => false,
+        }
+    }
+
+    /// How to report lines dropped by this filter.
+    fn dropped_status(&self, value: &str) -> Result<LineStatus, ParseError> {
+        match &self.func {
+            // A timestamp that can't be read is an error, not an old line
+            FilterFunc::TimeWindow { format, .. } => match format.parse(value) {
+                Some(_) => Ok(LineStatus::OutOfTimeWindow),
+                None => Err(ParseError::Invalid(format!("Invalid timestamp {:?}", value))),
+            },
             #[allow(unreachable_patterns)]
-            _ => true,
+            _ => Ok(LineStatus::Filtered),
+        }
+    }
+}
+
+/// The values measured from a line, other than labels.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measurements {
+    pub duration: Option<f32>,
+    pub upstream_duration: Option<f32>,
+    pub upstream_header_time: Option<f32>,
+    pub upstream_connect_time: Option<f32>,
+    pub response_body_size: Option<u64>,
+    /// The size of the request, including the request line and headers
+    pub request_length: Option<u64>,
+    pub timestamp: Option<f64>,
+    pub request_id: Option<String>,
+    pub connection_requests: Option<u64>,
+    /// The hash of `$remote_addr`, to count distinct clients
+    pub client_hash: Option<u64>,
+    /// The protocol of the request, e.g. `HTTP/1.1`
+    pub http_version: Option<&'static str>,
+    /// The cache status, e.g. `HIT`, for `cache_hit_ratio`
+    pub cache_status: Option<&'static str>,
+    /// The values of the counted response headers, by header name
+    pub response_headers: Vec<(String, String)>,
+    /// The status code, for `--duration-status`
+    pub status: Option<u16>,
+}
+
+/// Remove the trailing slashes of a path, keeping a single slash for the
+/// root.
+fn strip_trailing_slash(path: &mut Cow<str>) {
+    let len = path.trim_end_matches('/').len().max(1);
+    if len < path.len() {
+        match path {
+            Borrowed(p) => *p = &p[..len],
+            Owned(p) => p.truncate(len),
+        }
+    }
+}
+
+/// Whether this is a pattern for status codes, such as `2xx` or `304`.
+pub fn is_status_pattern(pattern: &str) -> bool {
+    pattern.len() == 3 && pattern.bytes().all(|b| b.is_ascii_digit() || b == b'x')
+}
+
+/// Whether the status code matches a pattern such as `2xx` or `304`.
+fn status_matches(pattern: &str, status: u16) -> bool {
+    pattern.bytes().zip(format!("{:03}", status).bytes()).all(|(p, s)| p == b'x' || p == s)
+}
+
+/// The number of distinct values of each header counted in
+/// `response_header_total`, after which they are counted as "other".
+pub const RESPONSE_HEADER_VALUES: usize = 20;
+
+/// The values of `$upstream_cache_status`. Other values, like "-" when the
+/// cache was not used, are not counted.
+pub const CACHE_STATUSES: [&str; 7] = ["HIT", "MISS", "BYPASS", "EXPIRED", "STALE", "UPDATING", "REVALIDATED"];
+
+/// Whether the response was served from the cache, for this cache status.
+pub fn from_cache(cache_status: &str) -> bool {
+    matches!(cache_status, "HIT" | "STALE" | "UPDATING" | "REVALIDATED")
+}
+
+const HTTP_VERSIONS: [&str; 5] = ["HTTP/0.9", "HTTP/1.0", "HTTP/1.1", "HTTP/2.0", "HTTP/3.0"];
+
+/// The version from a protocol such as `HTTP/1.1`, from a fixed list so the
+/// values are bounded. Requests without a protocol, such as the garbage sent
+/// to the server, are not counted.
+fn http_version(protocol: &str) -> Option<&'static str> {
+    if !protocol.starts_with("HTTP/") {
+        return None;
+    }
+    let protocol = match protocol {
+        "HTTP/2" => "HTTP/2.0",
+        "HTTP/3" => "HTTP/3.0",
+        p => p,
+    };
+    Some(HTTP_VERSIONS.iter().find(|&&v| v == protocol).copied().unwrap_or("other"))
+}
+
+/// How to combine the times of multiple upstreams, e.g. `0.01, 0.02`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamTimes {
+    /// Add up the times, for the total time spent waiting on upstreams
+    Sum,
+    /// Use the time of the last upstream, the one that answered
+    Last,
+}
+
+/// Parse a list of times from an upstream variable.
+///
+/// nginx separates the values with ", " for each upstream server that was
+/// tried and " : " for internal redirects, and uses "-" for servers that
+/// couldn't be reached.
+fn parse_upstream_times(value: &str, policy: UpstreamTimes) -> Result<Option<f32>, ParseError> {
+    let mut result = None;
+    for item in value.split(|c| c == ',' || c == ':') {
+        let item = item.trim();
+        if item == "-" {
+            continue;
+        }
+        let seconds: f32 = item.parse().map_err(|_| ParseError::Invalid("Invalid duration".to_owned()))?;
+        result = Some(match (policy, result) {
+            (UpstreamTimes::Sum, Some(total)) => total + seconds,
+            _ => seconds,
+        });
+    }
+    Ok(result)
+}
+
+/// The address of a client without its port, e.g. "1.2.3.4" from
+/// "1.2.3.4:5678" or "::1" from "[::1]:5678", as some proxies log it.
+///
+/// Bare IPv6 addresses also contain colons, so only a single colon followed
+/// by digits is taken as a port.
+pub fn strip_port(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        if let Some(end) = rest.find(']') {
+            let port = &rest[end + 1..];
+            if port.is_empty() || (port.starts_with(':') && port[1..].bytes().all(|b| b.is_ascii_digit())) {
+                return &rest[..end];
+            }
+        }
+        return addr;
+    }
+    match addr.find(':') {
+        Some(i) if i + 1 < addr.len() && addr[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &addr[..i],
+        _ => addr,
+    }
+}
+
+/// Decode percent-encoded characters, e.g. "%20" to " ".
+fn url_decode(value: &str) -> Cow<'_, str> {
+    if !value.contains('%') {
+        return Borrowed(value);
+    }
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push((h * 16 + l) as u8);
+                i += 3;
+                continue;
+            }
         }
+        decoded.push(bytes[i]);
+        i += 1;
     }
+    Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Whether a path segment looks like a UUID, e.g.
+/// `123e4567-e89b-12d3-a456-426614174000`.
+fn is_uuid(segment: &str) -> bool {
+    let groups: Vec<&str> = segment.split('-').collect();
+    groups.len() == 5
+        && groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12].iter().cloned())
+        && groups.iter().all(|g| g.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// A step of a `Pipeline` extractor, for `--transform`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransformStep {
+    /// The URI from `$request`, e.g. `/a?b` from `GET /a?b HTTP/1.1`
+    Uri,
+    /// Drop the query string
+    StripQuery,
+    /// Replace the segments of a path that look like identifiers
+    Template,
+    Lowercase,
+    UrlDecode,
+    /// A short hash of the value
+    Hash,
+}
+
+impl TransformStep {
+    pub const NAMES: &'static [&'static str] = &["uri", "strip_query", "template", "lowercase", "url_decode", "hash"];
+
+    pub fn from_name(name: &str) -> Option<TransformStep> {
+        Some(match name {
+            "uri" => TransformStep::Uri,
+            "strip_query" => TransformStep::StripQuery,
+            "template" => TransformStep::Template,
+            "lowercase" => TransformStep::Lowercase,
+            "url_decode" => TransformStep::UrlDecode,
+            "hash" => TransformStep::Hash,
+            _ => return None,
+        })
+    }
+
+    fn apply<'a>(&self, value: Cow<'a, str>) -> Cow<'a, str> {
+        match self {
+            TransformStep::Uri => match value {
+                Borrowed(v) => Borrowed(v.split(' ').nth(1).unwrap_or("")),
+                Owned(v) => Owned(v.split(' ').nth(1).unwrap_or("").to_owned()),
+            },
+            TransformStep::StripQuery => match value.find('?') {
+                Some(i) => match value {
+                    Borrowed(v) => Borrowed(&v[..i]),
+                    Owned(mut v) => {
+                        v.truncate(i);
+                        Owned(v)
+                    }
+                },
+                None => value,
+            },
+            TransformStep::Template => Owned(template_path(&value)),
+            TransformStep::Lowercase => {
+                if value.chars().any(char::is_uppercase) {
+                    Owned(value.to_lowercase())
+                } else {
+                    value
+                }
+            }
+            TransformStep::UrlDecode => match url_decode(&value) {
+                Borrowed(_) => value,
+                Owned(v) => Owned(v),
+            },
+            TransformStep::Hash => Owned(short_hash(&value, 8)),
+        }
+    }
+}
+
+/// Replace the segments of a path that look like identifiers with
+/// placeholders, e.g. `/api/pets/12` to `/api/pets/:id`. The query string is
+/// dropped.
+fn template_path(path: &str) -> String {
+    let path = match path.find('?') {
+        Some(i) => &path[..i],
+        None => path,
+    };
+    path.split('/').map(|segment| {
+        if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+            ":id"
+        } else if is_uuid(segment) {
+            ":uuid"
+        } else if segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit()) {
+            ":id"
+        } else {
+            segment
+        }
+    }).collect::<Vec<_>>().join("/")
+}
+
+/// Write a string to JSON, with quotes.
+pub(crate) fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Write the values of a line as a JSON object.
+///
+/// A variable that appears more than once in the format only gets its first
+/// value, so the keys are unique.
+fn to_json(fields: &[String], values: &[Cow<str>]) -> String {
+    let mut out = String::from("{");
+    for (i, (field, value)) in fields.iter().zip(values).enumerate() {
+        if fields[..i].contains(field) {
+            continue;
+        }
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(&mut out, field);
+        out.push(':');
+        json_string(&mut out, value);
+    }
+    out.push('}');
+    out
 }
 
 pub struct Extractor {
     pub(crate) label: Option<(String, usize)>,
+    /// The label value to use if the field is empty or "-"
+    pub(crate) default: Option<String>,
     pub(crate) field_index: usize,
     pub(crate) func: ExtractorFunc,
 }
 
 pub enum ExtractorFunc {
     User,
-    Status,
+    Status {
+        /// Label values to use instead of some status codes
+        aliases: HashMap<String, String>,
+    },
     Duration,
+    UpstreamDuration {
+        policy: UpstreamTimes,
+    },
+    UpstreamHeaderTime {
+        policy: UpstreamTimes,
+    },
+    UpstreamConnectTime {
+        policy: UpstreamTimes,
+    },
     Host,
+    ServerName,
+    /// The port the request was received on, `unk` if not a number
+    ServerPort,
+    /// `http` or `https`, `unk` if missing
+    Scheme,
     ResponseBodySize,
+    /// The size of the request from `$request_length`, for
+    /// `bytes_received_total`
+    RequestLength,
+    Country,
+    Timestamp {
+        format: TimestampFormat,
+    },
+    RequestId,
+    ConnectionRequests,
+    /// A hash of the client's address, for `approx_unique_clients`
+    ClientHash,
+    /// The cache status, e.g. from `$upstream_cache_status`, for
+    /// `cache_hit_ratio`
+    CacheStatus,
+    /// A response header from `$sent_http_<header>`, for
+    /// `response_header_total`
+    ResponseHeader {
+        header: String,
+    },
+    /// The protocol of the request, for `http_version_requests_total`
+    HttpVersion {
+        /// Whether the field is `$request`, e.g. `GET /path HTTP/1.1`, rather
+        /// than `$server_protocol`
+        from_request: bool,
+    },
+    /// The path of the request, with identifiers replaced by placeholders
+    TemplatedPath {
+        /// Whether the field is `$request`, e.g. `GET /path HTTP/1.1`, rather
+        /// than only the URI
+        from_request: bool,
+    },
+    /// "yes" if the user agent contains one of the patterns, ignoring case
+    BotDetect {
+        /// Lowercase substrings
+        patterns: Vec<String>,
+        /// Whether a missing user agent counts as a bot
+        empty_is_bot: bool,
+    },
+    /// A short hash of the value, e.g. to group by API key without exposing it
+    Hash,
+    /// The value after each of the steps, in order
+    Pipeline {
+        steps: Vec<TransformStep>,
+    },
+    /// `yes` if the request was not the first on its connection, else `no`
+    Keepalive,
+    /// One of `SIZE_CLASSES` for a number of bytes
+    SizeClass {
+        /// Upper bounds of all the classes but the last, inclusive
+        thresholds: [u64; 4],
+    },
+    /// Map values of the field to label values, e.g. for single-character
+    /// indicators such as `$pipe`
+    Map {
+        values: HashMap<String, String>,
+    },
+    /// The part of the field at `index` (from 0) when split on `delimiter`,
+    /// `unk` if there are not that many parts
+    Split {
+        delimiter: String,
+        index: usize,
+    },
+    #[cfg(feature = "geoip")]
+    ClientCountry {
+        geoip: Box<GeoIp>,
+    },
     #[cfg(feature = "re")]
     Regex {
         target: String,
@@ -55,15 +623,26 @@ pub enum ExtractorFunc {
 }
 
 impl Extractor {
-    fn extract<'a>(&'a self, value: &'a str, labels: &mut [Cow<'a, str>], duration: &mut Option<f32>, response_body_size: &mut Option<u64>) -> Result<(), ParseError> {
+    fn extract<'a>(&'a self, value: &'a str, labels: &mut [Cow<'a, str>], measurements: &mut Measurements, lowercase_labels: bool) -> Result<(), ParseError> {
         let mut set_label = |label: Cow<'a, str>| {
             let label_index = match self.label {
                 Some((_, idx)) => idx,
                 None => panic!("Extractor with no target label tried to set a label"),
             };
-            labels[label_index] = label;
+            labels[label_index] = if lowercase_labels && label.chars().any(char::is_uppercase) {
+                Owned(label.to_lowercase())
+            } else {
+                label
+            };
         };
 
+        if let Some(default) = &self.default {
+            if value.is_empty() || value == "-" {
+                set_label(Borrowed(default));
+                return Ok(());
+            }
+        }
+
         match &self.func {
             ExtractorFunc::User => {
                 if value != "-" {
@@ -72,19 +651,148 @@ impl Extractor {
                     set_label(Borrowed("no"))
                 }
             }
-            ExtractorFunc::Status => {
-                set_label(Owned(value.parse().map_err(|_| ParseError("Invalid status code".to_owned()))?))
+            ExtractorFunc::Status { aliases } => {
+                measurements.status = value.parse().ok();
+                match aliases.get(value) {
+                    Some(alias) => set_label(Borrowed(alias)),
+                    None => set_label(Owned(value.parse().map_err(|_| ParseError::Invalid("Invalid status code".to_owned()))?)),
+                }
             }
             ExtractorFunc::Duration => {
-                let seconds: f32 = value.parse().map_err(|_| ParseError("Invalid duration".to_owned()))?;
-                *duration = Some(seconds);
+                let seconds: f32 = value.parse().map_err(|_| ParseError::Invalid("Invalid duration".to_owned()))?;
+                measurements.duration = Some(seconds);
+            }
+            ExtractorFunc::UpstreamDuration { policy } => {
+                measurements.upstream_duration = parse_upstream_times(value, *policy)?;
+            }
+            ExtractorFunc::UpstreamHeaderTime { policy } => {
+                measurements.upstream_header_time = parse_upstream_times(value, *policy)?;
             }
-            ExtractorFunc::Host => {
+            ExtractorFunc::UpstreamConnectTime { policy } => {
+                measurements.upstream_connect_time = parse_upstream_times(value, *policy)?;
+            }
+            ExtractorFunc::Host | ExtractorFunc::ServerName => {
                 set_label(Borrowed(value));
             }
+            ExtractorFunc::ServerPort => {
+                if value.parse::<u16>().is_ok() {
+                    set_label(Borrowed(value));
+                } else {
+                    set_label(Borrowed("unk"));
+                }
+            }
+            ExtractorFunc::Scheme => {
+                if value.is_empty() || value == "-" {
+                    set_label(Borrowed("unk"));
+                } else {
+                    set_label(Borrowed(value));
+                }
+            }
             ExtractorFunc::ResponseBodySize => {
-                let size = value.parse().map_err(|_| ParseError("Invalid number of bytes".to_owned()))?;
-                *response_body_size = Some(size);
+                let size = value.parse().map_err(|_| ParseError::Invalid("Invalid number of bytes".to_owned()))?;
+                measurements.response_body_size = Some(size);
+            }
+            ExtractorFunc::RequestLength => {
+                let size = value.parse().map_err(|_| ParseError::Invalid("Invalid number of bytes".to_owned()))?;
+                measurements.request_length = Some(size);
+            }
+            ExtractorFunc::Country => {
+                if value.is_empty() || value == "-" {
+                    set_label(Borrowed("unknown"));
+                } else {
+                    set_label(Borrowed(value));
+                }
+            }
+            ExtractorFunc::TemplatedPath { from_request } => {
+                let path = if *from_request {
+                    value.split(' ').nth(1).unwrap_or("")
+                } else {
+                    value
+                };
+                if path.starts_with('/') {
+                    set_label(Owned(template_path(path)));
+                } else {
+                    set_label(Borrowed("other"));
+                }
+            }
+            ExtractorFunc::BotDetect { patterns, empty_is_bot } => {
+                let is_bot = if value.is_empty() || value == "-" {
+                    *empty_is_bot
+                } else {
+                    let value = value.to_lowercase();
+                    patterns.iter().any(|p| value.contains(p.as_str()))
+                };
+                set_label(Borrowed(if is_bot { "yes" } else { "no" }));
+            }
+            ExtractorFunc::Pipeline { steps } => {
+                let mut value = Borrowed(value);
+                for step in steps {
+                    value = step.apply(value);
+                }
+                set_label(value);
+            }
+            ExtractorFunc::Hash => {
+                if value.is_empty() || value == "-" {
+                    set_label(Borrowed("none"));
+                } else {
+                    set_label(Owned(short_hash(value, 8)));
+                }
+            }
+            ExtractorFunc::Keepalive => {
+                let count: u64 = value.parse().map_err(|_| ParseError::Invalid("Invalid number of requests".to_owned()))?;
+                set_label(Borrowed(if count > 1 { "yes" } else { "no" }));
+            }
+            ExtractorFunc::SizeClass { thresholds } => {
+                let size: u64 = value.parse().map_err(|_| ParseError::Invalid("Invalid number of bytes".to_owned()))?;
+                let class = thresholds.iter().position(|&t| size <= t).unwrap_or(thresholds.len());
+                set_label(Borrowed(SIZE_CLASSES[class]));
+            }
+            ExtractorFunc::Map { values } => {
+                match values.get(value) {
+                    Some(v) => set_label(Borrowed(v)),
+                    None => set_label(Borrowed("other")),
+                }
+            }
+            ExtractorFunc::Split { delimiter, index } => {
+                set_label(Borrowed(value.split(delimiter.as_str()).nth(*index).unwrap_or("unk")));
+            }
+            ExtractorFunc::Timestamp { format } => {
+                measurements.timestamp = format.parse(value);
+            }
+            ExtractorFunc::ConnectionRequests => {
+                let count = value.parse().map_err(|_| ParseError::Invalid("Invalid number of requests".to_owned()))?;
+                measurements.connection_requests = Some(count);
+            }
+            ExtractorFunc::RequestId => {
+                if !value.is_empty() && value != "-" {
+                    measurements.request_id = Some(value.to_owned());
+                }
+            }
+            ExtractorFunc::ClientHash => {
+                measurements.client_hash = Some(hyperloglog::hash(strip_port(value)));
+            }
+            ExtractorFunc::ResponseHeader { header } => {
+                if !value.is_empty() && value != "-" {
+                    measurements.response_headers.push((header.clone(), value.to_owned()));
+                }
+            }
+            ExtractorFunc::CacheStatus => {
+                measurements.cache_status = CACHE_STATUSES.iter().find(|&&s| s == value).copied();
+            }
+            ExtractorFunc::HttpVersion { from_request } => {
+                let protocol = if *from_request {
+                    value.rsplit(' ').next().unwrap_or("")
+                } else {
+                    value
+                };
+                measurements.http_version = http_version(protocol);
+            }
+            #[cfg(feature = "geoip")]
+            ExtractorFunc::ClientCountry { geoip } => {
+                match geoip.country(value) {
+                    Some(country) => set_label(Owned(country)),
+                    None => set_label(Borrowed("unknown")),
+                }
             }
             #[cfg(feature = "re")]
             ExtractorFunc::Regex { ref target, ref regex } => {
@@ -93,24 +801,491 @@ impl Extractor {
             }
         }
 
-        Ok(())
+        Ok(())
+    }
+}
+
+/// Sets a label from two fields, once the whole line is parsed.
+pub struct Combination {
+    pub(crate) label_index: usize,
+    pub(crate) fields: (usize, usize),
+    pub(crate) func: CombinationFunc,
+}
+
+pub enum CombinationFunc {
+    /// "yes" if the fields differ, ignoring case, "no" otherwise. Missing
+    /// values (empty or "-") don't count as a mismatch, e.g. there is no SNI
+    /// for plain HTTP requests.
+    Mismatch,
+    /// "cache", "origin", or "error" from `$upstream_cache_status` and
+    /// `$status`
+    ServedFrom,
+}
+
+impl Combination {
+    pub fn combine(&self, values: &[Cow<'_, str>], label_values: &mut [Cow<'_, str>]) {
+        let (a, b) = (&values[self.fields.0], &values[self.fields.1]);
+        let label = match self.func {
+            CombinationFunc::Mismatch => {
+                let missing = |v: &str| v.is_empty() || v == "-";
+                if !missing(a) && !missing(b) && !a.eq_ignore_ascii_case(b) { "yes" } else { "no" }
+            }
+            CombinationFunc::ServedFrom => {
+                if b.starts_with('5') {
+                    "error"
+                } else {
+                    if from_cache(a) { "cache" } else { "origin" }
+                }
+            }
+        };
+        label_values[self.label_index] = Borrowed(label);
+    }
+}
+
+/// A label made of the values of two other labels, e.g. `200_GET`.
+pub struct Composite {
+    pub(crate) label_index: usize,
+    pub(crate) parts: (usize, usize),
+    pub(crate) separator: String,
+}
+
+impl Composite {
+    pub fn compose(&self, label_values: &mut [Cow<'_, str>]) {
+        let value = format!("{}{}{}", label_values[self.parts.0], self.separator, label_values[self.parts.1]);
+        label_values[self.label_index] = Owned(value);
+    }
+}
+
+/// The longest line read from a TCP connection without `--max-buffer`.
+const MAX_CONNECTION_LINE: usize = 1024 * 1024;
+
+/// Skip the unread lines of a file from `offset`, up to the end of the line
+/// that has the byte before `to`.
+///
+/// Returns the number of lines skipped and the offset after them. A line that
+/// is not terminated yet is not skipped.
+fn skip_lines(file: &mut File, offset: u64, to: u64, terminator: u8) -> std::io::Result<(u64, u64)> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = [0; 8192];
+    let mut pos = offset;
+    let mut lines = 0;
+    let mut last_end = offset;
+    loop {
+        let res = file.read(&mut chunk)?;
+        if res == 0 {
+            return Ok((lines, last_end));
+        }
+        for (i, &b) in chunk[..res].iter().enumerate() {
+            if b == terminator {
+                lines += 1;
+                let end = pos + i as u64 + 1;
+                last_end = end;
+                if end >= to {
+                    return Ok((lines, end));
+                }
+            }
+        }
+        pos += res as u64;
+    }
+}
+
+/// Read the lines sent on a TCP connection, sending the complete ones to
+/// the processor. A last line that is not terminated is sent when the
+/// connection closes.
+///
+/// A line longer than `max_line` is dropped and counted as an error, so a
+/// sender can't make us buffer without limit.
+fn read_connection(mut stream: TcpStream, terminator: u8, max_line: usize, data: Arc<Mutex<LogData>>, tx: SyncSender<Vec<u8>>) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown".to_owned());
+    debug!("Connection from {}", peer);
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 8192];
+    // Whether the rest of a line that was too long is being skipped
+    let mut skipping = false;
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(res) => {
+                let mut received = &chunk[..res];
+                if skipping {
+                    match received.iter().position(|&b| b == terminator) {
+                        Some(end) => {
+                            skipping = false;
+                            received = &received[end + 1..];
+                        }
+                        None => continue,
+                    }
+                }
+                buffer.extend_from_slice(received);
+                if let Some(end) = buffer.iter().rposition(|&b| b == terminator) {
+                    let rest = buffer.split_off(end + 1);
+                    if tx.send(std::mem::replace(&mut buffer, rest)).is_err() {
+                        return;
+                    }
+                }
+                if buffer.len() > max_line {
+                    warn!("Line from {} is longer than {} bytes, dropping it", peer, max_line);
+                    data.lock().unwrap().error_count.inc();
+                    buffer.clear();
+                    skipping = true;
+                }
+            }
+            Err(e) => {
+                warn!("Error reading from {}: {}", peer, e);
+                break;
+            }
+        }
+    }
+    if !buffer.is_empty() && !skipping {
+        buffer.push(terminator);
+        tx.send(buffer).ok();
+    }
+    debug!("Connection from {} closed", peer);
+}
+
+/// Settings for how lines are processed.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessorOptions {
+    /// The maximum number of distinct label combinations, after which lines
+    /// with new combinations get counted in a single "overflow" series
+    pub max_series: Option<usize>,
+    /// Check the file for new data at this interval, even without events
+    pub poll_interval: Option<Duration>,
+    /// Re-establish the watch if there are no events or new data for this long
+    pub watchdog: Option<Duration>,
+    /// Save the position in the log to this file, to resume after a restart
+    pub offset_file: Option<PathBuf>,
+    /// The character encoding of the log, if not UTF-8
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+    /// Decode percent-encoded characters in `$request`
+    pub url_decode: bool,
+    /// Lowercase the label values set by extractors
+    pub lowercase_labels: bool,
+    /// The byte that ends records, if not "\n"
+    pub line_terminator: Option<u8>,
+    /// The maximum size of the data read but not processed yet, over which
+    /// the oldest lines are dropped
+    pub max_buffer: Option<usize>,
+    /// Update the metrics once per series for all the lines read at once,
+    /// holding the lock for the whole batch
+    pub batch_lines: bool,
+    /// Drop lines identical to one of this many previous lines
+    pub dedup_window: Option<usize>,
+    /// Skip the lines that are empty or only whitespace, rather than
+    /// counting them as errors
+    pub skip_blank_lines: bool,
+    /// Skip the lines that start with this, rather than counting them as
+    /// errors
+    pub comment_prefix: Option<String>,
+    /// Parse the lines read at once on this many threads
+    pub workers: Option<usize>,
+    /// Set `log_idle` if no lines are processed for this long
+    pub idle_timeout: Option<Duration>,
+}
+
+impl ProcessorOptions {
+    /// Whether the line is blank or a comment, and not a request.
+    pub(crate) fn is_skipped(&self, line: &str) -> bool {
+        if self.skip_blank_lines && line.trim().is_empty() {
+            return true;
+        }
+        match &self.comment_prefix {
+            Some(prefix) => line.starts_with(prefix.as_str()),
+            None => false,
+        }
+    }
+
+    /// Remove the CR of a line ending in CRLF, as written on Windows. Other
+    /// terminators leave the line alone.
+    pub(crate) fn strip_cr<'a>(&self, line: &'a [u8]) -> &'a [u8] {
+        match line {
+            [rest @ .., b'\r'] if self.line_terminator.unwrap_or(b'\n') == b'\n' => rest,
+            _ => line,
+        }
+    }
+
+    /// Decode a line from the log's encoding.
+    pub(crate) fn decode_line<'a>(&self, bytes: &'a [u8]) -> std::io::Result<Cow<'a, str>> {
+        match self.encoding {
+            Some(encoding) => Ok(encoding.decode_without_bom_handling(bytes).0),
+            None => match std::str::from_utf8(bytes) {
+                Ok(line) => Ok(Borrowed(line)),
+                Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            },
+        }
+    }
+}
+
+/// Start reading an existing log from the lines of the last `seconds`,
+/// found from their timestamps.
+pub(crate) struct Warmup {
+    pub(crate) field_index: usize,
+    pub(crate) format: TimestampFormat,
+    pub(crate) seconds: f64,
+}
+
+/// Process the lines at the pace of their timestamps, sped up by `speed`,
+/// as if the log was being written now.
+pub(crate) struct Replay {
+    pub(crate) field_index: usize,
+    pub(crate) format: TimestampFormat,
+    pub(crate) speed: f64,
+    /// The timestamp of the first line, and when it was processed, as a
+    /// Unix timestamp and as an instant
+    pub(crate) start: Mutex<Option<(f64, f64, Instant)>>,
+}
+
+impl Replay {
+    /// The time at which a line with this timestamp is replayed.
+    fn shift(&self, timestamp: f64) -> f64 {
+        match *self.start.lock().unwrap() {
+            Some((first, now, _)) => now + (timestamp - first) / self.speed,
+            None => timestamp,
+        }
+    }
+}
+
+/// How much of the log to read at once when looking for the start of the
+/// warm-up.
+const WARMUP_CHUNK: u64 = 64 * 1024;
+
+/// What happened to a line, for `/debug/last`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugLine {
+    pub line: String,
+    /// "accepted", "filtered", "duplicate", "out_of_time_window", or "error"
+    pub status: &'static str,
+    /// The label values, if the line was accepted
+    pub labels: Vec<(String, String)>,
+    pub error: Option<String>,
+}
+
+/// The last lines that were processed and their results.
+#[derive(Debug)]
+pub struct DebugLines {
+    pub(crate) lines: VecDeque<DebugLine>,
+    capacity: usize,
+}
+
+impl DebugLines {
+    pub fn new(capacity: usize) -> DebugLines {
+        DebugLines {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, line: DebugLine) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// The lines as a JSON array, oldest first.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"line\":");
+            json_string(&mut out, &line.line);
+            out.push_str(",\"status\":");
+            json_string(&mut out, line.status);
+            out.push_str(",\"labels\":{");
+            for (j, (label, value)) in line.labels.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                json_string(&mut out, label);
+                out.push(':');
+                json_string(&mut out, value);
+            }
+            out.push_str("},\"error\":");
+            match &line.error {
+                Some(e) => json_string(&mut out, e),
+                None => out.push_str("null"),
+            }
+            out.push('}');
+        }
+        out.push(']');
+        out
+    }
+}
+
+/// The hashes of the last distinct lines, to drop the repeated ones.
+pub(crate) struct RecentLines {
+    hashes: VecDeque<u64>,
+    set: HashSet<u64>,
+    capacity: usize,
+}
+
+impl RecentLines {
+    pub(crate) fn new(capacity: usize) -> RecentLines {
+        RecentLines {
+            hashes: VecDeque::with_capacity(capacity),
+            set: HashSet::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Remember a line, returning false if it is one of the recent ones.
+    fn insert(&mut self, line: &str) -> bool {
+        let hash = hyperloglog::hash(line);
+        if self.set.contains(&hash) {
+            return false;
+        }
+        if self.hashes.len() >= self.capacity {
+            if let Some(oldest) = self.hashes.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        self.hashes.push_back(hash);
+        self.set.insert(hash);
+        true
+    }
+}
+
+/// The measurements of lines waiting to be recorded, by series.
+#[derive(Default)]
+pub(crate) struct Batch {
+    series: HashMap<Vec<String>, Vec<Measurements>>,
+}
+
+/// What was found in a line, before it is recorded.
+///
+/// It only depends on the line, so it can be computed on any thread.
+pub(crate) struct ExtractedLine {
+    /// The parsed line as JSON, if they are written out
+    json: Option<String>,
+    status: Result<LineStatus, ParseError>,
+    label_values: Vec<String>,
+    measurements: Measurements,
+}
+
+/// Lines sent to a worker, with their position in the batch, and where to
+/// send them back once extracted.
+type Job = (usize, Vec<String>, Sender<(usize, Vec<(String, ExtractedLine)>)>);
+
+/// Threads parsing lines and extracting their labels, for `--workers`.
+///
+/// They don't keep the processor alive, and stop when it is dropped.
+pub(crate) struct WorkerPool {
+    jobs: Sender<Job>,
+    count: usize,
+}
+
+impl WorkerPool {
+    fn start(processor: &Arc<LogProcessor>, count: usize) -> WorkerPool {
+        let (jobs, rx) = std::sync::mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..count {
+            let processor: Weak<LogProcessor> = Arc::downgrade(processor);
+            let rx = rx.clone();
+            std::thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                let (index, lines, reply) = match job {
+                    Ok(job) => job,
+                    Err(RecvError) => return,
+                };
+                let processor = match processor.upgrade() {
+                    Some(p) => p,
+                    None => return,
+                };
+                let extracted = lines.into_iter().map(|line| {
+                    let extracted = processor.extract_line(&line);
+                    (line, extracted)
+                }).collect();
+                reply.send((index, extracted)).ok();
+            });
+        }
+        WorkerPool { jobs, count }
+    }
+
+    /// Extract the lines on the threads, returning them in their order.
+    fn extract(&self, mut lines: Vec<String>) -> Vec<(String, ExtractedLine)> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let chunk_size = (lines.len() + self.count - 1) / self.count;
+        let mut chunks = 0;
+        while !lines.is_empty() {
+            let chunk = lines.drain(..chunk_size.min(lines.len())).collect();
+            self.jobs.send((chunks, chunk, tx.clone())).unwrap();
+            chunks += 1;
+        }
+        drop(tx);
+        let mut results: Vec<_> = rx.iter().collect();
+        assert_eq!(results.len(), chunks, "worker thread failed");
+        results.sort_by_key(|&(index, _)| index);
+        results.into_iter().flat_map(|(_, extracted)| extracted).collect()
     }
 }
 
 pub struct LogProcessor {
     pub(crate) data: Arc<Mutex<LogData>>,
+    pub(crate) options: ProcessorOptions,
+    pub(crate) seen_series: Mutex<HashSet<Vec<String>>>,
+    /// The distinct values seen for each label
+    pub(crate) seen_values: Mutex<Vec<HashSet<String>>>,
+    /// The `generation` of the data when the series above were seen
+    pub(crate) generation: AtomicU64,
     pub(crate) filename: PathBuf,
     pub(crate) log_parser: LogParser,
     pub(crate) labels: Vec<String>,
     pub(crate) filters: Vec<Filter>,
     pub(crate) extractors: Vec<Extractor>,
+    /// Labels computed from several fields, once the line is parsed
+    pub(crate) combinations: Vec<Combination>,
+    /// Labels made from other labels, once those are set
+    pub(crate) composites: Vec<Composite>,
+    /// Where to record the time spent in each extractor, if profiling
+    pub(crate) extractor_duration: Option<HistogramVec>,
+    /// Only observe the durations of requests with these statuses, if any
+    pub(crate) duration_statuses: Vec<String>,
+    /// The index of the `path` label, if its trailing slash is stripped
+    pub(crate) path_slash_label: Option<usize>,
+    /// Gets the current time, as a Unix timestamp
+    pub(crate) clock: fn() -> f64,
+    /// Where to write the parsed lines as JSON, if anywhere
+    pub(crate) json_output: Option<Mutex<Box<dyn Write + Send>>>,
+    /// The last lines, if repeated lines are dropped
+    pub(crate) recent_lines: Option<Mutex<RecentLines>>,
+    pub(crate) warmup: Option<Warmup>,
+    pub(crate) replay: Option<Replay>,
+    /// Read the lines sent to this socket instead of the file
+    pub(crate) tcp_listener: Option<TcpListener>,
+    /// The threads extracting the lines, if started
+    pub(crate) workers: Mutex<Option<WorkerPool>>,
+    /// When lines were last processed, for `--idle-timeout`
+    pub(crate) last_line: Mutex<Instant>,
+    #[cfg(feature = "statsd")]
+    pub(crate) statsd: Option<StatsdClient>,
 }
 
 impl LogProcessor {
     pub fn start_thread(self) {
+        let processor = Arc::new(self);
+        LogProcessor::start_workers(&processor);
+        if let Some(timeout) = processor.options.idle_timeout {
+            // Like the workers, this doesn't keep the processor alive
+            let processor: Weak<LogProcessor> = Arc::downgrade(&processor);
+            std::thread::spawn(move || {
+                loop {
+                    std::thread::sleep(IDLE_CHECK_INTERVAL.min(timeout));
+                    match processor.upgrade() {
+                        Some(processor) => processor.check_idle(),
+                        None => return,
+                    }
+                }
+            });
+        }
         std::thread::spawn(move || {
             loop {
-                match self.watch_log() {
+                match processor.watch_log() {
                     Ok(()) => {}
                     Err(e) => {
                         eprintln!("{}", e);
@@ -122,14 +1297,58 @@ impl LogProcessor {
         });
     }
 
-    fn watch_log(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Start the threads extracting the lines, if there should be some.
+    pub(crate) fn start_workers(processor: &Arc<LogProcessor>) {
+        if let Some(count) = processor.options.workers {
+            *processor.workers.lock().unwrap() = Some(WorkerPool::start(processor, count));
+        }
+    }
+
+    /// Set `log_idle` if no lines were processed for the idle timeout.
+    pub(crate) fn check_idle(&self) {
+        if let Some(timeout) = self.options.idle_timeout {
+            // Holding the lock, so lines can't be processed in between
+            let data = self.data.lock().unwrap();
+            if let Some(gauge) = &data.idle {
+                let idle = self.last_line.lock().unwrap().elapsed() >= timeout;
+                gauge.set(if idle { 1 } else { 0 });
+            }
+        }
+    }
+
+    /// Remember that lines were just processed, and clear `log_idle`.
+    fn mark_active(&self, data: &LogData) {
+        if let Some(idle) = &data.idle {
+            *self.last_line.lock().unwrap() = Instant::now();
+            idle.set(0);
+        }
+    }
+
+    pub(crate) fn watch_log(&self) -> Result<(), Box<dyn std::error::Error>> {
         let data: &Mutex<LogData> = &self.data;
 
+        if let Some(listener) = &self.tcp_listener {
+            return self.read_tcp(listener);
+        }
+
+        if is_fifo(&self.filename) {
+            return self.read_fifo();
+        }
+
+        if is_gzip(&self.filename) {
+            self.read_gzip()?;
+            // The file won't change, keep the metrics without watching it
+            loop {
+                std::thread::park();
+            }
+        }
+
         let mut file = match std::fs::OpenOptions::new().read(true).open(&self.filename) {
             Ok(f) => f,
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     info!("File is missing, retrying...");
+                    data.lock().unwrap().file_present.set(0);
                     return Ok(());
                 } else {
                     return Err(e.into());
@@ -137,33 +1356,271 @@ impl LogProcessor {
             }
         };
 
+        data.lock().unwrap().file_present.set(1);
+
+        let (_watcher, rx) = self.watch()?;
+        let offset = self.start_offset(&mut file)?;
+
+        {
+            let mut data = data.lock().unwrap();
+            data.active = true;
+            data.watch_active.set(1);
+        }
+        info!("Watch established");
+
+        self.follow_log(&mut file, offset, &rx)
+    }
+
+    /// Watch the file for changes. The watcher needs to be kept for the
+    /// events to be sent.
+    fn watch(&self) -> notify::Result<(RecommendedWatcher, Receiver<notify::RawEvent>)> {
         let (tx, rx) = std::sync::mpsc::channel();
         let mut watcher: RecommendedWatcher = RecommendedWatcher::new_raw(tx)?;
         watcher.watch(&self.filename, notify::RecursiveMode::NonRecursive)?;
-        let mut offset = file.seek(SeekFrom::End(0))?;
+        Ok((watcher, rx))
+    }
 
-        data.lock().unwrap().active = true;
-        info!("Watch established");
+    /// Read a named pipe as a stream, opening it again each time the writer
+    /// closes it.
+    fn read_fifo(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let data: &Mutex<LogData> = &self.data;
+
+        loop {
+            // This blocks until there is a writer
+            let mut file = File::open(&self.filename)?;
+            {
+                let mut data = data.lock().unwrap();
+                data.file_present.set(1);
+                data.active = true;
+                data.watch_active.set(1);
+            }
+            info!("Reading from FIFO");
+
+            self.follow_stream(&mut file)?;
+
+            info!("Writer closed the FIFO, reopening");
+            let mut data = data.lock().unwrap();
+            data.active = false;
+            data.watch_active.set(0);
+        }
+    }
+
+    /// Accept connections on a TCP socket and read the lines they send.
+    ///
+    /// Each connection is read on its own thread, which only passes on
+    /// complete lines, so the lines of concurrent senders don't get mixed.
+    fn read_tcp(&self, listener: &TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = listener.try_clone()?;
+        let terminator = self.options.line_terminator.unwrap_or(b'\n');
+        let max_line = self.options.max_buffer.unwrap_or(MAX_CONNECTION_LINE);
+        let data = self.data.clone();
+        // Bounded, so the senders wait if we can't keep up
+        let (tx, rx) = std::sync::mpsc::sync_channel(16);
+        info!("Listening for lines on {}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = tx.clone();
+                        let data = data.clone();
+                        std::thread::spawn(move || read_connection(stream, terminator, max_line, data, tx));
+                    }
+                    Err(e) => warn!("Error accepting connection: {}", e),
+                }
+            }
+        });
+
+        {
+            let mut data = self.data.lock().unwrap();
+            data.active = true;
+            data.watch_active.set(1);
+        }
+        for mut lines in rx {
+            self.process_lines(&mut lines)?;
+        }
+        Ok(())
+    }
+
+    /// Read a whole compressed file once, e.g. a rotated log for a backfill.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn read_gzip(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let file = File::open(&self.filename)?;
+        {
+            let mut data = self.data.lock().unwrap();
+            data.file_present.set(1);
+            data.active = true;
+        }
+        info!("Reading compressed file");
+        self.follow_stream(&mut flate2::read::MultiGzDecoder::new(std::io::BufReader::new(file)))?;
+        info!("Finished reading compressed file");
+        Ok(())
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    pub(crate) fn read_gzip(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Err(format!("{:?} is compressed, but support for gzip files was not compiled in", self.filename).into())
+    }
 
-        let mut buffer = String::new();
+    /// Read lines until the end of the stream.
+    ///
+    /// A last line that is not terminated is still counted, like the last line
+    /// of a connection.
+    pub(crate) fn follow_stream(&self, reader: &mut impl Read) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 8192];
+        loop {
+            let res = reader.read(&mut chunk)?;
+            if res == 0 {
+                if !buffer.is_empty() {
+                    buffer.push(self.options.line_terminator.unwrap_or(b'\n'));
+                    self.process_lines(&mut buffer)?;
+                }
+                return Ok(());
+            }
+            buffer.extend_from_slice(&chunk[..res]);
+            self.process_lines(&mut buffer)?;
+        }
+    }
+
+    /// Find where to start reading, either where we left off, at the start
+    /// of the warm-up, or at the end.
+    pub(crate) fn start_offset(&self, file: &mut File) -> std::io::Result<u64> {
+        let size = file.seek(SeekFrom::End(0))?;
+        if let Some(path) = &self.options.offset_file {
+            if let (Some(saved), Some(identity)) = (read_offset(path)?, FileIdentity::of(file)?) {
+                if saved.identity == identity && saved.offset <= size {
+                    info!("Resuming from saved offset {}", saved.offset);
+                    return Ok(saved.offset);
+                }
+            }
+        }
+        if let Some(warmup) = &self.warmup {
+            let offset = self.warmup_offset(file, size, warmup)?;
+            info!("Warming up from offset {}, {} bytes before the end", offset, size - offset);
+            return Ok(offset);
+        }
+        Ok(size)
+    }
+
+    /// Go backward from the end of the file until a line older than the
+    /// warm-up, and return the offset of the line after it.
+    ///
+    /// Lines without a valid timestamp are skipped over.
+    fn warmup_offset(&self, file: &mut File, size: u64, warmup: &Warmup) -> std::io::Result<u64> {
+        let cutoff = (self.clock)() - warmup.seconds;
+        let terminator = self.options.line_terminator.unwrap_or(b'\n');
+
+        // The data from `pos` to `end`, where a line that is newer than the
+        // cutoff starts
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut pos = size;
+        let mut end = size;
+        // Whether the buffer ends with the terminator of the line before `end`
+        let mut terminated = false;
+        loop {
+            let content_end = if terminated { buffer.len() - 1 } else { buffer.len() };
+            let line_start = match buffer[..content_end].iter().rposition(|&b| b == terminator) {
+                Some(i) => i + 1,
+                None if pos == 0 => 0,
+                None => {
+                    // Read more of the file
+                    let read_from = pos.saturating_sub(WARMUP_CHUNK);
+                    let mut chunk = vec![0; (pos - read_from) as usize];
+                    file.seek(SeekFrom::Start(read_from))?;
+                    file.read_exact(&mut chunk)?;
+                    chunk.extend_from_slice(&buffer);
+                    buffer = chunk;
+                    pos = read_from;
+                    continue;
+                }
+            };
+
+            let line = self.options.strip_cr(&buffer[line_start..content_end]);
+            if let Some(timestamp) = self.line_timestamp(line, warmup) {
+                if timestamp < cutoff {
+                    return Ok(end);
+                }
+            }
+
+            end = pos + line_start as u64;
+            buffer.truncate(line_start);
+            terminated = true;
+            if end == 0 {
+                return Ok(0);
+            }
+        }
+    }
+
+    fn line_timestamp(&self, line: &[u8], warmup: &Warmup) -> Option<f64> {
+        let line = self.options.decode_line(line).ok()?;
+        let values = self.log_parser.parse(&line).ok()?;
+        warmup.format.parse(values.get(warmup.field_index)?.value)
+    }
+
+    /// Read new lines from the file as events are received.
+    pub(crate) fn follow_log(&self, file: &mut File, mut offset: u64, rx: &Receiver<notify::RawEvent>) -> Result<(), Box<dyn std::error::Error>> {
+        let data: &Mutex<LogData> = &self.data;
+
+        let mut buffer = Vec::new();
+
+        let identity = match self.options.offset_file {
+            Some(_) => FileIdentity::of(file)?,
+            None => None,
+        };
+        let mut saved_offset = offset;
+        let mut last_save: Option<Instant> = None;
+        // What was left to read after the last iteration, to tell whether we
+        // are falling behind
+        let mut last_unread = u64::MAX;
+
+        let mut last_activity = Instant::now();
+        // The watch set up again by the watchdog, replacing `rx`
+        let mut rewatched: Option<(RecommendedWatcher, Receiver<notify::RawEvent>)> = None;
+
+        // Read what is already after the offset before waiting
+        let mut catch_up = true;
 
         // Wait for events
         loop {
-            let event: notify::RawEvent = rx.recv()?;
+            // Wake up at the poll interval, or in time for the watchdog
+            let watchdog_remaining = self.options.watchdog.map(|w| w.checked_sub(last_activity.elapsed()).unwrap_or_default());
+            let timeout = match (self.options.poll_interval, watchdog_remaining) {
+                (Some(interval), Some(remaining)) => Some(interval.min(remaining)),
+                (interval, remaining) => interval.or(remaining),
+            };
+            let rx = rewatched.as_ref().map_or(rx, |(_, rx)| rx);
+            let event: Option<notify::RawEvent> = match timeout {
+                _ if catch_up => {
+                    catch_up = false;
+                    None
+                }
+                Some(timeout) => match rx.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Err(RecvError.into()),
+                },
+                None => Some(rx.recv()?),
+            };
 
             debug!("event: {:?}", event);
+            if event.is_some() {
+                last_activity = Instant::now();
+            }
 
-            let reopen = match event.op {
-                Ok(op) if !(notify::op::Op::WRITE | notify::op::Op::CLOSE_WRITE).contains(op) => {
+            let reopen = match event.map(|e| e.op) {
+                Some(Ok(op)) if !(notify::op::Op::WRITE | notify::op::Op::CLOSE_WRITE).contains(op) => {
                     info!("Restarting watch");
                     true
                 }
-                Err(e) => return Err(e.into()),
+                Some(Err(e)) => return Err(e.into()),
                 _ => false,
             };
 
             if reopen {
-                data.lock().unwrap().active = false;
+                let mut data = data.lock().unwrap();
+                data.active = false;
+                data.watch_active.set(0);
+                data.rotation_count.inc();
                 return Ok(());
             }
 
@@ -171,94 +1628,648 @@ impl LogProcessor {
             let size = file.seek(SeekFrom::End(0))?;
             if size < offset {
                 info!("Truncation detected ({} -> {})", offset, size);
+                data.lock().unwrap().truncation_count.inc();
                 offset = size;
             }
 
-            // Read
+            // Read, at most --max-buffer at a time
             file.seek(SeekFrom::Start(offset))?;
-            let res = file.read_to_string(&mut buffer)? as u64;
+            let res = match self.options.max_buffer {
+                Some(max_buffer) => {
+                    let res = (&mut *file).take(max_buffer as u64).read_to_end(&mut buffer)? as u64;
+                    if res == max_buffer as u64 {
+                        catch_up = true;
+                    }
+                    res
+                }
+                None => file.read_to_end(&mut buffer)? as u64,
+            };
             offset += res;
+            if res > 0 {
+                last_activity = Instant::now();
+            }
 
-            // Split into lines
-            let mut read_to = 0;
-            while let Some(ln) = buffer[read_to..].find('\n') {
-                let line = &buffer[read_to..read_to + ln];
-                debug!("line: {:?}", line);
-                read_to += ln + 1;
-
-                let data = data.lock().unwrap();
+            // Re-establish the watch if it seems to have died, reading on
+            // from the same offset so no line is lost
+            if let Some(watchdog) = self.options.watchdog {
+                if last_activity.elapsed() >= watchdog {
+                    warn!("No events or new data for {:?}, restarting watch", watchdog);
+                    rewatched = Some(self.watch()?);
+                    last_activity = Instant::now();
+                    // Events may have been missed if the file was replaced
+                    let current = File::open(&self.filename).and_then(|f| FileIdentity::of(&f));
+                    if !matches!(current, Ok(current) if current == FileIdentity::of(file)?) {
+                        info!("File was replaced, reopening");
+                        let mut data = data.lock().unwrap();
+                        data.active = false;
+                        data.watch_active.set(0);
+                        data.rotation_count.inc();
+                        return Ok(());
+                    }
+                    // Catch up on what was written while setting it up
+                    catch_up = true;
+                }
+            }
 
-                let mut label_values = vec![Borrowed("unk"); self.labels.len()];
-                let mut duration: Option<f32> = None;
-                let mut response_body_size: Option<u64> = None;
+            self.process_lines(&mut buffer)?;
 
-                match self.process_line(line, &mut label_values, &mut duration, &mut response_body_size) {
-                    Ok(true) => {}
-                    Ok(false) => continue,
-                    Err(e) => {
-                        warn!("{}", e);
-                        data.error_count.inc();
-                        continue;
+            // Drop the oldest lines if more than --max-buffer is waiting and
+            // that grew while processing, so a catch-up isn't dropped
+            let mut unread = file.seek(SeekFrom::End(0))?.saturating_sub(offset);
+            if let Some(max_buffer) = self.options.max_buffer {
+                if unread > max_buffer as u64 && unread > last_unread {
+                    let terminator = self.options.line_terminator.unwrap_or(b'\n');
+                    let (dropped, skip_to) = skip_lines(file, offset, offset + unread - max_buffer as u64, terminator)?;
+                    if dropped > 0 {
+                        warn!("Can't keep up with the log, dropping {} lines", dropped);
+                        data.lock().unwrap().dropped_lines_count.inc_by(dropped);
+                        // The partial line is the start of the first dropped line
+                        buffer.clear();
+                        offset = skip_to;
+                        unread = file.seek(SeekFrom::End(0))?.saturating_sub(offset);
                     }
-                };
+                }
+            }
+            last_unread = unread;
+            data.lock().unwrap().buffer_size.set((unread + buffer.len() as u64) as i64);
+
+            // Remember how far we got
+            if let (Some(path), Some(identity)) = (&self.options.offset_file, identity) {
+                let processed = offset - buffer.len() as u64;
+                if processed != saved_offset && last_save.map_or(true, |t| t.elapsed() >= OFFSET_SAVE_INTERVAL) {
+                    write_offset(path, &SavedOffset { identity, offset: processed })?;
+                    saved_offset = processed;
+                    last_save = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Decode the complete lines in the buffer and pass them to `f`, except
+    /// those that are skipped, then discard them from the buffer.
+    ///
+    /// Returns the number of bytes that were discarded.
+    fn take_lines(&self, buffer: &mut Vec<u8>, mut f: impl FnMut(Cow<str>)) -> std::io::Result<usize> {
+        let terminator = self.options.line_terminator.unwrap_or(b'\n');
+        let mut read_to = 0;
+        while let Some(ln) = buffer[read_to..].iter().position(|&b| b == terminator) {
+            let line = self.options.decode_line(self.options.strip_cr(&buffer[read_to..read_to + ln]))?;
+            read_to += ln + 1;
+            if self.options.is_skipped(&line) {
+                debug!("Skipping blank or comment line");
+                continue;
+            }
+            f(line);
+        }
+        buffer.drain(0..read_to);
+        Ok(read_to)
+    }
+
+    /// Handle the complete lines in the buffer, leaving the rest.
+    pub(crate) fn process_lines(&self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        if self.replay.is_none() {
+            if let Some(workers) = &*self.workers.lock().unwrap() {
+                return self.process_lines_on(workers, buffer);
+            }
+        }
+        // When batching, hold the lock and update each series once at the end,
+        // unless waiting between lines
+        let batch_data = if self.options.batch_lines && self.replay.is_none() { Some(self.data.lock().unwrap()) } else { None };
+        let mut batch = Batch::default();
+        let read_to = self.take_lines(buffer, |line| {
+            debug!("line: {:?}", line);
+            if let Some(replay) = &self.replay {
+                self.replay_wait(&line, replay);
+            }
+
+            match &batch_data {
+                Some(data) => self.add_line(data, &line, &mut batch),
+                None => {
+                    let data = self.data.lock().unwrap();
+                    self.handle_line(&data, &line);
+                }
+            }
+        })?;
+
+        let data = match batch_data {
+            Some(data) => {
+                self.record_batch(&data, batch);
+                data
+            }
+            None => self.data.lock().unwrap(),
+        };
+        if read_to > 0 {
+            self.mark_active(&data);
+        }
+        data.buffer_size.set(buffer.len() as i64);
+        Ok(())
+    }
+
+    /// Handle the complete lines in the buffer, extracting them on the worker
+    /// threads, then recording them in order.
+    fn process_lines_on(&self, workers: &WorkerPool, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        let mut lines = Vec::new();
+        let read_to = self.take_lines(buffer, |line| lines.push(line.into_owned()))?;
+
+        if self.recent_lines.is_some() {
+            let data = self.data.lock().unwrap();
+            lines.retain(|line| !self.is_duplicate(&data, line));
+        }
+        let extracted = workers.extract(lines);
+
+        let data = self.data.lock().unwrap();
+        let mut batch = Batch::default();
+        for (line, extracted) in extracted {
+            debug!("line: {:?}", line);
+            self.add_extracted(&data, &line, extracted, &mut batch);
+            if !self.options.batch_lines {
+                self.record_batch(&data, std::mem::take(&mut batch));
+            }
+        }
+        self.record_batch(&data, batch);
+        if read_to > 0 {
+            self.mark_active(&data);
+        }
+        data.buffer_size.set(buffer.len() as i64);
+        Ok(())
+    }
 
-                debug!("{}", line);
-                for (key, value) in self.labels.iter().zip(&label_values) {
-                    debug!("    {}: {}", key, value);
+    /// Wait until it is time to process the line, according to its timestamp
+    /// and that of the first line.
+    fn replay_wait(&self, line: &str, replay: &Replay) {
+        let timestamp = match self.log_parser.parse(line).ok().and_then(|values| replay.format.parse(values.get(replay.field_index)?.value)) {
+            Some(t) => t,
+            None => return,
+        };
+        let mut start = replay.start.lock().unwrap();
+        match *start {
+            None => *start = Some((timestamp, (self.clock)(), Instant::now())),
+            Some((first, _, started)) => {
+                let target = (timestamp - first) / replay.speed;
+                let elapsed = started.elapsed().as_secs_f64();
+                if target > elapsed {
+                    std::thread::sleep(Duration::from_secs_f64(target - elapsed));
                 }
+            }
+        }
+    }
+
+    /// Process a line and update the metrics with it.
+    pub(crate) fn handle_line(&self, data: &LogData, line: &str) {
+        let mut batch = Batch::default();
+        self.add_line(data, line, &mut batch);
+        self.record_batch(data, batch);
+    }
+
+    /// Process a line and add its measurements to the batch.
+    fn add_line(&self, data: &LogData, line: &str, batch: &mut Batch) {
+        if self.is_duplicate(data, line) {
+            return;
+        }
+        let extracted = self.extract_line(line);
+        self.add_extracted(data, line, extracted, batch);
+    }
+
+    /// Record what happened to a line, for `/debug/last`.
+    fn debug_line(&self, data: &LogData, line: &str, status: &'static str, label_values: &[String], error: Option<&ParseError>) {
+        if let Some(debug_lines) = &data.debug_lines {
+            debug_lines.lock().unwrap().push(DebugLine {
+                line: line.to_owned(),
+                status,
+                labels: self.labels.iter().cloned().zip(label_values.iter().cloned()).collect(),
+                error: error.map(|e| e.to_string()),
+            });
+        }
+    }
+
+    /// Whether the line is one of the recent ones, if those are dropped.
+    fn is_duplicate(&self, data: &LogData, line: &str) -> bool {
+        if let Some(recent_lines) = &self.recent_lines {
+            if !recent_lines.lock().unwrap().insert(line) {
+                debug!("Duplicate line");
+                data.duplicate_lines_count.inc();
+                self.debug_line(data, line, "duplicate", &[], None);
+                return true;
+            }
+        }
+        false
+    }
 
-                let label_refs: Vec<&str> = label_values.iter().map(|v| -> &str { &v }).collect();
+    /// Parse a line and extract its labels and measurements.
+    pub(crate) fn extract_line(&self, line: &str) -> ExtractedLine {
+        let mut extracted = ExtractedLine {
+            json: None,
+            status: Ok(LineStatus::Filtered),
+            label_values: Vec::new(),
+            measurements: Measurements::default(),
+        };
+        let values = match self.parse_line(line) {
+            Ok(v) => v,
+            Err(e) => {
+                extracted.status = Err(e);
+                return extracted;
+            }
+        };
+        if self.json_output.is_some() {
+            extracted.json = Some(to_json(self.log_parser.fields(), &values));
+        }
+        let mut label_values = vec![Borrowed("unk"); self.labels.len()];
+        extracted.status = self.process_line(&values, &mut label_values, &mut extracted.measurements);
+        extracted.label_values = label_values.into_iter().map(|v| v.into_owned()).collect();
+        extracted
+    }
+
+    /// Add the measurements of an extracted line to the batch.
+    fn add_extracted(&self, data: &LogData, line: &str, extracted: ExtractedLine, batch: &mut Batch) {
+        let count_error = |e: ParseError| {
+            warn!("{}", e);
+            data.error_count.inc();
+            if let ParseError::TrailingData(_) = e {
+                data.trailing_data_count.inc();
+            }
+            self.debug_line(data, line, "error", &[], Some(&e));
+        };
+
+        let ExtractedLine { json, status, mut label_values, mut measurements } = extracted;
+        if let (Some(output), Some(json)) = (&self.json_output, json) {
+            if let Err(e) = writeln!(output.lock().unwrap(), "{}", json) {
+                warn!("Error writing JSON: {}", e);
+            }
+        }
 
-                data.request_count.with_label_values(&label_refs).inc();
-                if let Some(d) = duration {
-                    data.request_duration.with_label_values(&label_refs).observe(d.into());
+        match status {
+            Ok(LineStatus::Accepted) => {
+                if let (Some(replay), Some(t)) = (&self.replay, measurements.timestamp) {
+                    measurements.timestamp = Some(replay.shift(t));
                 }
-                if let Some(s) = response_body_size {
-                    data.response_body_size.with_label_values(&label_refs).observe(s as f64);
+            }
+            Ok(LineStatus::Filtered) => return self.debug_line(data, line, "filtered", &[], None),
+            Ok(LineStatus::OutOfTimeWindow) => {
+                data.out_of_time_window_count.inc();
+                return self.debug_line(data, line, "out_of_time_window", &[], None);
+            }
+            Err(e) => return count_error(e),
+        };
+
+        debug!("{}", line);
+        for (key, value) in self.labels.iter().zip(&label_values) {
+            debug!("    {}: {}", key, value);
+        }
+
+        // The metrics were reset, start over
+        if self.generation.load(Ordering::Relaxed) != data.generation {
+            self.generation.store(data.generation, Ordering::Relaxed);
+            self.seen_series.lock().unwrap().clear();
+            for seen in self.seen_values.lock().unwrap().iter_mut() {
+                seen.clear();
+            }
+        }
+
+        if let Some(max_series) = self.options.max_series {
+            let mut seen_series = self.seen_series.lock().unwrap();
+            if !seen_series.contains(&label_values) {
+                if seen_series.len() >= max_series {
+                    debug!("Too many series, counting in overflow");
+                    data.cardinality_limit_reached.set(1);
+                    for value in label_values.iter_mut() {
+                        *value = "overflow".to_owned();
+                    }
+                } else {
+                    seen_series.insert(label_values.clone());
                 }
             }
+        }
+        self.debug_line(data, line, "accepted", &label_values, None);
+
+        let mut seen_values = self.seen_values.lock().unwrap();
+        for ((label, value), seen) in self.labels.iter().zip(&label_values).zip(seen_values.iter_mut()) {
+            if !seen.contains(value) {
+                seen.insert(value.clone());
+                data.label_cardinality.with_label_values(&[label]).set(seen.len() as i64);
+            }
+        }
+
+        batch.series.entry(label_values).or_default().push(measurements);
+    }
+
+    /// Update the metrics with the lines of a batch, one series at a time.
+    pub(crate) fn record_batch(&self, data: &LogData, batch: Batch) {
+        let now = (self.clock)();
+        let mut unique_clients = data.unique_clients_sketch.as_ref().map(|s| s.lock().unwrap());
+        let mut cache_statuses = data.cache_statuses.as_ref().map(|s| s.lock().unwrap());
+        let mut response_header_values = data.response_header_values.lock().unwrap();
+        for (series, lines) in batch.series {
+            let label_refs: Vec<&str> = series.iter().map(|v| -> &str { v }).collect();
+
+            data.request_count.with_label_values(&label_refs).inc_by(lines.len() as u64);
+            // Only create the children of series that get a measurement, so
+            // formats without these variables don't show empty histograms
+            let any_duration = lines.iter().any(|m| m.duration.is_some());
+            let any_upstream_duration = lines.iter().any(|m| m.upstream_duration.is_some());
+            let any_upstream_header_time = lines.iter().any(|m| m.upstream_header_time.is_some());
+            let any_upstream_connect_time = lines.iter().any(|m| m.upstream_connect_time.is_some());
+            let any_response_body_size = lines.iter().any(|m| m.response_body_size.is_some());
+            let any_request_length = lines.iter().any(|m| m.request_length.is_some());
+            let any_connection_requests = lines.iter().any(|m| m.connection_requests.is_some());
+            let request_duration = if any_duration { Some(data.request_duration.with_label_values(&label_refs)) } else { None };
+            let request_duration_sum = if any_duration { data.request_duration_sum.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let request_duration_count_counter = if any_duration { data.request_duration_count_counter.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let request_duration_sum_counter = if any_duration { data.request_duration_sum_counter.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let last_request_duration = if any_duration { data.last_request_duration.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let upstream_duration = if any_upstream_duration { data.upstream_duration.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let upstream_header_time = if any_upstream_header_time { data.upstream_header_time.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let upstream_connect_time = if any_upstream_connect_time { data.upstream_connect_time.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let response_body_size = if any_response_body_size { Some(data.response_body_size.with_label_values(&label_refs)) } else { None };
+            let last_response_body_size = if any_response_body_size { data.last_response_body_size.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let bytes_sent = if any_response_body_size { data.bytes_sent.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let bytes_received = if any_request_length { data.bytes_received.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
+            let requests_per_connection = if any_connection_requests { data.requests_per_connection.as_ref().map(|m| m.with_label_values(&label_refs)) } else { None };
 
-            // Discard the lines from the buffer
-            buffer.drain(0..read_to);
+            for measurements in lines {
+                #[cfg(feature = "statsd")]
+                {
+                    if let Some(statsd) = &self.statsd {
+                        statsd.record(&self.labels, &label_refs, &measurements);
+                    }
+                }
+                if let (Some(d), Some(request_duration)) = (measurements.duration, &request_duration) {
+                    request_duration.observe(d.into());
+                    if let Some(request_duration_sum) = &request_duration_sum {
+                        request_duration_sum.inc_by(d.into());
+                    }
+                    if let (Some(count), Some(sum)) = (&request_duration_count_counter, &request_duration_sum_counter) {
+                        count.inc();
+                        sum.inc_by(d.into());
+                    }
+                    if let Some(last_request_duration) = &last_request_duration {
+                        last_request_duration.set(d.into());
+                    }
+                    if let (Some(request_id), Some(exemplars)) = (measurements.request_id, &data.request_duration_exemplars) {
+                        exemplars.lock().unwrap().record(data.request_duration.buckets(&label_refs), &self.labels, &label_refs, Exemplar {
+                            request_id,
+                            value: d.into(),
+                            timestamp: now,
+                        });
+                    }
+                }
+                if let (Some(d), Some(upstream_duration)) = (measurements.upstream_duration, &upstream_duration) {
+                    upstream_duration.observe(d.into());
+                }
+                if let (Some(d), Some(upstream_header_time)) = (measurements.upstream_header_time, &upstream_header_time) {
+                    upstream_header_time.observe(d.into());
+                }
+                if let (Some(d), Some(upstream_connect_time)) = (measurements.upstream_connect_time, &upstream_connect_time) {
+                    upstream_connect_time.observe(d.into());
+                }
+                if let (Some(s), Some(response_body_size)) = (measurements.response_body_size, &response_body_size) {
+                    response_body_size.observe(s as f64);
+                    if let Some(last_response_body_size) = &last_response_body_size {
+                        last_response_body_size.set(s as f64);
+                    }
+                    if let Some(bytes_sent) = &bytes_sent {
+                        bytes_sent.inc_by(s);
+                    }
+                }
+                if let (Some(s), Some(bytes_received)) = (measurements.request_length, &bytes_received) {
+                    bytes_received.inc_by(s);
+                }
+                if let (Some(n), Some(requests_per_connection)) = (measurements.connection_requests, &requests_per_connection) {
+                    requests_per_connection.observe(n as f64);
+                }
+                if let (Some(t), Some(processing_lag)) = (measurements.timestamp, &data.processing_lag) {
+                    processing_lag.observe((now - t).max(0.0));
+                }
+                if let (Some(t), Some(last_timestamp)) = (measurements.timestamp, &data.last_timestamp) {
+                    if t > last_timestamp.get() {
+                        last_timestamp.set(t);
+                    }
+                }
+                if let (Some(h), Some(unique_clients)) = (measurements.client_hash, &mut unique_clients) {
+                    unique_clients.insert(h, now);
+                }
+                if let Some(response_header_count) = &data.response_header_count {
+                    for (header, value) in &measurements.response_headers {
+                        // Past the limit, new values are counted as "other"
+                        let seen = response_header_values.entry(header.clone()).or_default();
+                        let value = if seen.contains(value) {
+                            value.as_str()
+                        } else if seen.len() < RESPONSE_HEADER_VALUES {
+                            seen.insert(value.clone());
+                            value.as_str()
+                        } else {
+                            "other"
+                        };
+                        response_header_count.with_label_values(&[header, value]).inc();
+                    }
+                }
+                if let (Some(s), Some(cache_statuses)) = (measurements.cache_status, &mut cache_statuses) {
+                    *cache_statuses.entry(s).or_insert(0) += 1;
+                }
+                if let (Some(v), Some(http_version_requests)) = (measurements.http_version, &data.http_version_requests) {
+                    http_version_requests.with_label_values(&[v]).inc();
+                }
+            }
         }
     }
 
+    /// Split a line into the values of the fields, decoding them as needed.
+    pub fn parse_line<'a>(&'a self, line: &'a str) -> Result<Vec<Cow<'a, str>>, ParseError> {
+        let values = self.log_parser.parse(line)?;
+        Ok(values.into_iter().map(|LogValue { variable, value }| {
+            if self.options.url_decode && variable == "request" {
+                url_decode(value)
+            } else {
+                Borrowed(value)
+            }
+        }).collect())
+    }
+
     pub fn process_line<'a>(
         &'a self,
-        line: &'a str,
+        values: &'a [Cow<'a, str>],
         label_values: &mut [Cow<'a, str>],
-        duration: &mut Option<f32>,
-        response_body_size: &mut Option<u64>,
-    ) -> Result<bool, ParseError> {
-        let values = match self.log_parser.parse(line) {
-            Ok(v) => v,
-            Err(e) => return Err(e),
-        };
-
+        measurements: &mut Measurements,
+    ) -> Result<LineStatus, ParseError> {
         let mut extractor_index = 0;
         let mut filter_index = 0;
 
         for (field_index, value) in values.iter().enumerate() {
-            let LogValue { value, .. } = value;
-
-            // Run filters
+            // Run filters: alternatives on the same field need only one match
+            let mut alternatives_matched = None;
             while filter_index < self.filters.len() && self.filters[filter_index].field_index == field_index {
-                if !self.filters[filter_index].filter(value) {
+                let filter = &self.filters[filter_index];
+                if filter.is_alternative() {
+                    if alternatives_matched != Some(true) {
+                        alternatives_matched = Some(filter.filter(value, self.clock));
+                    }
+                } else if !filter.filter(value, self.clock) {
                     debug!("Skipping because of filter on {}", self.log_parser.fields()[field_index]);
-                    return Ok(false);
+                    return filter.dropped_status(value);
                 }
 
                 filter_index += 1;
             }
+            if alternatives_matched == Some(false) {
+                debug!("Skipping because of filter on {}", self.log_parser.fields()[field_index]);
+                return Ok(LineStatus::Filtered);
+            }
 
             // Run extractors
             while extractor_index < self.extractors.len() && self.extractors[extractor_index].field_index == field_index {
-                self.extractors[extractor_index].extract(value, label_values, duration, response_body_size)?;
+                let extractor = &self.extractors[extractor_index];
+                if let Some(extractor_duration) = &self.extractor_duration {
+                    let start = Instant::now();
+                    extractor.extract(value, label_values, measurements, self.options.lowercase_labels)?;
+                    let name = match &extractor.label {
+                        Some((label, _)) => label.as_str(),
+                        None => &self.log_parser.fields()[field_index],
+                    };
+                    extractor_duration.with_label_values(&[name]).observe(start.elapsed().as_secs_f64());
+                } else {
+                    extractor.extract(value, label_values, measurements, self.options.lowercase_labels)?;
+                }
 
                 extractor_index += 1;
             }
         }
 
-        Ok(true)
+        // Before the path is used in other labels
+        if let Some(index) = self.path_slash_label {
+            strip_trailing_slash(&mut label_values[index]);
+        }
+
+        for combination in &self.combinations {
+            combination.combine(values, label_values);
+        }
+
+        for composite in &self.composites {
+            composite.compose(label_values);
+        }
+
+        // The request is still counted, without its duration
+        if !self.duration_statuses.is_empty() {
+            let observed = match measurements.status {
+                Some(status) => self.duration_statuses.iter().any(|p| status_matches(p, status)),
+                None => false,
+            };
+            if !observed {
+                measurements.duration = None;
+            }
+        }
+
+        Ok(LineStatus::Accepted)
+    }
+}
+
+#[test]
+fn test_parse_upstream_times() {
+    assert_eq!(parse_upstream_times("0.125", UpstreamTimes::Sum).unwrap(), Some(0.125));
+    assert_eq!(parse_upstream_times("0.125, 0.25", UpstreamTimes::Sum).unwrap(), Some(0.375));
+    assert_eq!(parse_upstream_times("0.125, 0.25", UpstreamTimes::Last).unwrap(), Some(0.25));
+    assert_eq!(parse_upstream_times("-, 0.25 : 0.5", UpstreamTimes::Sum).unwrap(), Some(0.75));
+    assert_eq!(parse_upstream_times("0.125, -", UpstreamTimes::Last).unwrap(), Some(0.125));
+    assert_eq!(parse_upstream_times("-", UpstreamTimes::Sum).unwrap(), None);
+    assert!(parse_upstream_times("0.125, abc", UpstreamTimes::Sum).is_err());
+}
+
+#[test]
+fn test_status_matches() {
+    assert!(is_status_pattern("2xx"));
+    assert!(is_status_pattern("304"));
+    assert!(!is_status_pattern("2x"));
+    assert!(!is_status_pattern("2XX"));
+    assert!(status_matches("2xx", 204));
+    assert!(!status_matches("2xx", 404));
+    assert!(status_matches("304", 304));
+    assert!(!status_matches("304", 301));
+    assert!(status_matches("xxx", 599));
+}
+
+#[test]
+fn test_strip_port() {
+    assert_eq!(strip_port("1.2.3.4"), "1.2.3.4");
+    assert_eq!(strip_port("1.2.3.4:5678"), "1.2.3.4");
+    assert_eq!(strip_port("2001:db8::1"), "2001:db8::1");
+    assert_eq!(strip_port("::1"), "::1");
+    assert_eq!(strip_port("[2001:db8::1]:5678"), "2001:db8::1");
+    assert_eq!(strip_port("[::1]"), "::1");
+    // Not a port
+    assert_eq!(strip_port("1.2.3.4:"), "1.2.3.4:");
+    assert_eq!(strip_port("[::1]x"), "[::1]x");
+    assert_eq!(strip_port("unix:"), "unix:");
+    assert_eq!(strip_port("-"), "-");
+}
+
+#[test]
+fn test_strip_cr() {
+    let options = ProcessorOptions::default();
+    assert_eq!(options.strip_cr(b"example.org 200\r"), b"example.org 200");
+    assert_eq!(options.strip_cr(b"example.org 200"), b"example.org 200");
+    // Only CRLF line endings
+    let options = ProcessorOptions { line_terminator: Some(0), ..Default::default() };
+    assert_eq!(options.strip_cr(b"example.org 200\r"), b"example.org 200\r");
+}
+
+#[test]
+fn test_url_decode() {
+    assert_eq!(url_decode("GET /index.html HTTP/1.1"), "GET /index.html HTTP/1.1");
+    assert_eq!(url_decode("GET /my%20file%2Fname HTTP/1.1"), "GET /my file/name HTTP/1.1");
+    assert_eq!(url_decode("/caf%C3%A9"), "/caf\u{e9}");
+    // Invalid escapes are left alone
+    assert_eq!(url_decode("/100%"), "/100%");
+    assert_eq!(url_decode("/100%zz%4"), "/100%zz%4");
+}
+
+#[test]
+fn test_template_path() {
+    assert_eq!(template_path("/api/v4/pets"), "/api/v4/pets");
+    assert_eq!(template_path("/api/v4/pets/12/toys/345"), "/api/v4/pets/:id/toys/:id");
+    assert_eq!(template_path("/users/123e4567-e89b-12d3-a456-426614174000/avatar"), "/users/:uuid/avatar");
+    assert_eq!(template_path("/users/123E4567-E89B-12D3-A456-426614174000"), "/users/:uuid");
+    assert_eq!(template_path("/commit/9fceb02d0ae598e95dc970b74767f19372d61af8"), "/commit/:id");
+    assert_eq!(template_path("/objects/507f1f77bcf86cd799439011?full=1"), "/objects/:id");
+    // Short hex and mixed segments are kept
+    assert_eq!(template_path("/colors/beef"), "/colors/beef");
+    assert_eq!(template_path("/files/report-2021.pdf"), "/files/report-2021.pdf");
+    assert_eq!(template_path("/"), "/");
+}
+
+#[test]
+fn test_skip_lines() {
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-skip.log");
+    std::fs::write(&path, "example.org 200\nexample.org 404\nexample.org 500\nexample").unwrap();
+    let mut file = File::open(&path).unwrap();
+    // Up to the end of the line that has byte 19
+    assert_eq!(skip_lines(&mut file, 0, 20, b'\n').unwrap(), (2, 32));
+    assert_eq!(skip_lines(&mut file, 16, 32, b'\n').unwrap(), (1, 32));
+    // The last line is not terminated, it is kept
+    assert_eq!(skip_lines(&mut file, 32, 55, b'\n').unwrap(), (1, 48));
+    assert_eq!(skip_lines(&mut file, 48, 55, b'\n').unwrap(), (0, 48));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_to_json() {
+    let fields = vec!["host".to_owned(), "request".to_owned(), "status".to_owned()];
+    let values = vec![Borrowed("example.org"), Borrowed("GET /\"quoted\"\\path HTTP/1.1"), Borrowed("200")];
+    assert_eq!(
+        to_json(&fields, &values),
+        r#"{"host":"example.org","request":"GET /\"quoted\"\\path HTTP/1.1","status":"200"}"#,
+    );
+    assert_eq!(to_json(&["ua".to_owned()], &[Borrowed("a\tb\u{1}")]), r#"{"ua":"a\tb\u0001"}"#);
+    // Repeated variables only get their first value
+    let fields = vec!["status".to_owned(), "host".to_owned(), "status".to_owned()];
+    assert_eq!(to_json(&fields, &[Borrowed("200"), Borrowed("example.org"), Borrowed("-")]), r#"{"status":"200","host":"example.org"}"#);
+}
+
+#[cfg(feature = "re")]
+#[test]
+fn test_regex_size_limit() {
+    assert!(user_regex("^/api/v[0-9]+/", false).is_ok());
+    // Repetitions are compiled as copies of the pattern
+    match user_regex("(\\w+\\s*){1000}", false) {
+        Err(regex::Error::CompiledTooBig(limit)) => assert_eq!(limit, REGEX_SIZE_LIMIT),
+        r => panic!("unexpected result {:?}", r),
     }
+    assert!(FilterFunc::regex("[a-z]{5000}", true).is_err());
 }
@@ -0,0 +1,156 @@
+use hyper::{Body, Client, Method, Request};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use log::warn;
+use prometheus::{Encoder, TextEncoder};
+use prometheus::proto::MetricFamily;
+use std::time::Duration;
+
+/// Where to push metrics on a Pushgateway.
+#[derive(Clone, Debug)]
+pub struct PushTarget {
+    pub url: String,
+    pub job: String,
+    /// Extra labels identifying this group of metrics
+    pub grouping: Vec<(String, String)>,
+}
+
+/// Encode a value with the URL-safe base64 alphabet, with padding.
+fn base64_url(value: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in value.as_bytes().chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Add a label to the path, using base64 if the value can't appear as-is.
+fn push_path_label(path: &mut String, name: &str, value: &str) {
+    // Only the unreserved characters of URLs
+    let plain = value.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    if value.is_empty() || !plain {
+        path.push_str(&format!("/{}@base64/{}", name, base64_url(value)));
+    } else {
+        path.push_str(&format!("/{}/{}", name, value));
+    }
+}
+
+impl PushTarget {
+    /// The URL of this group of metrics, e.g. `http://host:9091/metrics/job/<job>`.
+    pub fn group_url(&self) -> String {
+        let mut url = self.url.trim_end_matches('/').to_owned();
+        url.push_str("/metrics");
+        push_path_label(&mut url, "job", &self.job);
+        for (name, value) in &self.grouping {
+            push_path_label(&mut url, name, value);
+        }
+        url
+    }
+}
+
+/// Replace the metrics of the group on the Pushgateway.
+pub async fn push(client: &Client<HttpConnector>, target: &PushTarget, families: &[MetricFamily]) -> Result<(), Box<dyn std::error::Error>> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(families, &mut buffer)?;
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(target.group_url())
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("Pushgateway returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+/// Push the metrics of the default registry at a regular interval.
+pub async fn run(target: PushTarget, interval: Duration) {
+    let client = Client::new();
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        if let Err(e) = push(&client, &target, &prometheus::gather()).await {
+            warn!("Error pushing metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Client, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use prometheus::core::Collector;
+
+    use super::{PushTarget, base64_url, push};
+
+    #[test]
+    fn test_group_url() {
+        assert_eq!(base64_url("/var/log"), "L3Zhci9sb2c=");
+        assert_eq!(base64_url("ab"), "YWI=");
+        assert_eq!(base64_url("a"), "YQ==");
+        assert_eq!(base64_url(""), "");
+
+        let target = PushTarget {
+            url: "http://pushgateway:9091/".to_owned(),
+            job: "access-log".to_owned(),
+            grouping: vec![
+                ("instance".to_owned(), "web1".to_owned()),
+                ("file".to_owned(), "/var/log".to_owned()),
+                ("env".to_owned(), "a b?#".to_owned()),
+            ],
+        };
+        assert_eq!(
+            target.group_url(),
+            "http://pushgateway:9091/metrics/job/access-log/instance/web1/file@base64/L3Zhci9sb2c=/env@base64/YSBiPyM=",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        // Mock Pushgateway, which sends what it gets on a channel
+        let (tx, rx) = std::sync::mpsc::channel();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let method = req.method().clone();
+                        let path = req.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        tx.send((method, path, body)).unwrap();
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        }));
+        let target = PushTarget {
+            url: format!("http://{}", server.local_addr()),
+            job: "access-log".to_owned(),
+            grouping: vec![("instance".to_owned(), "web1".to_owned())],
+        };
+        tokio::spawn(server);
+
+        let gauge = prometheus::IntGauge::new("log_file_present", "Present").unwrap();
+        gauge.set(1);
+        push(&Client::new(), &target, &gauge.collect()).await.unwrap();
+
+        let (method, path, body) = rx.try_recv().unwrap();
+        assert_eq!(method, hyper::Method::PUT);
+        assert_eq!(path, "/metrics/job/access-log/instance/web1");
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "# HELP log_file_present Present\n# TYPE log_file_present gauge\nlog_file_present 1\n",
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use hyper::{Body, Client, Method, Request};
+use hyper::client::HttpConnector;
+use log::warn;
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message;
+use std::time::Duration;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the Unix epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Convert gathered metrics to remote-write time series, one per sample.
+pub fn to_timeseries(families: &[MetricFamily], timestamp: i64) -> Vec<TimeSeries> {
+    let mut timeseries = Vec::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let mut add = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = vec![Label {
+                    name: "__name__".to_owned(),
+                    value: format!("{}{}", family.get_name(), suffix),
+                }];
+                for pair in metric.get_label() {
+                    labels.push(Label {
+                        name: pair.get_name().to_owned(),
+                        value: pair.get_value().to_owned(),
+                    });
+                }
+                if let Some((name, value)) = extra {
+                    labels.push(Label { name: name.to_owned(), value });
+                }
+                // Remote-write requires labels sorted by name
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                timeseries.push(TimeSeries {
+                    labels,
+                    samples: vec![Sample { value, timestamp }],
+                });
+            };
+
+            match family.get_field_type() {
+                MetricType::COUNTER => add("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => add("", None, metric.get_gauge().get_value()),
+                // Not produced by this program
+                MetricType::UNTYPED => {}
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        add("_bucket", Some(("le", bucket.get_upper_bound().to_string())), bucket.get_cumulative_count() as f64);
+                    }
+                    add("_bucket", Some(("le", "+Inf".to_owned())), histogram.get_sample_count() as f64);
+                    add("_sum", None, histogram.get_sample_sum());
+                    add("_count", None, histogram.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        add("", Some(("quantile", quantile.get_quantile().to_string())), quantile.get_value());
+                    }
+                    add("_sum", None, summary.get_sample_sum());
+                    add("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    timeseries
+}
+
+/// Encode the metrics as a snappy-compressed remote-write request.
+pub fn encode(families: &[MetricFamily], timestamp: i64) -> Vec<u8> {
+    let request = WriteRequest {
+        timeseries: to_timeseries(families, timestamp),
+    };
+    snap::raw::Encoder::new().compress_vec(&request.encode_to_vec()).unwrap()
+}
+
+/// Send the metrics to a remote-write endpoint.
+pub async fn push(client: &Client<HttpConnector>, url: &str, families: &[MetricFamily]) -> Result<(), Box<dyn std::error::Error>> {
+    let timestamp = (crate::timestamp::now() * 1000.0) as i64;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Encoding", "snappy")
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .body(Body::from(encode(families, timestamp)))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("Remote-write endpoint returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+/// Push the metrics of the default registry at a regular interval.
+pub async fn run(url: String, interval: Duration) {
+    let client = Client::new();
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        if let Err(e) = push(&client, &url, &prometheus::gather()).await {
+            warn!("Error sending metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Client, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use prometheus::core::Collector;
+    use prost::Message;
+
+    use super::{Label, WriteRequest, push, to_timeseries};
+
+    fn label(name: &str, value: &str) -> Label {
+        Label { name: name.to_owned(), value: value.to_owned() }
+    }
+
+    #[test]
+    fn test_to_timeseries() {
+        let counter = prometheus::IntCounterVec::new(prometheus::Opts::new("requests", "Requests"), &["vhost", "status"]).unwrap();
+        counter.with_label_values(&["example.org", "200"]).inc_by(3);
+        let histogram = prometheus::Histogram::with_opts(prometheus::HistogramOpts::new("request_duration", "Duration").buckets(vec![0.5])).unwrap();
+        histogram.observe(0.25);
+
+        let mut families = counter.collect();
+        families.extend(histogram.collect());
+        let timeseries = to_timeseries(&families, 1636598079000);
+        assert_eq!(timeseries.len(), 5);
+        assert_eq!(
+            timeseries[0].labels,
+            vec![label("__name__", "requests"), label("status", "200"), label("vhost", "example.org")],
+        );
+        assert_eq!(timeseries[0].samples[0].value, 3.0);
+        assert_eq!(timeseries[0].samples[0].timestamp, 1636598079000);
+        assert_eq!(timeseries[1].labels, vec![label("__name__", "request_duration_bucket"), label("le", "0.5")]);
+        assert_eq!(timeseries[2].labels, vec![label("__name__", "request_duration_bucket"), label("le", "+Inf")]);
+        assert_eq!(timeseries[3].labels, vec![label("__name__", "request_duration_sum")]);
+        assert_eq!(timeseries[3].samples[0].value, 0.25);
+        assert_eq!(timeseries[4].labels, vec![label("__name__", "request_duration_count")]);
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        // Mock receiver, which sends what it gets on a channel
+        let (tx, rx) = std::sync::mpsc::channel();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let encoding = req.headers()["Content-Encoding"].to_str().unwrap().to_owned();
+                        let path = req.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        tx.send((path, encoding, body)).unwrap();
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        }));
+        let url = format!("http://{}/api/v1/write", server.local_addr());
+        tokio::spawn(server);
+
+        let gauge = prometheus::IntGauge::new("log_file_present", "Present").unwrap();
+        gauge.set(1);
+        push(&Client::new(), &url, &gauge.collect()).await.unwrap();
+
+        let (path, encoding, body) = rx.try_recv().unwrap();
+        assert_eq!(path, "/api/v1/write");
+        assert_eq!(encoding, "snappy");
+        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let request = WriteRequest::decode(&body[..]).unwrap();
+        assert_eq!(request.timeseries.len(), 1);
+        assert_eq!(request.timeseries[0].labels, vec![label("__name__", "log_file_present")]);
+        assert_eq!(request.timeseries[0].samples[0].value, 1.0);
+    }
+}
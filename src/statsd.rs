@@ -0,0 +1,115 @@
+use log::debug;
+use std::fmt::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::processor::Measurements;
+
+/// Sends the requests to a statsd server as they are processed, with the
+/// labels as tags (in the DogStatsD format).
+#[derive(Debug)]
+pub struct StatsdClient {
+    socket: UdpSocket,
+}
+
+/// Replace the characters that have a meaning in the statsd format.
+fn tag_value(value: &str) -> String {
+    value.replace(|c| matches!(c, ',' | '|' | '#' | '\n'), "_")
+}
+
+impl StatsdClient {
+    pub fn connect(addr: &str) -> std::io::Result<StatsdClient> {
+        let target = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No address for {:?}", addr)))?;
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        Ok(StatsdClient { socket })
+    }
+
+    /// The statsd lines for a request: a counter increment, and timings in
+    /// milliseconds.
+    pub fn format(labels: &[String], label_values: &[&str], measurements: &Measurements) -> String {
+        let mut tags = String::new();
+        for (i, (label, value)) in labels.iter().zip(label_values).enumerate() {
+            tags.push(if i == 0 { '#' } else { ',' });
+            write!(tags, "{}:{}", label, tag_value(value)).unwrap();
+        }
+        let suffix = if tags.is_empty() { String::new() } else { format!("|{}", tags) };
+
+        let mut out = format!("requests:1|c{}", suffix);
+        let timings = [
+            ("request_duration", measurements.duration),
+            ("upstream_duration", measurements.upstream_duration),
+            ("upstream_header_time", measurements.upstream_header_time),
+            ("upstream_connect_time", measurements.upstream_connect_time),
+        ];
+        for (name, value) in &timings {
+            if let Some(seconds) = value {
+                write!(out, "\n{}:{}|ms{}", name, seconds * 1000.0, suffix).unwrap();
+            }
+        }
+        out
+    }
+
+    pub fn record(&self, labels: &[String], label_values: &[&str], measurements: &Measurements) {
+        let packet = Self::format(labels, label_values, measurements);
+        // Dropping is fine, like for any statsd client
+        if let Err(e) = self.socket.send(packet.as_bytes()) {
+            debug!("Error sending to statsd: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::collector::LogCollectorBuilder;
+    use crate::log_parser::LogParser;
+    use super::StatsdClient;
+
+    #[test]
+    fn test_statsd() {
+        // Mock statsd server
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let client = StatsdClient::connect(&server.local_addr().unwrap().to_string()).unwrap();
+
+        let log_parser = LogParser::from_format("$host $status $request_time \"$upstream_response_time\"").unwrap();
+        let mut collector_builder = LogCollectorBuilder::new(log_parser, "/tmp/access.log".into());
+        collector_builder.set_statsd(client);
+        let data = Arc::new(Mutex::new(collector_builder.build_data()));
+        let processor = collector_builder.build_processor(data.clone());
+
+        let data = data.lock().unwrap();
+        processor.handle_line(&data, "example.org 200 0.125 \"0.1\"");
+        processor.handle_line(&data, "a,b|c.org 404 0.5 \"-\"");
+        processor.handle_line(&data, "garbage");
+
+        let mut buf = [0; 1024];
+        let mut receive = || {
+            let len = server.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+        assert_eq!(
+            receive(),
+            "requests:1|c|#vhost:example.org,status:200\n\
+             request_duration:125|ms|#vhost:example.org,status:200\n\
+             upstream_duration:100|ms|#vhost:example.org,status:200",
+        );
+        assert_eq!(
+            receive(),
+            "requests:1|c|#vhost:a_b_c.org,status:404\n\
+             request_duration:500|ms|#vhost:a_b_c.org,status:404",
+        );
+        // Nothing for lines that fail parsing
+        server.set_nonblocking(true).unwrap();
+        assert!(server.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use listenfd::ListenFd;
+use std::net::TcpListener;
+
+/// The listening sockets passed by systemd socket activation.
+///
+/// The environment variables are removed, so they are not seen by child
+/// processes. Since that is not safe while other threads might read the
+/// environment, this has to be called before starting any thread.
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::new();
+    for i in 0..fds.len() {
+        // Fails if it's not a TCP socket
+        let listener = match fds.take_tcp_listener(i) {
+            Ok(Some(listener)) => listener,
+            Ok(None) => continue,
+            Err(e) => return Err(format!("Can't use socket {} passed by systemd: {}", i, e)),
+        };
+        // Tokio needs them in non-blocking mode
+        listener.set_nonblocking(true).map_err(|e| format!("Can't use socket {} passed by systemd: {}", i, e))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Run `test_listeners_child` in a new process, passed `fd` like systemd
+/// would, since `listeners()` changes the environment.
+#[cfg(test)]
+fn run_child(fd: std::os::unix::io::RawFd, expected_addr: Option<std::net::SocketAddr>) -> bool {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.args(&["--exact", "systemd::test_listeners_child", "--ignored", "--test-threads=1"]);
+    command.env("LISTEN_FDS", "1");
+    command.env("LISTEN_FDS_FIRST_FD", fd.to_string());
+    command.env_remove("LISTEN_PID");
+    match expected_addr {
+        Some(addr) => command.env("TEST_LISTEN_ADDR", addr.to_string()),
+        None => command.env_remove("TEST_LISTEN_ADDR"),
+    };
+    // Let the child inherit the descriptor
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let output = command.output().unwrap();
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stdout));
+    }
+    output.status.success()
+}
+
+/// The check run in the child process by `test_listeners`.
+#[test]
+#[ignore]
+fn test_listeners_child() {
+    // Only run from test_listeners
+    if std::env::var_os("LISTEN_FDS_FIRST_FD").is_none() {
+        return;
+    }
+    let result = listeners();
+    // Not seen again, e.g. by child processes
+    assert!(std::env::var_os("LISTEN_FDS").is_none());
+    match std::env::var("TEST_LISTEN_ADDR") {
+        Ok(addr) => {
+            let listeners = result.unwrap();
+            assert_eq!(listeners.len(), 1);
+            assert_eq!(listeners[0].local_addr().unwrap().to_string(), addr);
+        }
+        Err(_) => assert!(result.is_err()),
+    }
+}
+
+#[test]
+fn test_listeners() {
+    use std::os::unix::io::AsRawFd;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(run_child(listener.as_raw_fd(), Some(listener.local_addr().unwrap())));
+
+    // Not a socket
+    let file = std::fs::File::open("Cargo.toml").unwrap();
+    assert!(run_child(file.as_raw_fd(), None));
+}
@@ -0,0 +1,53 @@
+use chrono::DateTime;
+
+/// The log format variables holding the time of the request, in order of
+/// preference.
+pub const TIME_FIELDS: &[&str] = &["time_iso8601", "time_local", "msec"];
+
+/// How the time of the request is written in a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// `$time_iso8601`, e.g. `2021-11-11T02:34:39+00:00`
+    Iso8601,
+    /// `$time_local`, e.g. `11/Nov/2021:02:34:39 +0000`
+    Local,
+    /// `$msec`, seconds since the Unix epoch with milliseconds
+    Msec,
+}
+
+impl TimestampFormat {
+    pub fn for_field(field: &str) -> Option<TimestampFormat> {
+        match field {
+            "time_iso8601" => Some(TimestampFormat::Iso8601),
+            "time_local" => Some(TimestampFormat::Local),
+            "msec" => Some(TimestampFormat::Msec),
+            _ => None,
+        }
+    }
+
+    /// Parse a timestamp into seconds since the Unix epoch.
+    pub fn parse(&self, value: &str) -> Option<f64> {
+        let datetime = match self {
+            TimestampFormat::Iso8601 => DateTime::parse_from_rfc3339(value).ok()?,
+            TimestampFormat::Local => DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z").ok()?,
+            TimestampFormat::Msec => return value.parse().ok(),
+        };
+        Some(datetime.timestamp() as f64 + datetime.timestamp_subsec_millis() as f64 / 1000.0)
+    }
+}
+
+/// The current time, in seconds since the Unix epoch.
+pub fn now() -> f64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(TimestampFormat::Local.parse("11/Nov/2021:02:34:39 +0000"), Some(1636598079.0));
+    assert_eq!(TimestampFormat::Local.parse("10/Nov/2021:21:34:39 -0500"), Some(1636598079.0));
+    assert_eq!(TimestampFormat::Iso8601.parse("2021-11-11T02:34:39+00:00"), Some(1636598079.0));
+    assert_eq!(TimestampFormat::Iso8601.parse("2021-11-11T04:34:39+02:00"), Some(1636598079.0));
+    assert_eq!(TimestampFormat::Msec.parse("1636598079.123"), Some(1636598079.123));
+    assert_eq!(TimestampFormat::Local.parse("2021-11-11T02:34:39+00:00"), None);
+    assert_eq!(TimestampFormat::Iso8601.parse("-"), None);
+}
@@ -0,0 +1,101 @@
+use log::{info, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// A set of values that can be replaced while the log is being processed.
+pub type SharedSet = Arc<RwLock<HashSet<String>>>;
+
+/// Read a file of newline-separated values.
+///
+/// Blank lines and lines starting with `#` are ignored, and the values are
+/// trimmed.
+pub fn read_set(path: &Path) -> std::io::Result<HashSet<String>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_owned())
+        .collect())
+}
+
+/// A set of values loaded from a file.
+#[derive(Clone, Debug)]
+pub struct SetFile {
+    pub path: PathBuf,
+    pub set: SharedSet,
+}
+
+impl SetFile {
+    pub fn open(path: &Path) -> std::io::Result<SetFile> {
+        let set = read_set(path)?;
+        Ok(SetFile {
+            path: path.to_owned(),
+            set: Arc::new(RwLock::new(set)),
+        })
+    }
+
+    /// Replace the values with the current content of the file. If it can't
+    /// be read, the previous values are kept.
+    pub fn reload(&self) -> std::io::Result<usize> {
+        let set = read_set(&self.path)?;
+        let len = set.len();
+        *self.set.write().unwrap() = set;
+        Ok(len)
+    }
+}
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+/// Reload the files when the process receives SIGHUP.
+pub fn reload_on_sighup(files: Vec<SetFile>) {
+    if files.is_empty() {
+        return;
+    }
+
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGHUP, on_hangup as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t);
+    }
+
+    // Only an atomic store is safe in the signal handler, so a thread checks
+    // for it
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_millis(500));
+            if HANGUP.swap(false, Ordering::Relaxed) {
+                for file in &files {
+                    match file.reload() {
+                        Ok(len) => info!("Reloaded {} values from {:?}", len, file.path),
+                        Err(e) => warn!("Error reloading {:?}, keeping previous values: {}", file.path, e),
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[test]
+fn test_read_set() {
+    let path = std::env::temp_dir().join("access-log-to-prometheus-metrics-set.txt");
+    std::fs::write(&path, "# Internal hosts\n10.0.0.1\n\n  10.0.0.2 \r\n").unwrap();
+    let file = SetFile::open(&path).unwrap();
+    let expected: HashSet<String> = ["10.0.0.1", "10.0.0.2"].iter().map(|v| v.to_string()).collect();
+    assert_eq!(*file.set.read().unwrap(), expected);
+
+    std::fs::write(&path, "10.0.0.3\n").unwrap();
+    assert_eq!(file.reload().unwrap(), 1);
+    assert!(file.set.read().unwrap().contains("10.0.0.3"));
+
+    // The previous values are kept if the file is gone
+    std::fs::remove_file(&path).unwrap();
+    assert!(file.reload().is_err());
+    assert!(file.set.read().unwrap().contains("10.0.0.3"));
+}